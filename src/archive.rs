@@ -0,0 +1,169 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tokio::time::sleep;
+use tracing::{debug, error, info};
+
+use crate::{blob::BlobStore, store::Account};
+
+const PREFIX: &str = "archive/";
+
+/// The raw RFC822 source of the processed messages, kept to replay or inspect them.
+///
+/// Messages are stored in a blob store as `archive/<account>/<mailbox>/<id>.eml`,
+/// where the id is the UIDVALIDITY and UID for IMAP (e.g. "1700000000-42", since
+/// UIDs are reused once the UIDVALIDITY changes), or the id given by the other
+/// sources (e.g. the POP3 UIDL or the Maildir file name). They are deleted once they
+/// are older than the retention, if any.
+pub struct Archive {
+    store: Arc<dyn BlobStore>,
+    retention: Option<chrono::Duration>,
+}
+
+impl Archive {
+    pub fn new(store: Arc<dyn BlobStore>, retention_days: Option<u32>) -> Self {
+        Self {
+            store,
+            retention: retention_days.map(|days| chrono::Duration::days(days.into())),
+        }
+    }
+
+    /// The key of a message, with the account and the mailbox encoded as single segments.
+    pub fn key(email: &str, mailbox: &str, id: &str) -> String {
+        format!(
+            "{}{}/{}/{}.eml",
+            PREFIX,
+            encode_segment(email),
+            encode_segment(mailbox),
+            encode_segment(id)
+        )
+    }
+
+    /// Store the raw source of a message, replacing any previous copy.
+    pub async fn store(&self, account: &Account, id: &str, raw: &[u8]) -> Result<()> {
+        let key = Self::key(&account.email, &account.mailbox, id);
+        debug!("-- archiving message {} as {}", id, key);
+        self.store.put_blob(&key, "message/rfc822", raw).await
+    }
+
+    /// Get the raw source of an archived message, if it was not deleted yet.
+    pub async fn get(&self, email: &str, mailbox: &str, id: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_blob(&Self::key(email, mailbox, id)).await
+    }
+
+    /// Delete the messages archived before the retention, returning how many were deleted.
+    pub async fn purge(&self, now: DateTime<Utc>) -> Result<usize> {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return Ok(0),
+        };
+        let mut deleted = 0;
+        for blob in self.store.list_blobs(PREFIX).await? {
+            if now - blob.modified > retention {
+                self.store.delete_blob(&blob.key).await?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+/// Purge the archive forever, once per hour.
+pub async fn run_retention(archive: Arc<Archive>) {
+    loop {
+        match archive.purge(Utc::now()).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("{} archived messages deleted by the retention", deleted),
+            Err(e) => error!("Unable to purge the archive: {:?}", e),
+        }
+        sleep(Duration::from_secs(3600)).await;
+    }
+}
+
+/// Percent-encode the characters that are not safe in a key segment, e.g. the
+/// separator of nested mailboxes ("INBOX/Receipts") or the Maildir flags ("1.host:2,S").
+fn encode_segment(value: &str) -> String {
+    if value.chars().all(|c| c == '.') {
+        // Not a valid path segment, e.g. ".."
+        return value.replace('.', "%2E");
+    }
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'@' | b'+' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::FileBlobStore;
+    use crate::store::{AccountPolicy, SourceSettings};
+
+    #[test]
+    fn test_key() {
+        assert_eq!(
+            Archive::key("test@test.com", "INBOX/Receipts 2024", "42"),
+            "archive/test@test.com/INBOX%2FReceipts%202024/42.eml"
+        );
+        assert_eq!(
+            Archive::key("test@test.com", "..", "1.host:2,S"),
+            "archive/test@test.com/%2E%2E/1.host%3A2%2CS.eml"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_and_purge() {
+        let root = tempfile::tempdir().unwrap();
+        let blob_store = Arc::new(FileBlobStore::new(
+            root.path().to_string_lossy().to_string(),
+        ));
+        let archive = Archive::new(blob_store.clone(), Some(30));
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Imap,
+            policy: AccountPolicy::default(),
+        };
+        blob_store
+            .put_blob("attachments/test@test.com/hash", "image/png", b"PNG")
+            .await
+            .unwrap();
+
+        archive
+            .store(&account, "7", b"Subject: Hi\r\n\r\nHi")
+            .await
+            .unwrap();
+        assert_eq!(
+            archive.get("test@test.com", "INBOX", "7").await.unwrap(),
+            Some(b"Subject: Hi\r\n\r\nHi".to_vec())
+        );
+        assert_eq!(archive.purge(Utc::now()).await.unwrap(), 0);
+
+        // Only the archived messages are deleted
+        let later = Utc::now() + chrono::Duration::days(31);
+        assert_eq!(archive.purge(later).await.unwrap(), 1);
+        assert_eq!(
+            archive.get("test@test.com", "INBOX", "7").await.unwrap(),
+            None
+        );
+        assert!(blob_store
+            .get_blob("attachments/test@test.com/hash")
+            .await
+            .unwrap()
+            .is_some());
+
+        let forever = Archive::new(blob_store, None);
+        forever.store(&account, "8", b"Hi").await.unwrap();
+        assert_eq!(forever.purge(later).await.unwrap(), 0);
+    }
+}
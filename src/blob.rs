@@ -0,0 +1,525 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tokio::{fs, task};
+use tracing::debug;
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Store a blob under the given key, replacing any previous content.
+    async fn put_blob(&self, key: &str, content_type: &str, data: &[u8]) -> Result<()>;
+
+    /// Get the content of a blob, if it exists.
+    async fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// List the blobs whose key starts with the given prefix.
+    async fn list_blobs(&self, prefix: &str) -> Result<Vec<BlobInfo>>;
+
+    /// Delete a blob, if it exists.
+    async fn delete_blob(&self, key: &str) -> Result<()>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlobInfo {
+    pub key: String,
+    pub modified: DateTime<Utc>,
+}
+
+/// Blobs stored as files below a root directory.
+#[derive(Clone, Debug)]
+pub struct FileBlobStore {
+    root: PathBuf,
+}
+
+impl FileBlobStore {
+    pub fn new(root: String) -> Self {
+        Self {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        // Keys must stay below the root directory
+        if key
+            .split('/')
+            .any(|segment| segment == ".." || segment.is_empty())
+        {
+            return Err(anyhow::Error::msg(format!("Invalid blob key {}", key)));
+        }
+        Ok(self.root.join(Path::new(key)))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FileBlobStore {
+    async fn put_blob(&self, key: &str, _content_type: &str, data: &[u8]) -> Result<()> {
+        debug!("Store blob {} in {}", key, self.root.display());
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Write to a temporary file first, so readers never see a partial blob. Its
+        // name is unique, so concurrent writers of the same key do not collide.
+        let directory = path.parent().unwrap_or(&self.root).to_path_buf();
+        let data = data.to_vec();
+        task::spawn_blocking(move || {
+            let mut temporary = tempfile::Builder::new()
+                .suffix(".tmp")
+                .tempfile_in(directory)?;
+            temporary.write_all(&data)?;
+            temporary.persist(&path)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list_blobs(&self, prefix: &str) -> Result<Vec<BlobInfo>> {
+        let mut blobs = vec![];
+        // Only the directory of the prefix is walked, e.g. "archive/" for "archive/a@"
+        let directory = match prefix.rfind('/') {
+            Some(end) => self.path(&prefix[..end])?,
+            None => self.root.clone(),
+        };
+        let mut directories = vec![directory];
+        while let Some(directory) = directories.pop() {
+            let mut entries = match fs::read_dir(&directory).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                    continue;
+                }
+                let key = match entry.path().strip_prefix(&self.root) {
+                    Ok(key) => key.to_string_lossy().replace('\\', "/"),
+                    Err(_) => continue,
+                };
+                // Temporary files of the blobs being written are skipped
+                if key.starts_with(prefix) && !key.ends_with(".tmp") {
+                    blobs.push(BlobInfo {
+                        key,
+                        modified: metadata.modified()?.into(),
+                    });
+                }
+            }
+        }
+        blobs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(blobs)
+    }
+
+    async fn delete_blob(&self, key: &str) -> Result<()> {
+        debug!("Delete blob {} in {}", key, self.root.display());
+        match fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Blobs stored in an S3-compatible object store (AWS S3, MinIO...).
+///
+/// Requests use path-style addressing and AWS Signature Version 4.
+#[derive(Clone, Debug)]
+pub struct S3BlobStore {
+    http: reqwest::Client,
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3BlobStore {
+    pub fn new(
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket,
+            region,
+            access_key,
+            secret_key,
+        }
+    }
+
+    fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        data: &[u8],
+    ) -> reqwest::RequestBuilder {
+        let path = match key {
+            "" => format!("/{}", self.bucket),
+            _ => format!("/{}/{}", self.bucket, uri_encode(key)),
+        };
+        // The canonical query string is sorted by parameter name
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", query_encode(name), query_encode(value)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+        let host = self
+            .endpoint
+            .split("://")
+            .last()
+            .unwrap_or_default()
+            .to_string();
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(data));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = signing_key(&self.secret_key, &date, &self.region, "s3");
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let url = match query.as_str() {
+            "" => format!("{}{}", self.endpoint, path),
+            _ => format!("{}{}?{}", self.endpoint, path, query),
+        };
+        self.http
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("Authorization", authorization)
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put_blob(&self, key: &str, content_type: &str, data: &[u8]) -> Result<()> {
+        debug!("Store blob {} in bucket {}", key, self.bucket);
+        self.request(reqwest::Method::PUT, key, &[], data)
+            .header("Content-Type", content_type)
+            .body(data.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self
+            .request(reqwest::Method::GET, key, &[], b"")
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.bytes().await?.to_vec()))
+    }
+
+    async fn list_blobs(&self, prefix: &str) -> Result<Vec<BlobInfo>> {
+        let mut blobs = vec![];
+        let mut continuation_token: Option<String> = None;
+        loop {
+            // ListObjectsV2, in pages of up to 1000 keys
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token));
+            }
+            let response = self
+                .request(reqwest::Method::GET, "", &query, b"")
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            let (page, next) = parse_list_objects(&response)?;
+            blobs.extend(page);
+            match next {
+                Some(token) => continuation_token = Some(token),
+                None => return Ok(blobs),
+            }
+        }
+    }
+
+    async fn delete_blob(&self, key: &str) -> Result<()> {
+        debug!("Delete blob {} in bucket {}", key, self.bucket);
+        self.request(reqwest::Method::DELETE, key, &[], b"")
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Read the objects and the continuation token of a ListObjectsV2 response.
+fn parse_list_objects(xml: &str) -> Result<(Vec<BlobInfo>, Option<String>)> {
+    static CONTENTS: OnceLock<Regex> = OnceLock::new();
+    static ELEMENT: OnceLock<Regex> = OnceLock::new();
+    let contents = CONTENTS.get_or_init(|| Regex::new(r"(?s)<Contents>(.*?)</Contents>").unwrap());
+    // The elements read only hold text, e.g. "<Key>a/b</Key>"
+    let leaf = ELEMENT.get_or_init(|| Regex::new(r"<(\w+)>([^<]*)</(\w+)>").unwrap());
+    let element = |xml: &str, name: &str| {
+        leaf.captures_iter(xml)
+            .find(|caps| &caps[1] == name && &caps[3] == name)
+            .map(|caps| xml_unescape(&caps[2]))
+    };
+    let mut blobs = vec![];
+    for caps in contents.captures_iter(xml) {
+        let (key, modified) = match (element(&caps[1], "Key"), element(&caps[1], "LastModified")) {
+            (Some(key), Some(modified)) => (key, modified),
+            _ => return Err(anyhow::Error::msg("Invalid object in the bucket listing")),
+        };
+        blobs.push(BlobInfo {
+            key,
+            modified: DateTime::parse_from_rfc3339(&modified)?.with_timezone(&Utc),
+        });
+    }
+    let next = match element(xml, "IsTruncated").as_deref() {
+        Some("true") => element(xml, "NextContinuationToken"),
+        _ => None,
+    };
+    Ok((blobs, next))
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Encode a query parameter for the canonical query string, including the slashes.
+fn query_encode(value: &str) -> String {
+    uri_encode(value).replace('/', "%2F")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// Encode an object key for the canonical URI, keeping the path separators.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{header_exists, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_signing_key() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("attachments/test@test.com/a b.pdf"),
+            "attachments/test%40test.com/a%20b.pdf"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_blob_store() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FileBlobStore::new(root.path().to_string_lossy().to_string());
+
+        store
+            .put_blob("attachments/test/hash", "application/pdf", b"%PDF")
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_blob("attachments/test/hash").await.unwrap(),
+            Some(b"%PDF".to_vec())
+        );
+        assert_eq!(store.get_blob("attachments/missing").await.unwrap(), None);
+        store
+            .put_blob("archive/test/1.eml", "message/rfc822", b"Subject: Hi")
+            .await
+            .unwrap();
+        let blobs = store.list_blobs("attachments/").await.unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].key, "attachments/test/hash");
+        let blobs = store.list_blobs("archive/te").await.unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].key, "archive/test/1.eml");
+        assert_eq!(store.list_blobs("missing/").await.unwrap(), vec![]);
+        store.delete_blob("attachments/test/hash").await.unwrap();
+        store.delete_blob("attachments/test/hash").await.unwrap();
+        assert_eq!(store.list_blobs("attachments/").await.unwrap(), vec![]);
+        assert!(store
+            .put_blob("../escape", "text/plain", b"")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_blob_store_concurrent_writes() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FileBlobStore::new(root.path().to_string_lossy().to_string());
+
+        let writes = (0..8).map(|_| store.put_blob("attachments/test/hash", "text/plain", b"same"));
+        for result in futures::future::join_all(writes).await {
+            result.unwrap();
+        }
+        let blobs = store.list_blobs("attachments/").await.unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(
+            store.get_blob("attachments/test/hash").await.unwrap(),
+            Some(b"same".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s3_blob_store() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/bucket/attachments/hash"))
+            .and(header_exists("x-amz-date"))
+            .and(header_exists("authorization"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/bucket/attachments/hash"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"%PDF".to_vec()))
+            .mount(&server)
+            .await;
+        let store = S3BlobStore::new(
+            server.uri(),
+            "bucket".to_string(),
+            "us-east-1".to_string(),
+            "minioadmin".to_string(),
+            "minioadmin".to_string(),
+        );
+
+        store
+            .put_blob("attachments/hash", "application/pdf", b"%PDF")
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_blob("attachments/hash").await.unwrap(),
+            Some(b"%PDF".to_vec())
+        );
+        assert_eq!(store.get_blob("attachments/missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_s3_list_and_delete_blobs() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/bucket"))
+            .and(query_param("prefix", "archive/"))
+            .and(query_param("continuation-token", "page2"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                 <Contents><Key>archive/b&amp;c.eml</Key><LastModified>2024-01-02T00:00:00.000Z</LastModified></Contents>\
+                 </ListBucketResult>",
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/bucket"))
+            .and(query_param("list-type", "2"))
+            .and(query_param("prefix", "archive/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "<ListBucketResult><IsTruncated>true</IsTruncated>\
+                 <NextContinuationToken>page2</NextContinuationToken>\
+                 <Contents><Key>archive/a.eml</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified>\
+                 <Size>10</Size></Contents></ListBucketResult>",
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/bucket/archive/a.eml"))
+            .and(header_exists("authorization"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let store = S3BlobStore::new(
+            server.uri(),
+            "bucket".to_string(),
+            "us-east-1".to_string(),
+            "minioadmin".to_string(),
+            "minioadmin".to_string(),
+        );
+
+        let blobs = store.list_blobs("archive/").await.unwrap();
+        assert_eq!(
+            blobs
+                .iter()
+                .map(|blob| (blob.key.as_str(), blob.modified.to_rfc3339()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("archive/a.eml", "2024-01-01T00:00:00+00:00".to_string()),
+                ("archive/b&c.eml", "2024-01-02T00:00:00+00:00".to_string()),
+            ]
+        );
+        store.delete_blob("archive/a.eml").await.unwrap();
+    }
+}
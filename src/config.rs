@@ -19,9 +19,51 @@ pub enum AppEnv {
     Production,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum InboundProtocol {
+    Smtp,
+    Lmtp,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InboundConfig {
+    pub listen_addr: String,
+    pub protocol: InboundProtocol,
+    pub hostname: String,
+    pub max_message_size: usize,
+    pub max_connections: usize, // Concurrent sessions, the others are turned away with a 421
+    pub timeout_seconds: u64,   // The session is closed after waiting this long for a line
+    pub tls_cert_path: Option<String>, // PEM certificate chain, enables STARTTLS with the key
+    pub tls_key_path: Option<String>, // PEM PKCS#8 private key
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlobConfig {
+    Filesystem {
+        path: String,
+    },
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveConfig {
+    pub store: BlobConfig,
+    pub retention_days: Option<u32>, // Archived messages are kept forever when missing
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub app_env: AppEnv,
+    pub archive: Option<ArchiveConfig>, // Raw messages are not archived when missing
+    pub blob: Option<BlobConfig>,       // Attachments are not extracted when missing
+    pub inbound: Option<InboundConfig>, // The SMTP/LMTP listener is disabled when missing
+    pub keystore_path: Option<String>,  // Encrypted messages are not decrypted when missing
     pub log_level: Level,
     pub redis_server: String,
     pub version: String,
@@ -45,6 +87,56 @@ impl Config {
             .get_var("VERSION")
             .unwrap_or_else(|_| "experimental".to_string());
 
+        let inbound = env.get_var("SMTP_LISTEN").ok().map(|listen_addr| {
+            let protocol = match env
+                .get_var("SMTP_PROTOCOL")
+                .unwrap_or_else(|_| "smtp".to_string())
+                .to_lowercase()
+                .as_str()
+            {
+                "lmtp" => InboundProtocol::Lmtp,
+                _ => InboundProtocol::Smtp,
+            };
+            InboundConfig {
+                listen_addr,
+                protocol,
+                hostname: env
+                    .get_var("SMTP_HOSTNAME")
+                    .unwrap_or_else(|_| "localhost".to_string()),
+                max_message_size: env
+                    .get_var("SMTP_MAX_MESSAGE_SIZE")
+                    .unwrap_or_else(|_| "26214400".to_string())
+                    .parse()
+                    .unwrap_or(26214400),
+                max_connections: env
+                    .get_var("SMTP_MAX_CONNECTIONS")
+                    .unwrap_or_else(|_| "100".to_string())
+                    .parse()
+                    .unwrap_or(100),
+                timeout_seconds: env
+                    .get_var("SMTP_TIMEOUT_SECONDS")
+                    .unwrap_or_else(|_| "300".to_string())
+                    .parse()
+                    .unwrap_or(300),
+                tls_cert_path: env.get_var("SMTP_TLS_CERT").ok(),
+                tls_key_path: env.get_var("SMTP_TLS_KEY").ok(),
+            }
+        });
+
+        let blob = blob_config(env, "BLOB_STORE", "BLOB_PATH", "S3_BUCKET");
+        let archive =
+            blob_config(env, "ARCHIVE_STORE", "ARCHIVE_PATH", "ARCHIVE_S3_BUCKET").map(|store| {
+                ArchiveConfig {
+                    store,
+                    retention_days: env
+                        .get_var("ARCHIVE_RETENTION_DAYS")
+                        .ok()
+                        .and_then(|days| days.parse().ok()),
+                }
+            });
+
+        let keystore_path = env.get_var("KEYSTORE_PATH").ok();
+
         let redis_server = format!("redis://{}:{}", redis_host, redis_port)
             .parse()
             .expect("Failed to parse REDIS_HOST and REDIS_PORT");
@@ -64,6 +156,10 @@ impl Config {
 
         Config {
             app_env,
+            archive,
+            blob,
+            inbound,
+            keystore_path,
             log_level,
             redis_server,
             version,
@@ -73,6 +169,10 @@ impl Config {
     pub fn from_params(version: String) -> Config {
         Config {
             app_env: AppEnv::Development,
+            archive: None,
+            blob: None,
+            inbound: None,
+            keystore_path: None,
             log_level: Level::INFO,
             redis_server: "redis://127.0.0.1:6359".to_string().parse().unwrap(),
             version,
@@ -80,6 +180,45 @@ impl Config {
     }
 }
 
+/// Read the settings of a blob store, selected by `store_var` (fs or s3).
+///
+/// The stores share the S3 endpoint and credentials, and the attachment store
+/// settings are the defaults of the path and the bucket.
+fn blob_config<T: Environment>(
+    env: &T,
+    store_var: &str,
+    path_var: &str,
+    bucket_var: &str,
+) -> Option<BlobConfig> {
+    let var = |name: &str, fallback: &str, default: &str| {
+        env.get_var(name)
+            .or_else(|_| env.get_var(fallback))
+            .unwrap_or_else(|_| default.to_string())
+    };
+    match env
+        .get_var(store_var)
+        .unwrap_or_default()
+        .to_lowercase()
+        .as_str()
+    {
+        "fs" => Some(BlobConfig::Filesystem {
+            path: var(path_var, "BLOB_PATH", "blobs"),
+        }),
+        "s3" => Some(BlobConfig::S3 {
+            endpoint: env
+                .get_var("S3_ENDPOINT")
+                .unwrap_or_else(|_| "http://localhost:9000".to_string()),
+            bucket: var(bucket_var, "S3_BUCKET", "pregonero"),
+            region: env
+                .get_var("S3_REGION")
+                .unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key: env.get_var("S3_ACCESS_KEY").unwrap_or_default(),
+            secret_key: env.get_var("S3_SECRET_KEY").unwrap_or_default(),
+        }),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -108,6 +247,9 @@ mod tests {
         let env = MockEnvironment { vars };
         let config = Config::from_env(&env);
         assert_eq!(config.app_env, AppEnv::Production);
+        assert_eq!(config.archive, None);
+        assert_eq!(config.blob, None);
+        assert_eq!(config.inbound, None);
         assert_eq!(config.log_level, Level::WARN);
         assert_eq!(
             config.redis_server.to_string(),
@@ -116,6 +258,73 @@ mod tests {
         assert_eq!(config.version.to_string(), "myversion".to_string());
     }
 
+    #[test]
+    fn test_config_from_env_inbound() {
+        let mut vars = std::collections::HashMap::new();
+        vars.insert("SMTP_LISTEN".to_string(), "0.0.0.0:2525".to_string());
+        vars.insert("SMTP_PROTOCOL".to_string(), "lmtp".to_string());
+        vars.insert("SMTP_MAX_MESSAGE_SIZE".to_string(), "1024".to_string());
+        vars.insert("SMTP_MAX_CONNECTIONS".to_string(), "10".to_string());
+        vars.insert("SMTP_TLS_CERT".to_string(), "cert.pem".to_string());
+        vars.insert("SMTP_TLS_KEY".to_string(), "key.pem".to_string());
+        let env = MockEnvironment { vars };
+        let config = Config::from_env(&env);
+        assert_eq!(
+            config.inbound,
+            Some(InboundConfig {
+                listen_addr: "0.0.0.0:2525".to_string(),
+                protocol: InboundProtocol::Lmtp,
+                hostname: "localhost".to_string(),
+                max_message_size: 1024,
+                max_connections: 10,
+                timeout_seconds: 300,
+                tls_cert_path: Some("cert.pem".to_string()),
+                tls_key_path: Some("key.pem".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_config_from_env_blob() {
+        let mut vars = std::collections::HashMap::new();
+        vars.insert("BLOB_STORE".to_string(), "s3".to_string());
+        vars.insert("S3_BUCKET".to_string(), "attachments".to_string());
+        vars.insert("S3_ACCESS_KEY".to_string(), "minioadmin".to_string());
+        vars.insert("S3_SECRET_KEY".to_string(), "minioadmin".to_string());
+        let env = MockEnvironment { vars };
+        let config = Config::from_env(&env);
+        assert_eq!(
+            config.blob,
+            Some(BlobConfig::S3 {
+                endpoint: "http://localhost:9000".to_string(),
+                bucket: "attachments".to_string(),
+                region: "us-east-1".to_string(),
+                access_key: "minioadmin".to_string(),
+                secret_key: "minioadmin".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_config_from_env_archive() {
+        let mut vars = std::collections::HashMap::new();
+        vars.insert("BLOB_STORE".to_string(), "fs".to_string());
+        vars.insert("BLOB_PATH".to_string(), "/data".to_string());
+        vars.insert("ARCHIVE_STORE".to_string(), "fs".to_string());
+        vars.insert("ARCHIVE_RETENTION_DAYS".to_string(), "90".to_string());
+        let env = MockEnvironment { vars };
+        let config = Config::from_env(&env);
+        assert_eq!(
+            config.archive,
+            Some(ArchiveConfig {
+                store: BlobConfig::Filesystem {
+                    path: "/data".to_string()
+                },
+                retention_days: Some(90),
+            })
+        );
+    }
+
     #[test]
     fn test_config_from_params() {
         let config = Config::from_params("test".to_string());
@@ -0,0 +1,511 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use ed25519_dalek::Verifier;
+use hickory_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+use regex::bytes::Regex;
+use rsa::{pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, Pkcs1v15Sign, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::imap::{parse_tags, AuthResult};
+
+#[async_trait]
+pub trait DnsResolver: Send + Sync {
+    /// Look up the TXT records of a name, with the strings of each record joined.
+    /// Missing names have no records.
+    async fn txt_lookup(&self, name: &str) -> Result<Vec<String>>;
+}
+
+/// Resolver configured from the system, e.g. /etc/resolv.conf.
+pub struct SystemResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl SystemResolver {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+        })
+    }
+}
+
+#[async_trait]
+impl DnsResolver for SystemResolver {
+    async fn txt_lookup(&self, name: &str) -> Result<Vec<String>> {
+        match self.resolver.txt_lookup(name).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data).to_string())
+                        .collect()
+                })
+                .collect()),
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => Ok(vec![]),
+                _ => Err(e.into()),
+            },
+        }
+    }
+}
+
+/// A failed verification, with its result ("fail", "permerror" or "temperror").
+struct Failure(&'static str, String);
+
+/// Verify the DKIM signatures of a raw message (RFC 6376).
+///
+/// The `rsa-sha256` and `ed25519-sha256` (RFC 8463) algorithms are supported,
+/// `rsa-sha1` signatures are treated as invalid, as RFC 8301 requires.
+pub async fn verify(resolver: &dyn DnsResolver, raw: &[u8]) -> Vec<AuthResult> {
+    let (headers, body) = split_message(raw);
+    let mut results = vec![];
+    for signature in headers.iter().filter(|header| header.is("DKIM-Signature")) {
+        let tags = parse_tags(&String::from_utf8_lossy(signature.value()));
+        let mut result = AuthResult {
+            result: "pass".to_string(),
+            domain: find_tag(&tags, "d").map(str::to_lowercase),
+            selector: find_tag(&tags, "s").map(str::to_string),
+            reason: None,
+        };
+        if let Err(Failure(failure, reason)) =
+            verify_signature(resolver, &headers, body, signature, &tags).await
+        {
+            result.result = failure.to_string();
+            result.reason = Some(reason);
+        }
+        debug!(
+            "-- DKIM signature of {:?}: {}",
+            result.domain, result.result
+        );
+        results.push(result);
+    }
+    results
+}
+
+async fn verify_signature(
+    resolver: &dyn DnsResolver,
+    headers: &[Header],
+    body: &[u8],
+    signature: &Header,
+    tags: &[(String, String)],
+) -> Result<(), Failure> {
+    let tag = |name: &str| find_tag(tags, name);
+    let required =
+        |name: &str| tag(name).ok_or_else(|| Failure("permerror", format!("missing tag {}", name)));
+    let without_whitespace = |value: &str| value.split_whitespace().collect::<String>();
+    let decode = |name: &str| {
+        STANDARD
+            .decode(without_whitespace(required(name)?))
+            .map_err(|_| Failure("permerror", format!("invalid tag {}", name)))
+    };
+
+    if required("v")? != "1" {
+        return Err(Failure("permerror", "unsupported version".to_string()));
+    }
+    let algorithm = required("a")?.to_lowercase();
+    let domain = required("d")?.to_lowercase();
+    let selector = required("s")?;
+    let signed_headers: Vec<String> = required("h")?
+        .split(':')
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+    if !signed_headers.iter().any(|name| name == "from") {
+        return Err(Failure("permerror", "from header not signed".to_string()));
+    }
+    if let Some(identity) = tag("i") {
+        let identity = identity
+            .rsplit('@')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        if identity != domain && !identity.ends_with(&format!(".{}", domain)) {
+            return Err(Failure("permerror", "identity not in domain".to_string()));
+        }
+    }
+    if let Some(expiration) = tag("x").and_then(|x| x.parse::<i64>().ok()) {
+        if expiration < Utc::now().timestamp() {
+            return Err(Failure("fail", "signature expired".to_string()));
+        }
+    }
+    let (header_canonicalization, body_canonicalization) =
+        match tag("c").unwrap_or("simple").to_lowercase().split_once('/') {
+            Some((header, body)) => (header.to_string(), body.to_string()),
+            None => (
+                tag("c").unwrap_or("simple").to_lowercase(),
+                "simple".to_string(),
+            ),
+        };
+    let relaxed = |canonicalization: &str| match canonicalization {
+        "simple" => Ok(false),
+        "relaxed" => Ok(true),
+        _ => Err(Failure(
+            "permerror",
+            "unsupported canonicalization".to_string(),
+        )),
+    };
+    let (relaxed_header, relaxed_body) = (
+        relaxed(&header_canonicalization)?,
+        relaxed(&body_canonicalization)?,
+    );
+
+    // The body hash
+    let mut canonical_body = canonicalize_body(body, relaxed_body);
+    if let Some(length) = tag("l") {
+        let length = length
+            .parse()
+            .map_err(|_| Failure("permerror", "invalid tag l".to_string()))?;
+        canonical_body.truncate(length);
+    }
+    if Sha256::digest(&canonical_body).as_slice() != decode("bh")?.as_slice() {
+        return Err(Failure("fail", "body hash mismatch".to_string()));
+    }
+
+    // The headers, picked from the bottom when they appear more than once
+    let mut used = vec![false; headers.len()];
+    let mut data = vec![];
+    for name in signed_headers.iter() {
+        let found = (0..headers.len())
+            .rev()
+            .find(|&i| !used[i] && headers[i].is(name));
+        if let Some(i) = found {
+            used[i] = true;
+            data.extend(canonicalize_header(&headers[i].raw, relaxed_header));
+            data.extend(b"\r\n");
+        }
+    }
+    static UNSIGNED: OnceLock<Regex> = OnceLock::new();
+    let unsigned = UNSIGNED.get_or_init(|| Regex::new(r"(^|;)(\s*b\s*=)[^;]*").unwrap());
+    let unsigned = unsigned.replace(&signature.raw, &b"$1$2"[..]);
+    data.extend(canonicalize_header(&unsigned, relaxed_header));
+    let signature = decode("b")?;
+
+    // The public key of the selector
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = resolver
+        .txt_lookup(&name)
+        .await
+        .map_err(|e| Failure("temperror", format!("key lookup failed: {}", e)))?;
+    let record = records
+        .first()
+        .ok_or_else(|| Failure("permerror", format!("no key for {}", name)))?;
+    let key_tags = parse_tags(record);
+    let key_tag = |name: &str| find_tag(&key_tags, name).map(without_whitespace);
+    let key = STANDARD
+        .decode(key_tag("p").unwrap_or_default())
+        .map_err(|_| Failure("permerror", "invalid key".to_string()))?;
+    if key.is_empty() {
+        return Err(Failure("permerror", "key revoked".to_string()));
+    }
+    let key_type = key_tag("k").unwrap_or_else(|| "rsa".to_string());
+
+    let verified = match (algorithm.as_str(), key_type.as_str()) {
+        ("rsa-sha256", "rsa") => {
+            let key = RsaPublicKey::from_public_key_der(&key)
+                .or_else(|_| RsaPublicKey::from_pkcs1_der(&key))
+                .map_err(|_| Failure("permerror", "invalid key".to_string()))?;
+            key.verify(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &Sha256::digest(&data),
+                &signature,
+            )
+            .is_ok()
+        }
+        ("ed25519-sha256", "ed25519") => {
+            let key = <[u8; 32]>::try_from(key.as_slice())
+                .ok()
+                .and_then(|key| ed25519_dalek::VerifyingKey::from_bytes(&key).ok())
+                .ok_or_else(|| Failure("permerror", "invalid key".to_string()))?;
+            let signature = ed25519_dalek::Signature::from_slice(&signature)
+                .map_err(|_| Failure("fail", "invalid signature".to_string()))?;
+            key.verify(&Sha256::digest(&data), &signature).is_ok()
+        }
+        (algorithm, key_type) => {
+            return Err(Failure(
+                "permerror",
+                format!("unsupported algorithm {} for key {}", algorithm, key_type),
+            ))
+        }
+    };
+    if !verified {
+        return Err(Failure("fail", "signature mismatch".to_string()));
+    }
+    Ok(())
+}
+
+fn find_tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|(tag, _)| tag == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// A header field as it appears in the message, with its folded lines.
+struct Header {
+    raw: Vec<u8>, // Without the trailing CRLF
+}
+
+impl Header {
+    fn name(&self) -> &[u8] {
+        let end = self
+            .raw
+            .iter()
+            .position(|&c| c == b':')
+            .unwrap_or(self.raw.len());
+        self.raw[..end].trim_ascii()
+    }
+
+    fn value(&self) -> &[u8] {
+        match self.raw.iter().position(|&c| c == b':') {
+            Some(colon) => &self.raw[colon + 1..],
+            None => &[],
+        }
+    }
+
+    fn is(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name.as_bytes())
+    }
+}
+
+/// Split a message into its header fields and its body, with CRLF line endings.
+fn split_message(raw: &[u8]) -> (Vec<Header>, &[u8]) {
+    let mut headers: Vec<Header> = vec![];
+    let mut offset = 0;
+    for line in raw.split_inclusive(|&c| c == b'\n') {
+        offset += line.len();
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return (headers, &raw[offset..]);
+        }
+        match (line.first(), headers.last_mut()) {
+            (Some(b' ') | Some(b'\t'), Some(header)) => {
+                header.raw.extend(b"\r\n");
+                header.raw.extend(line);
+            }
+            _ => headers.push(Header { raw: line.to_vec() }),
+        }
+    }
+    (headers, &[])
+}
+
+fn canonicalize_header(raw: &[u8], relaxed: bool) -> Vec<u8> {
+    if !relaxed {
+        return raw.to_vec();
+    }
+    let header = Header { raw: raw.to_vec() };
+    let mut canonical = header.name().to_ascii_lowercase();
+    canonical.push(b':');
+    let value: Vec<u8> = header
+        .value()
+        .iter()
+        .copied()
+        .filter(|&c| c != b'\r' && c != b'\n')
+        .collect();
+    canonical.extend(compress_whitespace(&value).trim_ascii());
+    canonical
+}
+
+fn canonicalize_body(body: &[u8], relaxed: bool) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = body
+        .split(|&c| c == b'\n')
+        .map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if relaxed {
+                compress_whitespace(line).trim_ascii_end().to_vec()
+            } else {
+                line.to_vec()
+            }
+        })
+        .collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return if relaxed { vec![] } else { b"\r\n".to_vec() };
+    }
+    lines
+        .into_iter()
+        .flat_map(|mut line| {
+            line.extend(b"\r\n");
+            line
+        })
+        .collect()
+}
+
+/// Replace the runs of spaces and tabs with a single space.
+fn compress_whitespace(text: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![];
+    for &c in text {
+        let is_space = c == b' ' || c == b'\t';
+        if !is_space {
+            compressed.push(c);
+        } else if compressed.last() != Some(&b' ') {
+            compressed.push(b' ');
+        }
+    }
+    compressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mocks::MockResolver;
+
+    // Signed with relaxed/relaxed RSA and simple/simple Ed25519 signatures
+    const SIGNED: &str =
+        "DKIM-Signature: v=1; a=ed25519-sha256; c=simple/simple; d=test.com; s=ed;\r
+\tt=1690884000; h=from:subject:date;\r
+\tbh=C7RlM+mAtosnhsvAdNmskSbYng0pzq29mJ1RkRmTP04=;\r
+\tb=wlRRWYYUAn9k8vdjaN7Q4FeTYuoo3eqI5xS58iB7Jw2bEOwiv+T0LzY/4iQv\r
+\tILUp0co8HKXWkvh59zUTU12rCA==\r
+DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=test.com; s=rsa;\r
+\tt=1690884000; h=from:to:subject:date:message-id:cc;\r
+\tbh=MxyuMVO+Kq43Np3VPMCGKwqeGa0xZOKUj5lLYxIJXBk=;\r
+\tb=gjg+1m7CAoYXNdWlIuxcynWdsmtk3Z5Z+tj4RPYbdajbZcFdk+FargOabok+\r
+\tIgcyI7VmNwYvleb6zBLZq/crHqjIbgvsNiHTm13YtfKBONEiRzIqwDKyrra4\r
+\t/BBdQdapEXXzWeKsK1iTqIEdwot4AKUnWT+TmpBfPSuqXJROefg=\r
+From: Ana <ana@test.com>\r
+To: bob@test.com\r
+Subject:  Quarterly   report \r
+Date: Tue, 1 Aug 2023 10:00:00 +0000\r
+Message-ID: <1@test.com>\r
+\r
+Hi Bob,  \r
+\r
+The report is  attached.\r
+\r
+\r
+";
+
+    fn resolver() -> MockResolver {
+        MockResolver::default()
+            .with_record(
+                "rsa._domainkey.test.com",
+                "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDZI1LQqONTUvX7pDH6x+qTvfEIzdKKGDzfYB6P4eF6xEnCmS8A77LzkJqtgBIl5vYU7ZKPGMplE18fFYonK64kTQ95MKO8g1xjj7TQqZWNZzTDrhEEe2k4LCVYytDYKrMJb8DOn4jyuhyBUT2kPHeTT1LEs+3oo9Prf1tebmdvyQIDAQAB",
+            )
+            .with_record(
+                "ed._domainkey.test.com",
+                "v=DKIM1; k=ed25519; p=LoCP27eRWNjMo0FLCoV7lf8d1isdHlMw8AKmaV+oyeQ=",
+            )
+    }
+
+    fn results(results: &[AuthResult]) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        results
+            .iter()
+            .map(|result| {
+                (
+                    result.result.as_str(),
+                    result.selector.as_deref(),
+                    result.reason.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let verified = verify(&resolver(), SIGNED.as_bytes()).await;
+        assert_eq!(
+            results(&verified),
+            vec![("pass", Some("ed"), None), ("pass", Some("rsa"), None)]
+        );
+        assert_eq!(verified[0].domain, Some("test.com".to_string()));
+
+        // Relaxed canonicalization accepts whitespace changes, but not simple
+        let rewrapped = SIGNED
+            .replace("Subject:  Quarterly   report ", "Subject: Quarterly report")
+            .replace(
+                "The report is  attached.\r\n\r\n",
+                "The report is attached.\r\n",
+            );
+        assert_eq!(
+            results(&verify(&resolver(), rewrapped.as_bytes()).await),
+            vec![
+                ("fail", Some("ed"), Some("body hash mismatch")),
+                ("pass", Some("rsa"), None)
+            ]
+        );
+
+        let tampered = SIGNED.replace("From: Ana", "From: Eve");
+        assert_eq!(
+            results(&verify(&resolver(), tampered.as_bytes()).await),
+            vec![
+                ("fail", Some("ed"), Some("signature mismatch")),
+                ("fail", Some("rsa"), Some("signature mismatch"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_errors() {
+        let verified = verify(&MockResolver::default(), SIGNED.as_bytes()).await;
+        assert_eq!(
+            results(&verified),
+            vec![
+                (
+                    "permerror",
+                    Some("ed"),
+                    Some("no key for ed._domainkey.test.com")
+                ),
+                (
+                    "permerror",
+                    Some("rsa"),
+                    Some("no key for rsa._domainkey.test.com")
+                )
+            ]
+        );
+
+        let revoked = MockResolver::default()
+            .with_record("rsa._domainkey.test.com", "v=DKIM1; p=")
+            .with_record(
+                "ed._domainkey.test.com",
+                "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3",
+            );
+        let sha1 = SIGNED.replace("a=rsa-sha256", "a=rsa-sha1");
+        assert_eq!(
+            results(&verify(&revoked, SIGNED.as_bytes()).await),
+            vec![
+                (
+                    "permerror",
+                    Some("ed"),
+                    Some("unsupported algorithm ed25519-sha256 for key rsa")
+                ),
+                ("permerror", Some("rsa"), Some("key revoked"))
+            ]
+        );
+        assert_eq!(
+            results(&verify(&resolver(), sha1.as_bytes()).await)[1],
+            (
+                "permerror",
+                Some("rsa"),
+                Some("unsupported algorithm rsa-sha1 for key rsa")
+            )
+        );
+
+        let unsigned_from =
+            "DKIM-Signature: v=1; a=rsa-sha256; d=test.com; s=rsa; h=subject; bh=; b=\r\n\r\n";
+        assert_eq!(
+            results(&verify(&resolver(), unsigned_from.as_bytes()).await),
+            vec![("permerror", Some("rsa"), Some("from header not signed"))]
+        );
+        assert!(verify(&resolver(), b"From: ana@test.com\r\n\r\nHi")
+            .await
+            .is_empty());
+    }
+
+    #[test]
+    fn test_canonicalize_body() {
+        let body = b"Hi  Bob, \t\r\n\r\nBye\r\n\r\n\r\n";
+        assert_eq!(
+            canonicalize_body(body, false),
+            b"Hi  Bob, \t\r\n\r\nBye\r\n"
+        );
+        assert_eq!(canonicalize_body(body, true), b"Hi Bob,\r\n\r\nBye\r\n");
+        assert_eq!(canonicalize_body(b"", false), b"\r\n");
+        assert_eq!(canonicalize_body(b"\r\n", true), b"");
+    }
+}
@@ -0,0 +1,189 @@
+use mailparse::{DispositionType, ParsedMail};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tracing::error;
+
+use super::codecs;
+
+/// Metadata of a message attachment, its content is not part of the queue payload.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Attachment {
+    pub filename: Option<String>,
+    pub mime_type: String,
+    pub size: usize, // Decoded size in bytes
+    pub content_id: Option<String>,
+    pub disposition: String, // "attachment" or "inline"
+    pub sha256: String,
+}
+
+impl fmt::Display for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {} bytes)",
+            self.filename.as_deref().unwrap_or("unnamed"),
+            self.mime_type,
+            self.size
+        )
+    }
+}
+
+/// Collect the attachments of a message, skipping the part used as the body.
+pub fn parse_attachments(
+    mail: &ParsedMail<'_>,
+    body_part: Option<&ParsedMail<'_>>,
+) -> Vec<Attachment> {
+    parse_attachment_contents(mail, body_part)
+        .into_iter()
+        .map(|(attachment, _)| attachment)
+        .collect()
+}
+
+/// Collect the attachments of a message alongside their decoded content.
+pub fn parse_attachment_contents(
+    mail: &ParsedMail<'_>,
+    body_part: Option<&ParsedMail<'_>>,
+) -> Vec<(Attachment, Vec<u8>)> {
+    let mut attachments = vec![];
+    collect(mail, body_part, &mut attachments);
+    attachments
+}
+
+fn collect(
+    part: &ParsedMail<'_>,
+    body_part: Option<&ParsedMail<'_>>,
+    attachments: &mut Vec<(Attachment, Vec<u8>)>,
+) {
+    if part.ctype.mimetype.starts_with("multipart/") {
+        for subpart in part.subparts.iter() {
+            collect(subpart, body_part, attachments);
+        }
+        return;
+    }
+    if body_part.is_some_and(|body_part| std::ptr::eq(body_part, part)) {
+        return;
+    }
+
+    let disposition = part.get_content_disposition();
+    let filename = parse_filename(part);
+    let is_attachment = disposition.disposition == DispositionType::Attachment
+        || filename.is_some()
+        || !part.ctype.mimetype.starts_with("text/");
+    if !is_attachment {
+        // Alternative representations of the body are not attachments
+        return;
+    }
+
+    let content = match part.get_body_raw() {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Unable to decode attachment {:?}: {}", filename, e);
+            return;
+        }
+    };
+    let content_id = part
+        .headers
+        .iter()
+        .find(|header| header.get_key_ref().eq_ignore_ascii_case("Content-ID"))
+        .map(|header| {
+            header
+                .get_value()
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        });
+
+    let attachment = Attachment {
+        filename,
+        mime_type: part.ctype.mimetype.clone(),
+        size: content.len(),
+        content_id,
+        disposition: match disposition.disposition {
+            DispositionType::Inline => "inline".to_string(),
+            _ => "attachment".to_string(),
+        },
+        sha256: hex::encode(Sha256::digest(&content)),
+    };
+    attachments.push((attachment, content));
+}
+
+/// Read the filename from the Content-Disposition (or the legacy Content-Type name).
+///
+/// RFC 2231 parameters are decoded by mailparse, but some clients still put
+/// RFC 2047 encoded-words in them.
+fn parse_filename(part: &ParsedMail<'_>) -> Option<String> {
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))?;
+    if filename.contains("=?") {
+        match codecs::decode_rfc2047(filename) {
+            Ok(decoded) => return Some(decoded),
+            Err(e) => error!("Unable to decode filename {}: {}", filename, e),
+        }
+    }
+    Some(filename.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"Subject: Invoice\r
+Content-Type: multipart/mixed; boundary=\"mixed\"\r
+\r
+--mixed\r
+Content-Type: multipart/related; boundary=\"related\"\r
+\r
+--related\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<html><body><img src=\"cid:logo@test\"></body></html>\r
+--related\r
+Content-Type: image/png\r
+Content-ID: <logo@test>\r
+Content-Disposition: inline\r
+Content-Transfer-Encoding: base64\r
+\r
+aGVsbG8=\r
+--related--\r
+--mixed\r
+Content-Type: application/pdf\r
+Content-Disposition: attachment; filename*=utf-8''factura%20n%C2%BA1.pdf\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERi0=\r
+--mixed\r
+Content-Type: application/octet-stream; name=\"=?UTF-8?Q?r=C3=A9sum=C3=A9.txt?=\"\r
+\r
+plain\r
+--mixed--\r
+";
+
+    #[test]
+    fn test_parse_attachments() {
+        let mail = mailparse::parse_mail(MESSAGE).unwrap();
+        let body_part = &mail.subparts[0].subparts[0];
+        let attachments = parse_attachments(&mail, Some(body_part));
+        assert_eq!(attachments.len(), 3);
+
+        assert_eq!(attachments[0].filename, None);
+        assert_eq!(attachments[0].mime_type, "image/png");
+        assert_eq!(attachments[0].size, 5);
+        assert_eq!(attachments[0].content_id, Some("logo@test".to_string()));
+        assert_eq!(attachments[0].disposition, "inline");
+        assert_eq!(
+            attachments[0].sha256,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+
+        assert_eq!(attachments[1].filename, Some("factura nº1.pdf".to_string()));
+        assert_eq!(attachments[1].mime_type, "application/pdf");
+        assert_eq!(attachments[1].disposition, "attachment");
+
+        assert_eq!(attachments[2].filename, Some("résumé.txt".to_string()));
+    }
+}
@@ -0,0 +1,403 @@
+use std::sync::OnceLock;
+
+use mailparse::{MailHeader, MailHeaderMap};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+/// The overall authentication verdict of a message.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    #[default]
+    Neutral, // Nothing was checked, or no check was conclusive
+    Pass,
+    Fail,
+}
+
+/// The result of an authentication method, e.g. "dkim=pass header.d=test.com".
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuthResult {
+    pub result: String, // e.g. pass, fail, softfail, none, neutral, temperror or permerror
+    pub domain: Option<String>, // smtp.mailfrom for SPF, header.d for DKIM, header.from for DMARC
+    pub selector: Option<String>, // header.s, for DKIM
+    pub reason: Option<String>,
+}
+
+impl AuthResult {
+    fn is(&self, result: &str) -> bool {
+        self.result == result
+    }
+}
+
+/// The results of an `Authentication-Results` header (RFC 8601).
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuthenticationResults {
+    pub authserv_id: Option<String>, // The server that performed the checks
+    pub spf: Option<AuthResult>,
+    pub dkim: Vec<AuthResult>, // One per signature
+    pub dmarc: Option<AuthResult>,
+    pub arc: Option<AuthResult>,
+}
+
+/// The ARC chain of a message forwarded by intermediaries (RFC 8617).
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ArcChain {
+    pub instances: u32,
+    pub validation: String, // Chain validation of the last seal: none, pass or fail
+    pub original: Option<AuthenticationResults>, // As seen by the first intermediary (i=1)
+}
+
+/// How a message was authenticated by the servers it went through.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Authentication {
+    pub verdict: Verdict,
+    pub results: Option<AuthenticationResults>, // From a trusted authserv-id only
+    pub arc: Option<ArcChain>,
+    #[serde(default)]
+    pub dkim_verified: Vec<AuthResult>, // Local DKIM verification, only when enabled by the account policy
+    #[serde(skip)]
+    pub reported: Vec<AuthenticationResults>, // Every Authentication-Results header, from the top
+}
+
+impl Authentication {
+    /// Keep the topmost results reported by one of the trusted servers, ignoring the
+    /// `Authentication-Results` headers of any other server (RFC 8601, section 5),
+    /// which could have been added by the sender.
+    pub fn trust(&mut self, authserv_ids: &[String]) {
+        self.results = self
+            .reported
+            .iter()
+            .find(|results| {
+                results.authserv_id.as_ref().is_some_and(|authserv_id| {
+                    authserv_ids
+                        .iter()
+                        .any(|trusted| trusted.eq_ignore_ascii_case(authserv_id))
+                })
+            })
+            .cloned();
+        self.update_verdict();
+    }
+
+    /// Compute the verdict from the trusted and the locally verified results.
+    ///
+    /// DMARC decides when present, otherwise any passing SPF or DKIM check is enough.
+    /// The ARC chain is informative only: its seals are not verified, so it never
+    /// turns a DMARC failure into a pass.
+    pub fn update_verdict(&mut self) {
+        let results = self.results.clone().unwrap_or_default();
+        // The local verification, when done, is trusted over the reported DKIM results
+        let dkim = match self.dkim_verified.is_empty() {
+            true => &results.dkim,
+            false => &self.dkim_verified,
+        };
+        let checks: Vec<&AuthResult> = results.spf.iter().chain(dkim.iter()).collect();
+
+        self.verdict = match &results.dmarc {
+            Some(dmarc) if dmarc.is("pass") => Verdict::Pass,
+            Some(dmarc) if dmarc.is("fail") => Verdict::Fail,
+            _ if checks.iter().any(|check| check.is("pass")) => Verdict::Pass,
+            _ if checks.iter().any(|check| check.is("fail")) => Verdict::Fail,
+            _ => Verdict::Neutral,
+        };
+    }
+}
+
+/// Parse the authentication headers of a message.
+///
+/// The `Authentication-Results` headers are only reported, none is trusted until
+/// [`Authentication::trust`] is called with the authserv-ids of the account. The ARC
+/// seals are not verified, their chain validation is the one reported by the sealers.
+pub fn parse_authentication(headers: &[MailHeader<'_>]) -> Authentication {
+    let mut authentication = Authentication {
+        arc: parse_arc_chain(headers),
+        reported: headers
+            .get_all_values("Authentication-Results")
+            .iter()
+            .map(|value| parse_authentication_results(value))
+            .collect(),
+        ..Default::default()
+    };
+    authentication.update_verdict();
+    authentication
+}
+
+/// Parse the value of an `Authentication-Results` header, e.g.
+/// "mx.test.com; spf=pass smtp.mailfrom=test.com; dkim=fail (bad signature) header.d=test.com".
+pub fn parse_authentication_results(value: &str) -> AuthenticationResults {
+    static PROPERTY: OnceLock<Regex> = OnceLock::new();
+    let property = PROPERTY
+        .get_or_init(|| Regex::new(r#"([\w.\-/]+)\s*=\s*("(?:[^"\\]|\\.)*"|[^\s;"]+)"#).unwrap());
+    let value = strip_comments(value);
+    let mut segments = split_outside_quotes(&value, ';').into_iter();
+
+    let mut results = AuthenticationResults {
+        authserv_id: segments
+            .next()
+            .and_then(|segment| segment.split_whitespace().next().map(str::to_string)),
+        ..Default::default()
+    };
+    for segment in segments {
+        let mut properties = property.captures_iter(segment).map(|caps| {
+            (
+                caps[1].to_lowercase(),
+                caps[2].trim_matches('"').to_string(),
+            )
+        });
+        let Some((method, result)) = properties.next() else {
+            continue;
+        };
+        let mut auth_result = AuthResult {
+            result: result.to_lowercase(),
+            ..Default::default()
+        };
+        for (key, value) in properties {
+            match key.as_str() {
+                "smtp.mailfrom" | "header.d" | "header.from" => {
+                    // The domain of an address, e.g. smtp.mailfrom=bounces@test.com
+                    let domain = value.rsplit('@').next().unwrap_or_default();
+                    auth_result.domain = Some(domain.to_lowercase());
+                }
+                "smtp.helo" if auth_result.domain.is_none() => {
+                    auth_result.domain = Some(value.to_lowercase())
+                }
+                "header.i" if auth_result.domain.is_none() => {
+                    let domain = value.rsplit('@').next().unwrap_or_default();
+                    auth_result.domain = Some(domain.to_lowercase());
+                }
+                "header.s" => auth_result.selector = Some(value),
+                "reason" => auth_result.reason = Some(value),
+                _ => {}
+            }
+        }
+        // The method may have a version, e.g. "dkim/1"
+        match method.split('/').next().unwrap_or_default() {
+            "spf" => results.spf = Some(auth_result),
+            "dkim" => results.dkim.push(auth_result),
+            "dmarc" => results.dmarc = Some(auth_result),
+            "arc" => results.arc = Some(auth_result),
+            _ => {}
+        }
+    }
+    results
+}
+
+fn parse_arc_chain(headers: &[MailHeader<'_>]) -> Option<ArcChain> {
+    let seals: Vec<(u32, String)> = headers
+        .get_all_values("ARC-Seal")
+        .iter()
+        .filter_map(|value| {
+            let tags = parse_tags(value);
+            let instance = tags.iter().find(|(tag, _)| tag == "i")?.1.parse().ok()?;
+            let validation = tags
+                .iter()
+                .find(|(tag, _)| tag == "cv")
+                .map(|(_, cv)| cv.to_lowercase())
+                .unwrap_or_default();
+            Some((instance, validation))
+        })
+        .collect();
+    let (instances, last_validation) = seals.iter().max_by_key(|(instance, _)| *instance)?;
+    // A failed chain stays failed, whatever the later seals tell
+    let validation = if seals.iter().any(|(_, validation)| validation == "fail") {
+        "fail".to_string()
+    } else if *instances == 1 && last_validation == "none" {
+        // The first seal has nothing to validate
+        "pass".to_string()
+    } else {
+        last_validation.clone()
+    };
+    let original = headers
+        .get_all_values("ARC-Authentication-Results")
+        .iter()
+        .find_map(|value| {
+            let (instance, results) = value.split_once(';')?;
+            let (tag, instance) = instance.split_once('=')?;
+            (tag.trim() == "i" && instance.trim() == "1")
+                .then(|| parse_authentication_results(results))
+        });
+    Some(ArcChain {
+        instances: *instances,
+        validation,
+        original,
+    })
+}
+
+/// Parse a tag list, e.g. "i=1; a=rsa-sha256; cv=none" (RFC 6376, section 3.2).
+pub fn parse_tags(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Remove the comments in parentheses, which may be nested, outside of quoted strings.
+fn strip_comments(value: &str) -> String {
+    let mut stripped = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' if depth == 0 => {
+                quoted = !quoted;
+                stripped.push(c);
+            }
+            '(' if !quoted => depth += 1,
+            ')' if !quoted && depth > 0 => depth -= 1,
+            _ if depth == 0 => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped
+}
+
+fn split_outside_quotes(text: &str, separator: char) -> Vec<&str> {
+    let mut segments = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            segments.push(&text[start..i]);
+            start = i + 1;
+        }
+    }
+    segments.push(&text[start..]);
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_raw(raw: &str) -> Authentication {
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        let mut authentication = parse_authentication(&headers);
+        authentication.trust(&["mx.test.com".to_string(), "mx.dest.com".to_string()]);
+        authentication
+    }
+
+    #[test]
+    fn test_parse_authentication_results() {
+        let results = parse_authentication_results(
+            "mx.test.com (mail server);\r\n spf=pass (sender IP is 10.0.0.1) smtp.mailfrom=bounces@news.test.com;\r\n dkim=pass header.d=test.com header.s=sel1 header.b=abc;\r\n dkim=fail reason=\"bad signature; key rotated\" header.i=@other.com;\r\n dmarc=pass (p=REJECT) header.from=Test.com",
+        );
+        assert_eq!(
+            results,
+            AuthenticationResults {
+                authserv_id: Some("mx.test.com".to_string()),
+                spf: Some(AuthResult {
+                    result: "pass".to_string(),
+                    domain: Some("news.test.com".to_string()),
+                    ..Default::default()
+                }),
+                dkim: vec![
+                    AuthResult {
+                        result: "pass".to_string(),
+                        domain: Some("test.com".to_string()),
+                        selector: Some("sel1".to_string()),
+                        reason: None,
+                    },
+                    AuthResult {
+                        result: "fail".to_string(),
+                        domain: Some("other.com".to_string()),
+                        selector: None,
+                        reason: Some("bad signature; key rotated".to_string()),
+                    }
+                ],
+                dmarc: Some(AuthResult {
+                    result: "pass".to_string(),
+                    domain: Some("test.com".to_string()),
+                    ..Default::default()
+                }),
+                arc: None,
+            }
+        );
+        assert_eq!(
+            parse_authentication_results("mx.test.com; none"),
+            AuthenticationResults {
+                authserv_id: Some("mx.test.com".to_string()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_authentication_verdict() {
+        let authentication = parse_raw(
+            "Authentication-Results: mx.test.com; spf=pass smtp.mailfrom=test.com; dmarc=fail header.from=test.com\r
+Authentication-Results: mx.forged.com; dmarc=pass header.from=test.com\r
+\r
+",
+        );
+        assert_eq!(authentication.verdict, Verdict::Fail);
+        assert_eq!(
+            authentication.results.unwrap().authserv_id,
+            Some("mx.test.com".to_string())
+        );
+
+        // The results of an untrusted server are ignored, even when on top
+        let authentication = parse_raw(
+            "Authentication-Results: MX.forged.com; dmarc=pass header.from=test.com
+Authentication-Results: MX.TEST.COM; dmarc=fail header.from=test.com
+
+",
+        );
+        assert_eq!(authentication.verdict, Verdict::Fail);
+        assert_eq!(authentication.reported.len(), 2);
+        let authentication = parse_raw(
+            "Authentication-Results: mx.forged.com; spf=pass; dmarc=pass header.from=test.com
+
+",
+        );
+        assert_eq!(authentication.results, None);
+        assert_eq!(authentication.verdict, Verdict::Neutral);
+
+        let authentication =
+            parse_raw("Authentication-Results: mx.test.com; spf=softfail; dkim=pass\r\n\r\n");
+        assert_eq!(authentication.verdict, Verdict::Pass);
+        let authentication = parse_raw("Authentication-Results: mx.test.com; spf=fail\r\n\r\n");
+        assert_eq!(authentication.verdict, Verdict::Fail);
+        let authentication = parse_raw("From: ana@test.com\r\n\r\n");
+        assert_eq!(authentication, Authentication::default());
+    }
+
+    #[test]
+    fn test_parse_arc_chain() {
+        // Forwarded by a mailing list, which broke the DKIM signature of the author
+        let authentication = parse_raw(
+            "ARC-Seal: i=2; a=rsa-sha256; t=1690000100; cv=pass; d=lists.test.com; s=arc; b=xyz\r
+ARC-Authentication-Results: i=2; mx.lists.test.com; dmarc=pass header.from=lists.test.com\r
+ARC-Seal: i=1; a=rsa-sha256; t=1690000000; cv=none; d=test.com; s=arc; b=abc\r
+ARC-Authentication-Results: i=1; mx.test.com;\r
+ spf=pass smtp.mailfrom=ana@test.com; dkim=pass header.d=test.com; dmarc=pass header.from=test.com\r
+Authentication-Results: mx.dest.com; dkim=fail header.d=test.com; dmarc=fail header.from=test.com; arc=pass\r
+\r
+",
+        );
+        let arc = authentication.arc.clone().unwrap();
+        assert_eq!(arc.instances, 2);
+        assert_eq!(arc.validation, "pass");
+        assert_eq!(
+            arc.original.unwrap().authserv_id,
+            Some("mx.test.com".to_string())
+        );
+        assert_eq!(authentication.results.unwrap().arc.unwrap().result, "pass");
+        // The seals are not verified, so the chain does not override the DMARC failure
+        assert_eq!(authentication.verdict, Verdict::Fail);
+
+        let authentication = parse_raw(
+            "ARC-Seal: i=2; cv=fail; d=lists.test.com\r
+ARC-Seal: i=1; cv=none; d=test.com\r
+ARC-Authentication-Results: i=1; mx.test.com; dmarc=pass header.from=test.com\r
+Authentication-Results: mx.dest.com; dmarc=fail header.from=test.com\r
+\r
+",
+        );
+        assert_eq!(authentication.arc.unwrap().validation, "fail");
+        assert_eq!(authentication.verdict, Verdict::Fail);
+    }
+}
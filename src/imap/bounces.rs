@@ -0,0 +1,505 @@
+use std::sync::OnceLock;
+
+use mailparse::{MailHeaderMap, ParsedMail};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use tracing::error;
+
+/// A delivery status notification, e.g. a bounce of a message sent to an unknown address.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Bounce {
+    pub reporting_mta: Option<String>,
+    pub original_message_id: Option<String>, // Message-ID of the message that bounced
+    pub recipients: Vec<BounceRecipient>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct BounceRecipient {
+    pub recipient: String, // The final recipient
+    pub original_recipient: Option<String>,
+    pub action: String,         // failed, delayed, delivered, relayed or expanded
+    pub status: Option<String>, // Enhanced status code (RFC 3463), e.g. "5.1.1"
+    pub permanent: bool,        // Failed with a 5.x.x status, the address should not be retried
+    pub diagnostic: Option<String>, // e.g. "550 5.1.1 User unknown"
+    pub remote_mta: Option<String>,
+}
+
+/// Parse the bounce reported by a message, if it is one.
+///
+/// Standard reports (`multipart/report; report-type=delivery-status`, RFC 3464) are
+/// read from their delivery status part. Otherwise, the messages from a mailer daemon
+/// (or a postmaster with a bounce subject) are read as the plain text bounces of Exim, qmail, Postfix
+/// and others, with the failed recipients found in `X-Failed-Recipients` or the body.
+pub fn parse_bounce(parsed: &ParsedMail<'_>, body: &str) -> Option<Bounce> {
+    let is_report = parsed.ctype.mimetype == "multipart/report"
+        && parsed
+            .ctype
+            .params
+            .get("report-type")
+            .is_some_and(|report_type| report_type.eq_ignore_ascii_case("delivery-status"));
+    let bounce = if is_report {
+        parse_delivery_status_report(parsed)
+    } else if is_bounce_sender(parsed) {
+        parse_text_bounce(parsed, body)
+    } else {
+        None
+    }?;
+    Some(Bounce {
+        original_message_id: bounce
+            .original_message_id
+            .or_else(|| original_message_id(parsed, body)),
+        ..bounce
+    })
+}
+
+fn parse_delivery_status_report(parsed: &ParsedMail<'_>) -> Option<Bounce> {
+    let status_part = find_part(parsed, &|part| {
+        matches!(
+            part.ctype.mimetype.as_str(),
+            "message/delivery-status" | "message/global-delivery-status"
+        )
+    })?;
+    let text = match status_part.get_body() {
+        Ok(text) => text,
+        Err(e) => {
+            error!("Unable to decode delivery status with mailparser: {}", e);
+            return None;
+        }
+    };
+
+    // A group of per-message fields, then a group of fields per recipient
+    let mut groups = text
+        .replace('\r', "")
+        .split("\n\n")
+        .filter(|group| !group.trim().is_empty())
+        .map(|group| format!("{}\r\n\r\n", group.trim_start_matches('\n')))
+        .collect::<Vec<_>>()
+        .into_iter();
+    let message_fields = groups.next()?;
+    let (message_fields, _) = mailparse::parse_headers(message_fields.as_bytes()).ok()?;
+    let mut bounce = Bounce {
+        reporting_mta: message_fields
+            .get_first_value("Reporting-MTA")
+            .map(|value| typed_value(&value)),
+        ..Default::default()
+    };
+    for group in groups {
+        let Ok((fields, _)) = mailparse::parse_headers(group.as_bytes()) else {
+            continue;
+        };
+        let field = |key: &str| fields.get_first_value(key).map(|value| typed_value(&value));
+        let Some(recipient) = field("Final-Recipient").or_else(|| field("Original-Recipient"))
+        else {
+            continue;
+        };
+        let status =
+            field("Status").and_then(|status| status.split_whitespace().next().map(str::to_string));
+        let action = field("Action")
+            .map(|action| action.to_lowercase())
+            .unwrap_or_else(|| "failed".to_string());
+        bounce.recipients.push(BounceRecipient {
+            recipient,
+            original_recipient: field("Original-Recipient"),
+            permanent: action == "failed"
+                && status
+                    .as_deref()
+                    .is_none_or(|status| status.starts_with('5')),
+            action,
+            status,
+            diagnostic: field("Diagnostic-Code"),
+            remote_mta: field("Remote-MTA"),
+        });
+    }
+    Some(bounce)
+}
+
+struct Patterns {
+    daemon: Regex,
+    postmaster: Regex,
+    subject: Regex,
+    explicit_address: Regex,
+    indented_address: Regex,
+    failed_intro: Regex,
+    enhanced_status: Regex,
+    smtp_reply: Regex,
+    returned_copy: Regex,
+    message_id: Regex,
+}
+
+fn patterns() -> &'static Patterns {
+    static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+    PATTERNS.get_or_init(|| Patterns {
+        daemon: Regex::new(r"(?i)\bmailer-daemon@").unwrap(),
+        postmaster: Regex::new(r"(?i)\bpostmaster@").unwrap(),
+        subject: Regex::new(
+            r"(?i)(undeliver|delivery status notification \((failure|delay)\)|mail delivery (failed|failure)|returned mail|failure notice|delivery failure)",
+        )
+        .unwrap(),
+        // qmail and Postfix: "<user@test.com>:"
+        explicit_address: Regex::new(r"(?m)^<([^<>\s]+@[^<>\s]+)>:").unwrap(),
+        // Exim and Gmail: the address on its own line, after the failure notice
+        indented_address: Regex::new(r"(?m)^[ \t]+([^<>\s@]+@[^<>\s@]+\.[^<>\s@]+)\s*$")
+            .unwrap(),
+        failed_intro: Regex::new(
+            r"(?i)(following (address(es)?|recipients?)|could not be delivered|wasn't delivered|was not delivered|delivery to the following)",
+        )
+        .unwrap(),
+        // Not part of an IP address, e.g. "10.5.0.1"
+        enhanced_status: Regex::new(r"(?:^|[^\d.])([245]\.\d{1,3}\.\d{1,3})(?:[^\d.]|$)").unwrap(),
+        smtp_reply: Regex::new(r"(?m)^.*\b([45])\d\d[ -].*$").unwrap(),
+        returned_copy: Regex::new(
+            r"(?im)^.*(original message|copy of the message|message headers follow).*$",
+        )
+        .unwrap(),
+        message_id: Regex::new(r"(?im)^\s*message-id:\s*(<[^<>\s]+>)").unwrap(),
+    })
+}
+
+/// Whether a message is a plain text bounce: it has `X-Failed-Recipients`, or comes
+/// from a mailer daemon, or from a postmaster with a bounce subject. A subject alone
+/// is not enough, anybody can write "Returned mail".
+fn is_bounce_sender(parsed: &ParsedMail<'_>) -> bool {
+    let patterns = patterns();
+    let from = parsed.headers.get_first_value("From").unwrap_or_default();
+    let subject = parsed
+        .headers
+        .get_first_value("Subject")
+        .unwrap_or_default();
+    parsed
+        .headers
+        .get_first_header("X-Failed-Recipients")
+        .is_some()
+        || patterns.daemon.is_match(&from)
+        || (patterns.postmaster.is_match(&from) && patterns.subject.is_match(&subject))
+}
+
+fn parse_text_bounce(parsed: &ParsedMail<'_>, body: &str) -> Option<Bounce> {
+    let patterns = patterns();
+    // The copy of the original message is not part of the report
+    let report = patterns
+        .returned_copy
+        .find(body)
+        .map_or(body, |found| &body[..found.start()]);
+
+    let found: Vec<String> = match parsed.headers.get_first_value("X-Failed-Recipients") {
+        Some(value) => value
+            .split(',')
+            .map(|recipient| recipient.trim().to_string())
+            .filter(|recipient| !recipient.is_empty())
+            .collect(),
+        None => {
+            let mut recipients: Vec<String> = patterns
+                .explicit_address
+                .captures_iter(report)
+                .map(|caps| caps[1].to_string())
+                .collect();
+            // Only the indented addresses listed after the failure notice are failed
+            // recipients, not any address quoted in the text
+            if let Some(intro) = patterns.failed_intro.find(report) {
+                recipients.extend(
+                    patterns
+                        .indented_address
+                        .captures_iter(&report[intro.end()..])
+                        .map(|caps| caps[1].to_string()),
+                );
+            }
+            recipients
+        }
+    };
+    // A recipient may be listed again, e.g. in the quoted headers
+    let mut recipients: Vec<String> = vec![];
+    for recipient in found {
+        if !recipients.contains(&recipient) {
+            recipients.push(recipient);
+        }
+    }
+    if recipients.is_empty() {
+        return None;
+    }
+
+    let diagnostic = patterns.smtp_reply.captures(report);
+    let status = patterns
+        .enhanced_status
+        .captures(report)
+        .map(|caps| caps[1].to_string())
+        .or_else(|| {
+            // The class of the basic SMTP reply code, e.g. 5.0.0 for "550 User unknown"
+            let class = diagnostic.as_ref()?.get(1)?.as_str();
+            Some(format!("{}.0.0", class))
+        });
+    let action = match status.as_deref() {
+        Some(status) if status.starts_with('4') => "delayed",
+        _ => "failed",
+    };
+    // Without a status or reply code, the failure may still be transient
+    let permanent = status
+        .as_deref()
+        .is_some_and(|status| status.starts_with('5'));
+    Some(Bounce {
+        reporting_mta: None,
+        original_message_id: None,
+        recipients: recipients
+            .into_iter()
+            .map(|recipient| BounceRecipient {
+                recipient,
+                original_recipient: None,
+                action: action.to_string(),
+                status: status.clone(),
+                permanent,
+                diagnostic: diagnostic.as_ref().map(|caps| caps[0].trim().to_string()),
+                remote_mta: None,
+            })
+            .collect(),
+    })
+}
+
+/// The Message-ID of the original message, from the returned copy or its headers.
+fn original_message_id(parsed: &ParsedMail<'_>, body: &str) -> Option<String> {
+    let returned = find_part(parsed, &|part| {
+        matches!(
+            part.ctype.mimetype.as_str(),
+            "message/rfc822" | "text/rfc822-headers" | "message/global"
+        )
+    });
+    if let Some(returned) = returned {
+        let raw = returned.get_body_raw().ok()?;
+        let (headers, _) = mailparse::parse_headers(&raw).ok()?;
+        return headers
+            .get_first_value("Message-ID")
+            .map(|value| value.trim().to_string());
+    }
+    // Copied inline in the text
+    patterns()
+        .message_id
+        .captures(body)
+        .map(|caps| caps[1].to_string())
+}
+
+fn find_part<'a>(
+    part: &'a ParsedMail<'a>,
+    predicate: &dyn Fn(&ParsedMail<'_>) -> bool,
+) -> Option<&'a ParsedMail<'a>> {
+    if predicate(part) {
+        return Some(part);
+    }
+    part.subparts
+        .iter()
+        .find_map(|subpart| find_part(subpart, predicate))
+}
+
+/// The value of a typed field, e.g. "rfc822; user@test.com" or "smtp; 550 User unknown".
+fn typed_value(value: &str) -> String {
+    match value.split_once(';') {
+        Some((_, value)) => value.trim().to_string(),
+        None => value.trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_raw(raw: &str) -> Option<Bounce> {
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let body = parsed
+            .subparts
+            .first()
+            .unwrap_or(&parsed)
+            .get_body()
+            .unwrap();
+        parse_bounce(&parsed, &body)
+    }
+
+    #[test]
+    fn test_parse_delivery_status_report() {
+        let bounce = parse_raw(
+            "From: MAILER-DAEMON@mx.test.com\r
+Subject: Undelivered Mail Returned to Sender\r
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"report\"\r
+\r
+--report\r
+Content-Type: text/plain\r
+\r
+I'm sorry to have to inform you that your message could not be delivered.\r
+--report\r
+Content-Type: message/delivery-status\r
+\r
+Reporting-MTA: dns; mx.test.com\r
+Arrival-Date: Tue,  1 Aug 2023 10:00:00 +0000\r
+\r
+Final-Recipient: rfc822; missing@other.com\r
+Original-Recipient: rfc822; Missing@other.com\r
+Action: failed\r
+Status: 5.1.1\r
+Remote-MTA: dns; mx.other.com\r
+Diagnostic-Code: smtp; 550 5.1.1 <missing@other.com>: Recipient address\r
+ rejected: User unknown\r
+\r
+Final-Recipient: rfc822; full@other.com\r
+Action: delayed\r
+Status: 4.2.2 (mailbox full)\r
+\r
+--report\r
+Content-Type: text/rfc822-headers\r
+\r
+From: ana@test.com\r
+Message-ID: <1@test.com>\r
+\r
+--report--\r
+",
+        );
+        assert_eq!(
+            bounce,
+            Some(Bounce {
+                reporting_mta: Some("mx.test.com".to_string()),
+                original_message_id: Some("<1@test.com>".to_string()),
+                recipients: vec![
+                    BounceRecipient {
+                        recipient: "missing@other.com".to_string(),
+                        original_recipient: Some("Missing@other.com".to_string()),
+                        action: "failed".to_string(),
+                        status: Some("5.1.1".to_string()),
+                        permanent: true,
+                        diagnostic: Some(
+                            "550 5.1.1 <missing@other.com>: Recipient address rejected: User unknown"
+                                .to_string()
+                        ),
+                        remote_mta: Some("mx.other.com".to_string()),
+                    },
+                    BounceRecipient {
+                        recipient: "full@other.com".to_string(),
+                        original_recipient: None,
+                        action: "delayed".to_string(),
+                        status: Some("4.2.2".to_string()),
+                        permanent: false,
+                        diagnostic: None,
+                        remote_mta: None,
+                    }
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_text_bounces() {
+        // Exim
+        let bounce = parse_raw(
+            "From: Mail Delivery System <Mailer-Daemon@mx.test.com>\r
+Subject: Mail delivery failed: returning message to sender\r
+X-Failed-Recipients: missing@other.com\r
+\r
+This message was created automatically by mail delivery software.\r
+\r
+A message that you sent could not be delivered to one or more of its\r
+recipients. This is a permanent error. The following address(es) failed:\r
+\r
+  missing@other.com\r
+    host mx.other.com [10.0.0.1]\r
+    SMTP error from remote mail server after RCPT TO:<missing@other.com>:\r
+    550 5.1.1 User unknown\r
+\r
+------ This is a copy of the message, including all the headers. ------\r
+\r
+Message-ID: <2@test.com>\r
+From: ana@test.com\r
+",
+        )
+        .unwrap();
+        assert_eq!(bounce.original_message_id, Some("<2@test.com>".to_string()));
+        assert_eq!(
+            bounce.recipients,
+            vec![BounceRecipient {
+                recipient: "missing@other.com".to_string(),
+                original_recipient: None,
+                action: "failed".to_string(),
+                status: Some("5.1.1".to_string()),
+                permanent: true,
+                diagnostic: Some("550 5.1.1 User unknown".to_string()),
+                remote_mta: None,
+            }]
+        );
+
+        // qmail
+        let bounce = parse_raw(
+            "From: MAILER-DAEMON@mail.test.com\r
+Subject: failure notice\r
+\r
+Hi. This is the qmail-send program at mail.test.com.\r
+I'm afraid I wasn't able to deliver your message to the following addresses.\r
+\r
+<full@other.com>:\r
+10.0.0.1 does not like recipient.\r
+Remote host said: 452 Mailbox full\r
+\r
+--- Below this line is a copy of the message.\r
+",
+        )
+        .unwrap();
+        assert_eq!(bounce.recipients.len(), 1);
+        assert_eq!(bounce.recipients[0].recipient, "full@other.com");
+        assert_eq!(bounce.recipients[0].action, "delayed");
+        assert_eq!(bounce.recipients[0].status, Some("4.0.0".to_string()));
+        assert!(!bounce.recipients[0].permanent);
+    }
+
+    #[test]
+    fn test_parse_bounce_ordinary_messages() {
+        assert_eq!(
+            parse_raw("From: ana@test.com\r\nSubject: Hi\r\n\r\n  bob@test.com\r\n"),
+            None
+        );
+        // From a mailer daemon, but without any recipient
+        assert_eq!(
+            parse_raw(
+                "From: postmaster@test.com\r\nSubject: Quota\r\n\r\nYour mailbox is full\r\n"
+            ),
+            None
+        );
+        // A bounce subject is not enough
+        assert_eq!(
+            parse_raw(
+                "From: ana@test.com\r\nSubject: Returned mail\r\n\r\n<bob@test.com>:\r\n550 User unknown\r\n"
+            ),
+            None
+        );
+        assert_eq!(
+            parse_raw(
+                "From: postmaster@test.com\r\nSubject: New policy\r\n\r\nThe following addresses changed:\r\n  bob@test.com\r\n"
+            ),
+            None
+        );
+        // Only the addresses after the failure notice, and not permanent without a code
+        let bounce = parse_raw(
+            "From: MAILER-DAEMON@mx.test.com\r\nSubject: Undeliverable\r\n\r\nContact:\r\n  help@test.com\r\n\r\nYour message could not be delivered to:\r\n  bob@other.com\r\n",
+        )
+        .unwrap();
+        assert_eq!(bounce.recipients.len(), 1);
+        assert_eq!(bounce.recipients[0].recipient, "bob@other.com");
+        assert_eq!(bounce.recipients[0].status, None);
+        assert!(!bounce.recipients[0].permanent);
+    }
+
+    #[test]
+    fn test_parse_text_bounce_repeated_recipients() {
+        let bounce = parse_raw(
+            "From: MAILER-DAEMON@mx.test.com\r
+Subject: Undeliverable\r
+\r
+<bob@other.com>:\r
+550 5.1.1 User unknown\r
+\r
+The following addresses failed:\r
+  carl@other.com\r
+  bob@other.com\r
+  carl@other.com\r
+",
+        )
+        .unwrap();
+        let recipients: Vec<&str> = bounce
+            .recipients
+            .iter()
+            .map(|recipient| recipient.recipient.as_str())
+            .collect();
+        assert_eq!(recipients, vec!["bob@other.com", "carl@other.com"]);
+    }
+}
@@ -0,0 +1,428 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use mailparse::ParsedMail;
+use serde_derive::{Deserialize, Serialize};
+use tracing::error;
+
+/// An event of a calendar invitation (iCalendar VEVENT, RFC 5545).
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct CalendarEvent {
+    pub method: Option<String>, // iTIP method of the calendar, e.g. REQUEST, CANCEL or REPLY
+    pub uid: Option<String>,
+    pub sequence: Option<u32>,
+    pub status: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub organizer: Option<Attendee>,
+    pub attendees: Vec<Attendee>,
+    pub start: Option<EventTime>,
+    pub end: Option<EventTime>,
+    pub recurrence_rule: Option<String>, // RRULE value, e.g. "FREQ=WEEKLY;BYDAY=MO"
+    pub recurrence_id: Option<EventTime>, // The instance of a recurring event it overrides
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Attendee {
+    pub name: Option<String>,
+    pub email: String,
+    pub role: Option<String>,   // e.g. REQ-PARTICIPANT
+    pub status: Option<String>, // Participation status, e.g. ACCEPTED
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct EventTime {
+    pub value: String,             // ISO 8601 local time, e.g. "2023-08-01T10:00:00"
+    pub time_zone: Option<String>, // "UTC", the TZID, or floating time when missing
+    pub all_day: bool,             // The value is a date, e.g. "2023-08-01"
+}
+
+/// A content line of an iCalendar object: "NAME;PARAM=value:value".
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Collect the events of the calendar parts of a message (`text/calendar` or `.ics`).
+///
+/// Clients send the same invitation inline and as an attachment (e.g. invite.ics),
+/// so an event is kept once by its UID, SEQUENCE and RECURRENCE-ID.
+pub fn parse_calendar_parts(part: &ParsedMail<'_>) -> Vec<CalendarEvent> {
+    let mut events: Vec<CalendarEvent> = vec![];
+    for event in collect_calendar_parts(part) {
+        let duplicate = events.iter().any(|kept| match (&kept.uid, &event.uid) {
+            (Some(kept_uid), Some(uid)) => {
+                kept_uid == uid
+                    && kept.sequence == event.sequence
+                    && kept.recurrence_id == event.recurrence_id
+            }
+            _ => *kept == event,
+        });
+        if !duplicate {
+            events.push(event);
+        }
+    }
+    events
+}
+
+fn collect_calendar_parts(part: &ParsedMail<'_>) -> Vec<CalendarEvent> {
+    if part.ctype.mimetype.starts_with("multipart/") {
+        return part
+            .subparts
+            .iter()
+            .flat_map(collect_calendar_parts)
+            .collect();
+    }
+    if !is_calendar(part) {
+        return vec![];
+    }
+    match part.get_body() {
+        Ok(text) => parse_calendar(&text),
+        Err(e) => {
+            error!("Unable to decode calendar with mailparser: {}", e);
+            vec![]
+        }
+    }
+}
+
+fn is_calendar(part: &ParsedMail<'_>) -> bool {
+    let mimetype = part.ctype.mimetype.as_str();
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"));
+    mimetype == "text/calendar"
+        || mimetype == "application/ics"
+        || filename.is_some_and(|filename| filename.to_lowercase().ends_with(".ics"))
+}
+
+/// Parse the VEVENT components of an iCalendar object.
+pub fn parse_calendar(text: &str) -> Vec<CalendarEvent> {
+    let mut events = vec![];
+    let mut method = None;
+    let mut event: Option<CalendarEvent> = None;
+    // Nested components (e.g. VALARM) do not describe the event
+    let mut nested = 0;
+    for property in unfold(text).iter().filter_map(|line| parse_property(line)) {
+        match (
+            property.name.as_str(),
+            property.value.to_uppercase().as_str(),
+        ) {
+            ("BEGIN", "VEVENT") => event = Some(CalendarEvent::default()),
+            ("END", "VEVENT") => {
+                if let Some(mut event) = event.take() {
+                    event.method = method.clone();
+                    events.push(event);
+                }
+            }
+            ("BEGIN", _) if event.is_some() => nested += 1,
+            ("END", _) if event.is_some() => nested -= 1,
+            ("METHOD", value) if event.is_none() => method = Some(value.to_string()),
+            _ => {
+                if let Some(event) = event.as_mut().filter(|_| nested == 0) {
+                    apply_property(event, property);
+                }
+            }
+        }
+    }
+    events
+}
+
+fn apply_property(event: &mut CalendarEvent, property: Property) {
+    match property.name.as_str() {
+        "UID" => event.uid = Some(property.value),
+        "SEQUENCE" => event.sequence = property.value.trim().parse().ok(),
+        "STATUS" => event.status = Some(property.value.to_uppercase()),
+        "SUMMARY" => event.summary = Some(unescape(&property.value)),
+        "DESCRIPTION" => event.description = Some(unescape(&property.value)),
+        "LOCATION" => event.location = Some(unescape(&property.value)),
+        "ORGANIZER" => event.organizer = Some(parse_attendee(&property)),
+        "ATTENDEE" => event.attendees.push(parse_attendee(&property)),
+        "DTSTART" => event.start = parse_time(&property),
+        "DTEND" => event.end = parse_time(&property),
+        "RRULE" => event.recurrence_rule = Some(property.value),
+        "RECURRENCE-ID" => event.recurrence_id = parse_time(&property),
+        _ => {}
+    }
+}
+
+fn parse_attendee(property: &Property) -> Attendee {
+    let value = property.value.trim();
+    let email = match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &value[7..],
+        _ => value,
+    };
+    Attendee {
+        name: property.param("CN").map(str::to_string),
+        email: email.to_string(),
+        role: property.param("ROLE").map(str::to_uppercase),
+        status: property.param("PARTSTAT").map(str::to_uppercase),
+    }
+}
+
+fn parse_time(property: &Property) -> Option<EventTime> {
+    let value = property.value.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Some(EventTime {
+            value: date.format("%Y-%m-%d").to_string(),
+            time_zone: None,
+            all_day: true,
+        });
+    }
+    let (value, time_zone) = match value.strip_suffix('Z') {
+        Some(value) => (value, Some("UTC".to_string())),
+        None => (value, property.param("TZID").map(str::to_string)),
+    };
+    match NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        Ok(time) => Some(EventTime {
+            value: time.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            time_zone,
+            all_day: false,
+        }),
+        Err(e) => {
+            error!("Unable to parse calendar time {}: {}", property.value, e);
+            None
+        }
+    }
+}
+
+/// Join the folded lines, which continue with a leading space or tab.
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+fn parse_property(line: &str) -> Option<Property> {
+    // The value starts at the first colon outside of a quoted parameter value
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let mut segments = split_params(&line[..colon]).into_iter();
+    let name = segments.next()?.to_uppercase();
+    let params = segments
+        .filter_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            Some((key.to_uppercase(), value.trim_matches('"').to_string()))
+        })
+        .collect();
+    Some(Property {
+        name,
+        params,
+        value: line[colon + 1..].to_string(),
+    })
+}
+
+fn split_params(text: &str) -> Vec<&str> {
+    let mut segments = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                segments.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&text[start..]);
+    segments
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVITE: &str = "BEGIN:VCALENDAR\r
+PRODID:-//Test//EN\r
+VERSION:2.0\r
+METHOD:REQUEST\r
+BEGIN:VTIMEZONE\r
+TZID:Europe/Madrid\r
+END:VTIMEZONE\r
+BEGIN:VEVENT\r
+UID:meeting-1@test.com\r
+SEQUENCE:2\r
+SUMMARY:Weekly sync\\, planning\r
+DESCRIPTION:Agenda:\\n- Roadmap\r
+LOCATION:Room 1\r
+ORGANIZER;CN=\"Ana, PM\":mailto:ana@test.com\r
+ATTENDEE;ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;CN=Bob:MAILTO:bob@te\r
+ st.com\r
+DTSTART;TZID=Europe/Madrid:20230801T100000\r
+DTEND:20230801T090000Z\r
+RRULE:FREQ=WEEKLY;BYDAY=TU\r
+BEGIN:VALARM\r
+DESCRIPTION:Reminder\r
+END:VALARM\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    #[test]
+    fn test_parse_calendar() {
+        let events = parse_calendar(INVITE);
+        assert_eq!(
+            events,
+            vec![CalendarEvent {
+                method: Some("REQUEST".to_string()),
+                uid: Some("meeting-1@test.com".to_string()),
+                sequence: Some(2),
+                status: None,
+                summary: Some("Weekly sync, planning".to_string()),
+                description: Some("Agenda:\n- Roadmap".to_string()),
+                location: Some("Room 1".to_string()),
+                organizer: Some(Attendee {
+                    name: Some("Ana, PM".to_string()),
+                    email: "ana@test.com".to_string(),
+                    role: None,
+                    status: None,
+                }),
+                attendees: vec![Attendee {
+                    name: Some("Bob".to_string()),
+                    email: "bob@test.com".to_string(),
+                    role: Some("REQ-PARTICIPANT".to_string()),
+                    status: Some("NEEDS-ACTION".to_string()),
+                }],
+                start: Some(EventTime {
+                    value: "2023-08-01T10:00:00".to_string(),
+                    time_zone: Some("Europe/Madrid".to_string()),
+                    all_day: false,
+                }),
+                end: Some(EventTime {
+                    value: "2023-08-01T09:00:00".to_string(),
+                    time_zone: Some("UTC".to_string()),
+                    all_day: false,
+                }),
+                recurrence_rule: Some("FREQ=WEEKLY;BYDAY=TU".to_string()),
+                recurrence_id: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_calendar_parts() {
+        let raw = format!(
+            "Subject: Cancelled\r
+Content-Type: multipart/mixed; boundary=\"mixed\"\r
+\r
+--mixed\r
+Content-Type: text/plain\r
+\r
+The event was cancelled\r
+--mixed\r
+Content-Type: application/octet-stream; name=\"invite.ics\"\r
+\r
+{}\r
+--mixed--\r
+",
+            "BEGIN:VCALENDAR\r\nMETHOD:CANCEL\r\nBEGIN:VEVENT\r\nUID:1\r\nDTSTART;VALUE=DATE:20230801\r\nEND:VEVENT\r\nEND:VCALENDAR"
+        );
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let events = parse_calendar_parts(&parsed);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].method, Some("CANCEL".to_string()));
+        assert_eq!(
+            events[0].start,
+            Some(EventTime {
+                value: "2023-08-01".to_string(),
+                time_zone: None,
+                all_day: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_calendar_parts_inline_and_attachment() {
+        let calendar = |uid: &str, recurrence_id: &str| {
+            format!(
+                "BEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nBEGIN:VEVENT\r\nUID:{}\r\nSEQUENCE:0\r\n{}END:VEVENT\r\nEND:VCALENDAR",
+                uid, recurrence_id
+            )
+        };
+        let raw = format!(
+            "Subject: Invitation\r
+Content-Type: multipart/mixed; boundary=\"mixed\"\r
+\r
+--mixed\r
+Content-Type: multipart/alternative; boundary=\"alternative\"\r
+\r
+--alternative\r
+Content-Type: text/plain\r
+\r
+You are invited\r
+--alternative\r
+Content-Type: text/calendar; method=REQUEST\r
+\r
+{}\r
+--alternative--\r
+--mixed\r
+Content-Type: application/ics; name=\"invite.ics\"\r
+Content-Disposition: attachment; filename=\"invite.ics\"\r
+\r
+{}\r
+--mixed\r
+Content-Type: text/calendar\r
+\r
+{}\r
+--mixed--\r
+",
+            calendar("1", ""),
+            calendar("1", ""),
+            calendar("1", "RECURRENCE-ID:20230808T100000Z\r\n"),
+        );
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let events = parse_calendar_parts(&parsed);
+        // The attached copy is dropped, but not the instance of the recurring event
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].recurrence_id, None);
+        assert_eq!(
+            events[1].recurrence_id,
+            Some(EventTime {
+                value: "2023-08-08T10:00:00".to_string(),
+                time_zone: Some("UTC".to_string()),
+                all_day: false,
+            })
+        );
+    }
+}
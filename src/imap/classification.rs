@@ -0,0 +1,186 @@
+use std::sync::OnceLock;
+
+use mailparse::{MailHeader, MailHeaderMap};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+/// The kind of sender of a message, as told by its headers.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageClass {
+    #[default]
+    Personal,
+    AutoReply,     // Out-of-office and other automatic replies
+    Bulk,          // Newsletters and marketing
+    List,          // Mailing list discussions
+    Transactional, // Automated notifications, e.g. receipts or password resets
+}
+
+/// Metadata of the mailing list a message was sent through (RFC 2919 and RFC 2369).
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ListInfo {
+    pub id: Option<String>, // e.g. "users.lists.test.com"
+    pub name: Option<String>,
+    pub unsubscribe: Vec<String>,    // mailto: and http(s): URLs
+    pub one_click_unsubscribe: bool, // RFC 8058 List-Unsubscribe-Post
+}
+
+/// Classify a message from its `Auto-Submitted`, `Precedence`, `X-Autoreply`,
+/// `List-*` and `Feedback-ID` headers, reading its list metadata if any.
+pub fn classify(headers: &[MailHeader<'_>]) -> (MessageClass, Option<ListInfo>) {
+    let value = |key: &str| {
+        headers
+            .get_first_value(key)
+            .map(|value| value.trim().to_lowercase())
+    };
+    let auto_submitted = value("Auto-Submitted").filter(|value| !value.starts_with("no"));
+    let precedence = value("Precedence");
+    let list = parse_list_info(headers);
+
+    let is_auto_reply = auto_submitted
+        .as_deref()
+        .is_some_and(|value| value.starts_with("auto-replied"))
+        || precedence.as_deref() == Some("auto_reply")
+        || value("X-Autoreply").is_some_and(|value| value == "yes")
+        || headers.get_first_header("X-Autorespond").is_some();
+    // Newsletters are sent with a List-Id too, so their precedence is checked first
+    let class = if is_auto_reply {
+        MessageClass::AutoReply
+    } else if matches!(precedence.as_deref(), Some("bulk") | Some("junk")) {
+        MessageClass::Bulk
+    } else if headers.get_first_header("List-Id").is_some() || precedence.as_deref() == Some("list")
+    {
+        MessageClass::List
+    } else if headers.get_first_header("List-Unsubscribe").is_some() {
+        MessageClass::Bulk
+    } else if auto_submitted.is_some() || headers.get_first_header("Feedback-ID").is_some() {
+        // Sent by a machine, but not meant to be unsubscribed from
+        MessageClass::Transactional
+    } else {
+        MessageClass::Personal
+    };
+    (class, list)
+}
+
+fn parse_list_info(headers: &[MailHeader<'_>]) -> Option<ListInfo> {
+    let list_id = headers.get_first_value("List-Id");
+    let unsubscribe = headers.get_first_value("List-Unsubscribe");
+    if list_id.is_none() && unsubscribe.is_none() {
+        return None;
+    }
+    static BRACKETED: OnceLock<Regex> = OnceLock::new();
+    let bracketed = BRACKETED.get_or_init(|| Regex::new(r"<([^<>]*)>").unwrap());
+
+    let (id, name) = match list_id {
+        Some(list_id) => match bracketed.captures(&list_id) {
+            Some(caps) => {
+                let name = list_id[..caps.get(0).unwrap().start()]
+                    .trim()
+                    .trim_matches('"')
+                    .to_string();
+                (
+                    Some(caps[1].trim().to_string()),
+                    Some(name).filter(|name| !name.is_empty()),
+                )
+            }
+            None => (Some(list_id.trim().to_string()), None),
+        },
+        None => (None, None),
+    };
+    let unsubscribe = unsubscribe
+        .map(|value| {
+            bracketed
+                .captures_iter(&value)
+                .map(|caps| caps[1].trim().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    let one_click_unsubscribe = headers
+        .get_first_value("List-Unsubscribe-Post")
+        .is_some_and(|value| {
+            value
+                .trim()
+                .eq_ignore_ascii_case("List-Unsubscribe=One-Click")
+        });
+    Some(ListInfo {
+        id,
+        name,
+        unsubscribe,
+        one_click_unsubscribe,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify_raw(raw: &str) -> (MessageClass, Option<ListInfo>) {
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        classify(&headers)
+    }
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            ("From: ana@test.com\r\n\r\n", MessageClass::Personal),
+            ("Auto-Submitted: no\r\n\r\n", MessageClass::Personal),
+            (
+                "Auto-Submitted: auto-replied\r\n\r\n",
+                MessageClass::AutoReply,
+            ),
+            ("X-Autoreply: yes\r\n\r\n", MessageClass::AutoReply),
+            ("Precedence: auto_reply\r\n\r\n", MessageClass::AutoReply),
+            ("List-Id: <dev.lists.test.com>\r\n\r\n", MessageClass::List),
+            ("Precedence: bulk\r\n\r\n", MessageClass::Bulk),
+            (
+                "List-Id: <news.test.com>\r\nPrecedence: bulk\r\n\r\n",
+                MessageClass::Bulk,
+            ),
+            (
+                "List-Id: <news.test.com>\r\nPrecedence: junk\r\n\r\n",
+                MessageClass::Bulk,
+            ),
+            (
+                "List-Unsubscribe: <https://test.com/u>\r\nFeedback-ID: 1:news:esp\r\n\r\n",
+                MessageClass::Bulk,
+            ),
+            (
+                "Feedback-ID: 1:receipts:esp\r\n\r\n",
+                MessageClass::Transactional,
+            ),
+            (
+                "Auto-Submitted: auto-generated\r\n\r\n",
+                MessageClass::Transactional,
+            ),
+        ];
+        for (raw, class) in cases {
+            assert_eq!(classify_raw(raw).0, class, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn test_parse_list_info() {
+        let (class, list) = classify_raw(
+            "List-Id: \"Rust Users\" <users.lists.test.com>\r
+List-Unsubscribe: <mailto:leave@test.com?subject=unsubscribe>,\r
+ <https://test.com/unsubscribe/1>\r
+List-Unsubscribe-Post: List-Unsubscribe=One-Click\r
+\r
+",
+        );
+        assert_eq!(class, MessageClass::List);
+        assert_eq!(
+            list,
+            Some(ListInfo {
+                id: Some("users.lists.test.com".to_string()),
+                name: Some("Rust Users".to_string()),
+                unsubscribe: vec![
+                    "mailto:leave@test.com?subject=unsubscribe".to_string(),
+                    "https://test.com/unsubscribe/1".to_string()
+                ],
+                one_click_unsubscribe: true,
+            })
+        );
+        assert_eq!(classify_raw("From: ana@test.com\r\n\r\n").1, None);
+    }
+}
@@ -1,40 +1,124 @@
-use encoding::all::ISO_8859_1;
-use encoding::{DecoderTrap, Encoding};
+use std::sync::OnceLock;
+
+use base64::engine::{general_purpose::GeneralPurposeConfig, DecodePaddingMode, GeneralPurpose};
+use base64::{alphabet, Engine};
+use encoding::label::encoding_from_whatwg_label;
+use encoding::{all, DecoderTrap, EncodingRef};
 use quoted_printable::{decode as qp_decode, ParseMode};
 use regex::Regex;
-use std::str;
+use tracing::warn;
+
+/// Base64 as found in encoded-words, where some clients drop the padding.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
 
+/// Decode a header value containing RFC 2047 encoded-words.
+///
+/// Plain text around the encoded-words is kept, while the whitespace between two
+/// adjacent encoded-words is dropped. Adjacent words in the same charset are decoded
+/// together, since clients split multibyte characters across them. Any WHATWG
+/// charset label is supported (e.g. GB2312, ISO-2022-JP, windows-1252, KOI8-R).
 pub fn decode_rfc2047(input: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let re = Regex::new(r"(?i)\=\?(.*?)\?(q|b)\?(.*?)\?\=").unwrap();
+    static ENCODED_WORD: OnceLock<Regex> = OnceLock::new();
+    let re =
+        ENCODED_WORD.get_or_init(|| Regex::new(r"=\?([^?\s]+)\?([QqBb])\?([^?\s]*)\?=").unwrap());
 
     if !re.is_match(input) {
         return Err("Input is not a valid RFC 2047 encoded-word".into());
     }
 
-    let caps = re.captures(input).unwrap();
+    let mut decoded = String::new();
+    // Bytes of the adjacent encoded-words not yet decoded, with their charset
+    let mut pending: Option<(String, Vec<u8>)> = None;
+    let mut last_end = 0;
+    for caps in re.captures_iter(input) {
+        let word = caps.get(0).unwrap();
+        let between = &input[last_end..word.start()];
+        let adjacent = last_end > 0 && between.trim().is_empty();
+        if !adjacent {
+            flush(&mut pending, &mut decoded)?;
+            decoded.push_str(between);
+        }
+        last_end = word.end();
 
-    let charset = &caps[1];
-    let encoding = &caps[2].to_uppercase();
-    let encoded_text = &caps[3];
+        // The charset may carry an RFC 2231 language suffix (e.g. "utf-8*en")
+        let charset = caps[1].split('*').next().unwrap_or_default().to_lowercase();
+        let bytes = match caps[2].to_uppercase().as_str() {
+            // Encode underscores as spaces, so trailing ones are not stripped
+            "Q" => qp_decode(caps[3].replace('_', "=20"), ParseMode::Robust)?,
+            _ => BASE64.decode(&caps[3])?,
+        };
+        match &mut pending {
+            Some((pending_charset, pending_bytes)) if *pending_charset == charset => {
+                pending_bytes.extend(bytes)
+            }
+            _ => {
+                flush(&mut pending, &mut decoded)?;
+                pending = Some((charset, bytes));
+            }
+        }
+    }
+    flush(&mut pending, &mut decoded)?;
+    decoded.push_str(&input[last_end..]);
+    Ok(decoded)
+}
 
-    let decoded_bytes = match encoding.as_str() {
-        "Q" => qp_decode(encoded_text.replace('_', " "), ParseMode::Robust)?,
-        _ => return Err(format!("Unsupported encoding: {}", encoding).into()),
-    };
+/// Decode the pending bytes of adjacent encoded-words into the output.
+fn flush(
+    pending: &mut Option<(String, Vec<u8>)>,
+    decoded: &mut String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some((charset, bytes)) = pending.take() {
+        let encoding = encoding_from_whatwg_label(&charset)
+            .ok_or_else(|| format!("Unsupported charset: {}", charset))?;
+        decoded.push_str(&encoding.decode(&bytes, DecoderTrap::Replace)?);
+    }
+    Ok(())
+}
 
-    match charset.to_uppercase().as_str() {
-        "UTF-8" | "UTF8" => {
-            let decoded_str = str::from_utf8(&decoded_bytes)?;
-            Ok(decoded_str.to_string())
+/// Decode a text body, with its declared charset or a sniffed one when missing.
+///
+/// Returns the text and whether the decoding was lossy, i.e. invalid sequences
+/// were replaced with U+FFFD rather than dropping the whole body.
+pub fn decode_text(bytes: &[u8], charset: Option<&str>) -> (String, bool) {
+    let declared = charset.and_then(|charset| {
+        let encoding = encoding_from_whatwg_label(charset.trim().trim_matches('"'));
+        if encoding.is_none() {
+            warn!("Unsupported charset {}, sniffing it instead", charset);
         }
-        "ISO-8859-1" => {
-            let decoded_str = ISO_8859_1.decode(&decoded_bytes, DecoderTrap::Strict)?;
-            Ok(decoded_str.to_string())
+        encoding
+    });
+    let encoding = declared.unwrap_or_else(|| sniff_charset(bytes));
+    match encoding.decode(bytes, DecoderTrap::Strict) {
+        Ok(text) => (text, false),
+        Err(e) => {
+            warn!("Lossy decoding of text as {}: {}", encoding.name(), e);
+            let text = encoding
+                .decode(bytes, DecoderTrap::Replace)
+                .unwrap_or_else(|_| String::from_utf8_lossy(bytes).to_string());
+            (text, true)
         }
-        _ => Err(format!("Unsupported charset: {}", charset).into()),
     }
 }
 
+/// Guess the charset of undeclared text.
+///
+/// ISO-2022-JP and UTF-8 are recognized by their structure, and any other text is
+/// taken as windows-1252 (the de facto superset of ISO-8859-1). Shift_JIS is not
+/// guessed, since most Latin-1 text is also valid Shift_JIS byte-wise.
+fn sniff_charset(bytes: &[u8]) -> EncodingRef {
+    // ISO-2022-JP is 7-bit, so it has to be detected before UTF-8
+    if bytes.windows(3).any(|w| w == b"\x1b$B" || w == b"\x1b$@") {
+        return all::ISO_2022_JP;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return all::UTF_8;
+    }
+    all::WINDOWS_1252
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,4 +159,98 @@ mod tests {
         let result = decode_rfc2047(input);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_decode_rfc2047_b_encoded() {
+        let input = "=?UTF-8?B?SGVsbG8gd29ybGQ=?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "Hello world");
+        // Missing padding
+        let input = "=?utf-8?b?SGVsbG8gd29ybGQ?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "Hello world");
+    }
+
+    #[test]
+    fn test_decode_rfc2047_mixed_text() {
+        let input = "Re: =?ISO-8859-1?Q?Caf=E9?= con leche";
+        assert_eq!(decode_rfc2047(input).unwrap(), "Re: Café con leche");
+    }
+
+    #[test]
+    fn test_decode_rfc2047_adjacent_words() {
+        // Whitespace between adjacent encoded-words is ignored, even across folding
+        let input = "=?UTF-8?Q?Hello_?=\r\n =?UTF-8?Q?world?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "Hello world");
+        // A multibyte character split across two words ("é" is C3 A9)
+        let input = "=?UTF-8?B?Y2Fmw6k=?= =?UTF-8?Q?=C3?= =?UTF-8?Q?=A9?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "caféé");
+    }
+
+    #[test]
+    fn test_decode_rfc2047_whatwg_charsets() {
+        // GB2312 (decoded as GBK)
+        let input = "=?GB2312?B?xOO6ww==?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "你好");
+        // ISO-2022-JP
+        let input = "=?ISO-2022-JP?B?GyRCJDMkcyRLJEEkTxsoQg==?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "こんにちは");
+        // windows-1252 maps 0x80 to the euro sign
+        let input = "=?windows-1252?Q?10_=80?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "10 €");
+        // KOI8-R
+        let input = "=?KOI8-R?B?8NLJ18XU?=";
+        assert_eq!(decode_rfc2047(input).unwrap(), "Привет");
+    }
+
+    #[test]
+    fn test_decode_rfc2047_invalid_base64() {
+        let input = "=?UTF-8?B?*invalid*?=";
+        assert!(decode_rfc2047(input).is_err());
+    }
+
+    #[test]
+    fn test_decode_text_declared_charset() {
+        assert_eq!(
+            decode_text(b"caf\xe9 \x80", Some("windows-1252")),
+            ("café €".to_string(), false)
+        );
+        assert_eq!(
+            decode_text(b"\x82\xb1\x82\xf1", Some("Shift_JIS")),
+            ("こん".to_string(), false)
+        );
+        // Unknown labels are sniffed
+        assert_eq!(
+            decode_text("café".as_bytes(), Some("x-unknown")),
+            ("café".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn test_decode_text_sniffed_charset() {
+        assert_eq!(
+            decode_text("café".as_bytes(), None),
+            ("café".to_string(), false)
+        );
+        assert_eq!(
+            decode_text(b"\x1b$B$3$s\x1b(B", None),
+            ("こん".to_string(), false)
+        );
+        assert_eq!(decode_text(b"caf\xe9 ", None), ("café ".to_string(), false));
+        // Latin-1 text that is also valid Shift_JIS byte-wise
+        assert_eq!(
+            decode_text(b"r\xe9sum\xe9s", None),
+            ("résumés".to_string(), false)
+        );
+        assert_eq!(
+            decode_text(b"Ma\xf1ana", None),
+            ("Mañana".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn test_decode_text_lossy() {
+        assert_eq!(
+            decode_text(b"caf\xc3", Some("utf-8")),
+            ("caf\u{FFFD}".to_string(), true)
+        );
+    }
 }
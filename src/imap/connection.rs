@@ -3,21 +3,26 @@ use std::{sync::Arc, time::Duration};
 use anyhow::Result;
 use async_imap::extensions::idle::IdleResponse::{ManualInterrupt, NewData, Timeout};
 use async_native_tls::TlsStream;
+use async_trait::async_trait;
 use futures::{StreamExt, TryStreamExt};
 use itertools::Itertools;
 
-use async_imap::{types::Name, Session};
+use async_imap::{
+    types::{Fetch, Name},
+    Session,
+};
 use tokio::{net::TcpStream, task, time::sleep};
 use tracing::{debug, error};
 
+use super::flag_name;
 use crate::{
-    queue,
+    source::{MailSource, RawMessage},
     store::{self, Account},
 };
 
-use super::parsers;
-
-async fn get_session(account: &Account) -> Result<Session<TlsStream<TcpStream>>> {
+/// Log in and select the mailbox of the account, returning the session with the
+/// UIDVALIDITY of the mailbox.
+async fn get_session(account: &Account) -> Result<(Session<TlsStream<TcpStream>>, u32)> {
     let imap_addr = (account.imap_host.clone(), 993);
     let tcp_stream = TcpStream::connect(imap_addr).await?;
     let tls = async_native_tls::TlsConnector::new();
@@ -56,10 +61,10 @@ async fn get_session(account: &Account) -> Result<Session<TlsStream<TcpStream>>>
             }
 
             // Select the INBOX mailbox
-            imap_session.select(account.mailbox.clone()).await?;
+            let mailbox = imap_session.select(account.mailbox.clone()).await?;
             debug!("-- INBOX selected");
 
-            Ok(imap_session)
+            Ok((imap_session, mailbox.uid_validity.unwrap_or_default()))
         }
         Err(error) => {
             // Handle the error here, e.g., print an error message or return an error
@@ -70,21 +75,117 @@ async fn get_session(account: &Account) -> Result<Session<TlsStream<TcpStream>>>
     }
 }
 
-pub async fn idle_inbox(
+pub struct ImapSource {
     account: Account,
     store: Arc<dyn store::Store>,
-    queue: Arc<dyn queue::Queue>,
-) -> Result<()> {
-    loop {
-        // Allocate account to be used in the async block
-        let account = account.clone();
+    session: Option<Session<TlsStream<TcpStream>>>,
+    uid_validity: u32,     // The UIDs of the mailbox are only unique along with it
+    last_uid: Option<u32>, // The highest UID fetched, stored as the last sequence
+}
+
+impl ImapSource {
+    pub fn new(account: Account, store: Arc<dyn store::Store>) -> Self {
+        Self {
+            account,
+            store,
+            session: None,
+            uid_validity: 0,
+            last_uid: None,
+        }
+    }
 
-        // Idle for new email messages (unless interrupted)
-        let mut imap_session = get_session(&account).await?;
-        debug!("-- logged in with account {}", account.email);
+    fn session(&mut self) -> Result<&mut Session<TlsStream<TcpStream>>> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| anyhow::Error::msg("IMAP session is not connected"))
+    }
+}
+
+#[async_trait]
+impl MailSource for ImapSource {
+    async fn connect(&mut self) -> Result<()> {
+        let (session, uid_validity) = get_session(&self.account).await?;
+        self.session = Some(session);
+        self.uid_validity = uid_validity;
+        debug!("-- logged in with account {}", self.account.email);
+        Ok(())
+    }
 
-        imap_session =
-            fetch_inbox(imap_session, &account.email, store.clone(), queue.clone()).await?;
+    async fn fetch_new(&mut self) -> Result<Vec<RawMessage>> {
+        // Fetch unread email messages
+        let email = self.account.email.clone();
+        let last_uid = self.store.load_last_sequence(&email).await?;
+        let uid_set = format!("{}:*", last_uid + 1);
+        // The flags and size are fetched first, so the bodies of the messages skipped
+        // by the account policy are never downloaded
+        let query = "(UID FLAGS RFC822.SIZE)";
+        debug!(
+            "Fetching emails for '{}' with UID set '{}' and query '{}'",
+            email, uid_set, query
+        );
+        let messages_stream = self.session()?.uid_fetch(uid_set, query).await?;
+        let mut summaries: Vec<Fetch> = messages_stream.try_collect().await?;
+        // "n:*" includes the highest UID even when it is below n, i.e. already fetched
+        summaries.retain(|summary| summary.uid.is_some_and(|uid| uid > last_uid));
+        self.last_uid = summaries
+            .iter()
+            .filter_map(|summary| summary.uid)
+            .max()
+            .or(Some(last_uid));
+
+        let uids: Vec<u32> = summaries
+            .iter()
+            .filter(|summary| {
+                let flags: Vec<String> = summary.flags().map(|flag| flag_name(&flag)).collect();
+                let size = summary.size.map(|size| size as usize);
+                let allowed = self.account.policy.allows_message(&flags, size);
+                if !allowed {
+                    debug!(
+                        "-- message {:?} skipped by the policy of account {}",
+                        summary.uid, email
+                    );
+                }
+                allowed
+            })
+            .filter_map(|summary| summary.uid)
+            .collect();
+        if uids.is_empty() {
+            return Ok(vec![]);
+        }
+        let uid_set = uids.iter().join(",");
+        let query = "(FLAGS INTERNALDATE RFC822.SIZE BODY.PEEK[] ENVELOPE UID)";
+        debug!(
+            "Fetching {} emails for '{}' with query '{}'",
+            uids.len(),
+            email,
+            query
+        );
+        let messages_stream = self.session()?.uid_fetch(uid_set, query).await?;
+        let raw_messages: Vec<Fetch> = messages_stream.try_collect().await?;
+        let uid_validity = self.uid_validity;
+        Ok(raw_messages
+            .into_iter()
+            .map(|fetch| RawMessage::Imap {
+                fetch,
+                uid_validity,
+            })
+            .collect())
+    }
+
+    async fn checkpoint(&mut self) -> Result<()> {
+        if let Some(last_uid) = self.last_uid.take() {
+            self.store
+                .store_last_sequence(&self.account.email, last_uid)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_changes(&mut self) -> Result<()> {
+        let imap_session = self
+            .session
+            .take()
+            .ok_or_else(|| anyhow::Error::msg("IMAP session is not connected"))?;
 
         debug!("-- initializing idle");
         let mut idle = imap_session.idle();
@@ -93,15 +194,17 @@ pub async fn idle_inbox(
         debug!("-- idle async wait");
         let (idle_wait, interrupt) = idle.wait();
 
+        let email = self.account.email.clone();
+        let idle_time_seconds = self.account.idle_time_seconds;
         task::spawn(async move {
             debug!(
                 "-- thread: waiting '{}' for {} seconds",
-                account.email, account.idle_time_seconds
+                email, idle_time_seconds
             );
-            sleep(Duration::from_secs(account.idle_time_seconds)).await;
+            sleep(Duration::from_secs(idle_time_seconds)).await;
             debug!(
                 "-- thread: waited for '{}' for {} seconds, now interrupting idle",
-                account.email, account.idle_time_seconds
+                email, idle_time_seconds
             );
             drop(interrupt);
         });
@@ -111,12 +214,10 @@ pub async fn idle_inbox(
             ManualInterrupt => {
                 // This could be a timeout from the client (our sleep function)
                 debug!("-- IDLE manually interrupted");
-                // continue; // restart infinite loop, fetching at the beginning of the loop
             }
             Timeout => {
                 // This is a timeout from the server
                 debug!("-- IDLE timed out");
-                // continue; // restart infinite loop, fetching at the beginning of the loop
             }
             NewData(data) => {
                 // The mailbox has received an update, it is time to trigger fetch
@@ -128,62 +229,37 @@ pub async fn idle_inbox(
 
         // return the session after an idle event is received
         debug!("-- idle DONE");
-        imap_session = idle.done().await?;
+        let mut imap_session = idle.done().await?;
 
         // be nice to the server and log out
         debug!("-- logging out");
         imap_session.logout().await?;
-
-        // Introduce a delay before the next iteration to avoid busy-waiting
-        // This delay could be a bit longer to prevent bans, or even randomized
-        tokio::time::sleep(Duration::from_secs(account.wait_time_seconds)).await;
+        Ok(())
     }
-}
 
-async fn fetch_inbox(
-    mut imap_session: Session<TlsStream<TcpStream>>,
-    email: &str,
-    store: Arc<dyn store::Store>,
-    queue: Arc<dyn queue::Queue>,
-) -> Result<Session<TlsStream<TcpStream>>> {
-    // Fetch unread email messages
-    let mut last_sequence = store.load_last_sequence(email).await?;
-    let sequence_set = format!("{}:*", last_sequence);
-    let query = "(FLAGS INTERNALDATE RFC822.SIZE BODY.PEEK[TEXT] ENVELOPE UID)";
-    debug!(
-        "Fetching emails for '{}' with sequence set '{}' and query '{}'",
-        email, sequence_set, query
-    );
-    let messages_stream = imap_session.fetch(sequence_set, query).await?;
-    let raw_messages: Vec<_> = messages_stream.try_collect().await?;
-    let mut parsed = 0;
-    let mut skipped = 0;
-    for raw_message in raw_messages.iter() {
-        let message = parsers::parse_message(email, raw_message);
-        match message {
-            Some(message) => {
-                last_sequence = message.seq_id;
-                queue
-                    .publish_message(queue::QueueMessage {
-                        email_message: message,
-                    })
-                    .await?;
-                parsed += 1;
-                // debug!("pushed message to queue");
-            }
-            None => {
-                error!("unable to parse message (skipped).");
-                skipped += 1;
-            }
+    async fn refetch(&mut self, id: &str) -> Result<Option<Vec<u8>>> {
+        let (uid_validity, uid) = id
+            .split_once('-')
+            .ok_or_else(|| anyhow::Error::msg(format!("Invalid IMAP message id {}", id)))?;
+        let uid: u32 = uid.parse()?;
+        if uid_validity.parse::<u32>()? != self.uid_validity {
+            // The UIDs were reassigned, the message cannot be found by its UID anymore
+            debug!("-- message {} is from a previous UIDVALIDITY", id);
+            return Ok(None);
         }
+        debug!(
+            "-- fetching message {} again for {}",
+            uid, self.account.email
+        );
+        let messages_stream = self
+            .session()?
+            .uid_fetch(uid.to_string(), "(UID BODY.PEEK[])")
+            .await?;
+        let fetches: Vec<Fetch> = messages_stream.try_collect().await?;
+        Ok(fetches
+            .iter()
+            .find(|fetch| fetch.uid == Some(uid))
+            .and_then(|fetch| fetch.body())
+            .map(|body| body.to_vec()))
     }
-    store.store_last_sequence(email, last_sequence).await?;
-
-    debug!(
-        "--  parsed {} | skipped {} | total {}",
-        parsed,
-        skipped,
-        raw_messages.len()
-    );
-    Ok(imap_session)
 }
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use super::attachments::Attachment;
+
+/// Sanitize an HTML body so it can be rendered safely.
+///
+/// Scripts, styles, event handlers and any other non allowlisted markup are removed.
+/// Remote resources (e.g. tracking images) are stripped, while `cid:` references to
+/// inline attachments are rewritten to `attachment:<sha256>`, matching the hash of
+/// the attachment metadata and of its blob reference.
+pub fn sanitize_html(html: &str, attachments: &[Attachment]) -> String {
+    let content_ids: HashMap<String, String> = attachments
+        .iter()
+        .filter_map(|attachment| {
+            let content_id = attachment.content_id.as_ref()?;
+            Some((content_id.to_lowercase(), attachment.sha256.clone()))
+        })
+        .collect();
+
+    ammonia::Builder::default()
+        .add_url_schemes(&["cid"])
+        .attribute_filter(move |_element, attribute, value| {
+            if attribute != "src" {
+                return Some(value.into());
+            }
+            let content_id = value
+                .get(..4)?
+                .eq_ignore_ascii_case("cid:")
+                .then(|| &value[4..])?;
+            content_ids
+                .get(&content_id.to_lowercase())
+                .map(|sha256| format!("attachment:{}", sha256).into())
+        })
+        .clean(html)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_html() {
+        let attachments = vec![Attachment {
+            filename: Some("logo.png".to_string()),
+            mime_type: "image/png".to_string(),
+            size: 4,
+            content_id: Some("logo@test".to_string()),
+            disposition: "inline".to_string(),
+            sha256: "abc".to_string(),
+        }];
+        let html = r#"<html><head><style>p { color: red }</style><script>alert(1)</script></head>
+<body onload="track()"><p onclick="steal()">Hello <a href="https://test.com">link</a></p>
+<img src="cid:LOGO@test" alt="Logo"><img src="https://tracker.test/pixel.gif"><img src="cid:missing"></body></html>"#;
+
+        assert_eq!(
+            sanitize_html(html, &attachments),
+            "\n<p>Hello <a href=\"https://test.com\" rel=\"noopener noreferrer\">link</a></p>\n\
+             <img src=\"attachment:abc\" alt=\"Logo\"><img><img>"
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use std::sync::OnceLock;
+
+use html5ever::tendril::TendrilSink;
+use mailparse::{DispositionType, ParsedMail};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use tracing::error;
+use url::Url;
+
+/// A link whose text shows a URL of another host than the one it points to.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LinkMismatch {
+    pub url: String,  // Normalized href
+    pub text: String, // Anchor text, as displayed
+}
+
+/// The URLs found in the text and HTML parts of a message.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Links {
+    pub urls: Vec<String>, // Normalized and deduplicated, in order of appearance
+    pub mismatches: Vec<LinkMismatch>,
+    pub tracking_pixels: Vec<String>, // Remote images of at most 1x1 pixels, or hidden
+}
+
+impl Links {
+    fn add_url(&mut self, url: String) {
+        if !self.urls.contains(&url) {
+            self.urls.push(url);
+        }
+    }
+}
+
+/// Extract the links of the inline text and HTML parts of a message.
+///
+/// Only the web URLs (http and https) are kept, e.g. `mailto:` or `cid:`
+/// references are not.
+pub fn extract_links(parsed: &ParsedMail<'_>) -> Links {
+    let mut links = Links::default();
+    collect_parts(parsed, &mut links);
+    links
+}
+
+fn collect_parts(part: &ParsedMail<'_>, links: &mut Links) {
+    if part.get_content_disposition().disposition == DispositionType::Attachment {
+        return;
+    }
+    if part.ctype.mimetype.starts_with("multipart/") {
+        for subpart in &part.subparts {
+            collect_parts(subpart, links);
+        }
+        return;
+    }
+    let html = match part.ctype.mimetype.as_str() {
+        "text/html" => true,
+        "text/plain" => false,
+        _ => return,
+    };
+    match part.get_body() {
+        Ok(body) if html => extract_html_links(&body, links),
+        Ok(body) => extract_text_links(&body, links),
+        Err(e) => error!("Unable to decode part for links with mailparser: {}", e),
+    }
+}
+
+/// Extract the URLs written in a text, e.g. "https://test.com" or "www.test.com".
+fn extract_text_links(text: &str, links: &mut Links) {
+    for url in find_text_urls(text) {
+        links.add_url(url);
+    }
+}
+
+fn find_text_urls(text: &str) -> Vec<String> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re =
+        RE.get_or_init(|| Regex::new(r#"(?i)\b(?:https?://|www\.)[^\s<>"'\[\]{}|\\^`]+"#).unwrap());
+    re.find_iter(text)
+        .filter_map(|found| {
+            // Punctuation of the sentence around the URL
+            let url = found
+                .as_str()
+                .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'']);
+            if url.len() >= 4 && url[..4].eq_ignore_ascii_case("www.") {
+                normalize_url(&format!("http://{}", url))
+            } else {
+                normalize_url(url)
+            }
+        })
+        .collect()
+}
+
+/// Extract the anchors, the images and the URLs written in the text of an HTML part.
+fn extract_html_links(html: &str, links: &mut Links) {
+    let dom = html5ever::parse_document(RcDom::default(), Default::default()).one(html);
+    let base = find_base(&dom.document);
+    walk_html(&dom.document, base.as_ref(), false, links);
+}
+
+/// The `<base href>` of a document, which relative links are resolved against.
+fn find_base(node: &Handle) -> Option<Url> {
+    if let NodeData::Element { name, attrs, .. } = &node.data {
+        if name.local.as_ref() == "base" {
+            let attrs = attrs.borrow();
+            let href = attrs
+                .iter()
+                .find(|attr| attr.name.local.as_ref() == "href")?;
+            return Url::parse(href.value.trim()).ok();
+        }
+    }
+    node.children.borrow().iter().find_map(find_base)
+}
+
+/// Walk the HTML tree, with `in_anchor` set below an anchor, whose text is only
+/// checked as a mismatch.
+fn walk_html(node: &Handle, base: Option<&Url>, in_anchor: bool, links: &mut Links) {
+    let mut in_anchor = in_anchor;
+    match &node.data {
+        NodeData::Element { name, attrs, .. } => {
+            let attrs = attrs.borrow();
+            let attr = |key: &str| {
+                attrs
+                    .iter()
+                    .find(|attr| attr.name.local.as_ref() == key)
+                    .map(|attr| attr.value.trim().to_string())
+            };
+            match name.local.as_ref() {
+                "a" | "area" => {
+                    if let Some(url) = attr("href").and_then(|href| resolve_url(&href, base)) {
+                        let text = node_text(node);
+                        if is_mismatch(&url, &text) {
+                            links.mismatches.push(LinkMismatch {
+                                url: url.clone(),
+                                text,
+                            });
+                        }
+                        links.add_url(url);
+                    }
+                    // A URL in the text of the anchor is checked as a mismatch instead,
+                    // its elements (e.g. an image) are still walked
+                    in_anchor = true;
+                }
+                "img" => {
+                    if let Some(url) = attr("src").and_then(|src| resolve_url(&src, base)) {
+                        let style = attr("style").unwrap_or_default();
+                        if is_tracking_pixel(attr("width"), attr("height"), &style)
+                            && !links.tracking_pixels.contains(&url)
+                        {
+                            links.tracking_pixels.push(url.clone());
+                        }
+                        links.add_url(url);
+                    }
+                }
+                "script" | "style" => return,
+                _ => {}
+            }
+        }
+        NodeData::Text { contents } if !in_anchor => extract_text_links(&contents.borrow(), links),
+        _ => {}
+    }
+    for child in node.children.borrow().iter() {
+        walk_html(child, base, in_anchor, links);
+    }
+}
+
+/// The text of a node and its descendants, with the whitespace collapsed.
+fn node_text(node: &Handle) -> String {
+    fn collect(node: &Handle, text: &mut String) {
+        if let NodeData::Text { contents } = &node.data {
+            text.push_str(&contents.borrow());
+        }
+        for child in node.children.borrow().iter() {
+            collect(child, text);
+        }
+    }
+    let mut text = String::new();
+    collect(node, &mut text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Whether an image is a tracking pixel: at most one pixel wide or high, or hidden.
+fn is_tracking_pixel(width: Option<String>, height: Option<String>, style: &str) -> bool {
+    static WIDTH: OnceLock<Regex> = OnceLock::new();
+    static HEIGHT: OnceLock<Regex> = OnceLock::new();
+    let style = style.to_lowercase().replace(' ', "");
+    let tiny = |value: Option<String>, property: &OnceLock<Regex>, name: &str| {
+        let declared = value.or_else(|| {
+            let re =
+                property.get_or_init(|| Regex::new(&format!(r"(?:^|;){}:([^;]+)", name)).unwrap());
+            re.captures(&style).map(|caps| caps[1].to_string())
+        });
+        declared
+            .and_then(|value| value.trim_end_matches("px").trim().parse::<f32>().ok())
+            .is_some_and(|size| size <= 1.0)
+    };
+    tiny(width, &WIDTH, "width")
+        || tiny(height, &HEIGHT, "height")
+        || style.contains("display:none")
+        || style.contains("visibility:hidden")
+}
+
+/// Whether the text of an anchor shows a URL of another host than its href.
+fn is_mismatch(url: &str, text: &str) -> bool {
+    // Only a text that is a URL or a domain name is checked, e.g. not "click here"
+    if text.contains(' ') || !text.contains('.') {
+        return false;
+    }
+    let host = |url: &str| {
+        let url = Url::parse(url).ok()?;
+        let host = url.host_str()?.to_string();
+        Some(
+            host.strip_prefix("www.")
+                .map(str::to_string)
+                .unwrap_or(host),
+        )
+    };
+    let shown = if text.contains("://") {
+        normalize_url(text).and_then(|shown| host(&shown))
+    } else {
+        // A domain name, with a top level domain of letters (e.g. not a version number)
+        normalize_url(&format!("http://{}", text))
+            .and_then(|shown| host(&shown))
+            .filter(|shown| {
+                let tld = shown.rsplit('.').next().unwrap_or_default();
+                tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic())
+            })
+    };
+    match (shown, host(url)) {
+        (Some(shown), Some(actual)) => shown != actual,
+        _ => false,
+    }
+}
+
+/// Resolve the value of an href or a src against the base of the document.
+fn resolve_url(value: &str, base: Option<&Url>) -> Option<String> {
+    match Url::parse(value) {
+        Ok(_) => normalize_url(value),
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            normalize_url(base?.join(value).ok()?.as_str())
+        }
+        Err(_) => None,
+    }
+}
+
+/// Normalize a web URL: lowercase scheme and host, IDNA host, default port and
+/// dot segments removed, and without the fragment.
+fn normalize_url(value: &str) -> Option<String> {
+    let mut url = Url::parse(value).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+    url.set_fragment(None);
+    Some(url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEWSLETTER: &str = "Subject: Offers\r
+MIME-Version: 1.0\r
+Content-Type: multipart/alternative; boundary=b\r
+\r
+--b\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+Visit www.Shop.test/offers. Or https://shop.test:443/a/../offers#top, or mail us.\r
+--b\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<html><head><base href=\"https://shop.test/\"><style>a { background: url(https://style.test/x.png) }</style></head><body>\r
+<p>See <a href=\"offers?id=1\">our offers</a> and https://blog.shop.test/post.</p>\r
+<p>Log in at <a href=\"https://evil.test/login\">https://www.bank.test/login</a>,\r
+<a href=\"https://www.bank.test/help\">bank.test</a>, <a href=\"mailto:help@shop.test\">help@shop.test</a></p>\r
+<img src=\"https://track.test/open.gif?u=1\" width=\"1\" height=\"1\">\r
+<img src=\"https://track.test/hidden.gif\" style=\"display: none\">\r
+<img src=\"https://shop.test/logo.png\" width=\"120\"><img src=\"cid:logo@shop\">\r
+<a href=\"https://shop.test/\"><img src=\"https://track.test/link.gif\" width=\"1\" height=\"1\"> https://shop.test/home</a>\r
+</body></html>\r
+--b--\r
+";
+
+    #[test]
+    fn test_extract_links() {
+        let parsed = mailparse::parse_mail(NEWSLETTER.as_bytes()).unwrap();
+        let links = extract_links(&parsed);
+        assert_eq!(
+            links.urls,
+            vec![
+                "http://www.shop.test/offers",
+                "https://shop.test/offers",
+                "https://shop.test/offers?id=1",
+                "https://blog.shop.test/post",
+                "https://evil.test/login",
+                "https://www.bank.test/help",
+                "https://track.test/open.gif?u=1",
+                "https://track.test/hidden.gif",
+                "https://shop.test/logo.png",
+                "https://shop.test/",
+                "https://track.test/link.gif",
+            ]
+        );
+        assert_eq!(
+            links.mismatches,
+            vec![LinkMismatch {
+                url: "https://evil.test/login".to_string(),
+                text: "https://www.bank.test/login".to_string(),
+            }]
+        );
+        assert_eq!(
+            links.tracking_pixels,
+            vec![
+                "https://track.test/open.gif?u=1",
+                "https://track.test/hidden.gif",
+                "https://track.test/link.gif"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_mismatch() {
+        let cases = [
+            ("https://paypal.test.evil.test/", "paypal.test", true),
+            ("https://www.paypal.test/", "paypal.test", false),
+            ("https://paypal.test/", "WWW.PAYPAL.TEST/login", false),
+            ("https://evil.test/", "Click here", false),
+            ("https://evil.test/", "v1.2", false),
+            ("https://evil.test/", "http://10.0.0.1/", true),
+        ];
+        for (url, text, expected) in cases {
+            assert_eq!(is_mismatch(url, text), expected, "{} {}", url, text);
+        }
+    }
+}
@@ -1,7 +1,25 @@
+mod attachments;
+mod authentication;
+mod bounces;
+mod calendar;
+mod classification;
 mod codecs;
 mod connection;
+mod html;
+mod links;
 mod parsers;
+mod replies;
+mod security;
 
+pub use attachments::*;
+pub use authentication::*;
+pub use bounces::*;
+pub use calendar::*;
+pub use classification::*;
 pub use codecs::*;
 pub use connection::*;
+pub use html::*;
+pub use links::*;
 pub use parsers::*;
+pub use replies::*;
+pub use security::*;
@@ -1,18 +1,85 @@
-use async_imap::{imap_proto::Envelope, types::Fetch};
+use async_imap::{
+    imap_proto::{Address as ImapAddress, Envelope},
+    types::{Fetch, Flag},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
 use itertools::Itertools;
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fmt;
+use std::sync::OnceLock;
 use tracing::{debug, error, warn};
 
+use super::attachments::{self, Attachment};
+use super::authentication::{self, Authentication};
+use super::bounces::{self, Bounce};
+use super::calendar::{self, CalendarEvent};
+use super::classification::{self, ListInfo, MessageClass};
 use super::codecs;
+use super::html;
+use super::links::{self, Links};
+use super::replies;
+use super::security::{self, Security};
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub struct EmailMessage {
     pub account: String,
     pub senders: Vec<Address>,
     pub subject: String,
     pub body: String,
     pub seq_id: u32,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub from: Vec<Address>,
+    #[serde(default)]
+    pub to: Vec<Address>,
+    #[serde(default)]
+    pub cc: Vec<Address>,
+    #[serde(default)]
+    pub bcc: Vec<Address>,
+    #[serde(default)]
+    pub reply_to: Vec<Address>,
+    #[serde(default)]
+    pub date: Option<String>, // RFC 3339, in UTC
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    #[serde(default)]
+    pub message_id: Option<String>,
+    #[serde(default)]
+    pub flags: Vec<String>, // e.g. "\\Seen", or custom keywords
+    #[serde(default)]
+    pub internal_date: Option<String>, // RFC 3339 in UTC, when the server received the message
+    #[serde(default)]
+    pub size: Option<u32>, // RFC822.SIZE in bytes
+    #[serde(default)]
+    pub body_lossy: bool, // Some bytes of the body could not be decoded in its charset
+    #[serde(default)]
+    pub html_body: Option<String>, // Sanitized, only when enabled by the account policy
+    #[serde(default)]
+    pub new_content: String, // The body without the quoted thread and the signature
+    #[serde(default)]
+    pub references: Vec<String>, // Message ids of the References header
+    #[serde(default)]
+    pub thread_id: Option<String>,
+    #[serde(default)]
+    pub thread_position: Option<u32>, // Order of arrival in the thread, starting at 1
+    #[serde(default)]
+    pub events: Vec<CalendarEvent>, // Calendar invitations, replies and cancellations
+    #[serde(default)]
+    pub class: MessageClass,
+    #[serde(default)]
+    pub list: Option<ListInfo>,
+    #[serde(default)]
+    pub authentication: Authentication, // SPF, DKIM, DMARC and ARC results
+    #[serde(default)]
+    pub bounce: Option<Bounce>, // Published as a bounce event instead of a message
+    #[serde(default)]
+    pub security: Option<Security>, // S/MIME or OpenPGP encryption and signature
+    #[serde(default)]
+    pub links: Links, // URLs of the text and HTML parts, for reputation checks
 }
 
 impl fmt::Display for EmailMessage {
@@ -28,6 +95,16 @@ impl fmt::Display for EmailMessage {
     }
 }
 
+/// How the HTML bodies are rendered into the message text.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyFormat {
+    #[default]
+    Plain, // HTML stripped into plain text
+    Markdown, // HTML converted to Markdown, keeping links, lists, emphasis and tables
+    Raw,      // HTML kept as it is
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Address {
     pub name: Option<String>,
@@ -43,121 +120,354 @@ impl fmt::Display for Address {
     }
 }
 
-pub fn parse_message(email: &str, raw_message: &Fetch) -> Option<EmailMessage> {
+/// Why a message could not be parsed (or published, for the dead letters).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum ParseError {
+    MissingUid,
+    MissingBody,
+    MissingEnvelope,
+    Decode(String),  // The MIME structure or the headers could not be decoded
+    Publish(String), // The message was parsed, but could not be published
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingUid => write!(f, "message did not have a uid"),
+            ParseError::MissingBody => write!(f, "message did not have a body"),
+            ParseError::MissingEnvelope => write!(f, "message did not have an envelope"),
+            ParseError::Decode(e) => write!(f, "unable to decode message: {}", e),
+            ParseError::Publish(e) => write!(f, "unable to publish message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse_message(
+    email: &str,
+    raw_message: &Fetch,
+    format: BodyFormat,
+) -> Result<EmailMessage, ParseError> {
     let mut message = EmailMessage {
         account: email.to_string(),
-        senders: Vec::<Address>::new(),
-        subject: "".to_string(),
-        body: "".to_string(),
-        seq_id: 0,
+        ..Default::default()
     };
 
-    match raw_message.uid {
-        Some(uid) => message.seq_id = uid,
-        None => {
-            error!("message did not have a uid!");
-            // Unable to parse any type of message, go to the next one
-            return None;
-        }
+    message.seq_id = raw_message.uid.ok_or(ParseError::MissingUid)?;
+    message.flags = raw_message.flags().map(|flag| flag_name(&flag)).collect();
+    message.internal_date = raw_message.internal_date().map(|date| {
+        date.with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::Secs, true)
+    });
+    message.size = raw_message.size;
+    let body = raw_message.body().ok_or(ParseError::MissingBody)?;
+    // The envelope does not include the References header
+    message.references = match mailparse::parse_headers(body) {
+        Ok((headers, _)) => headers
+            .get_first_value("References")
+            .map(|references| parse_message_ids(&references))
+            .unwrap_or_default(),
+        Err(_) => vec![],
+    };
+    apply_content(&mut message, body, format);
+    let envelope = raw_message.envelope().ok_or(ParseError::MissingEnvelope)?;
+    message.senders = parse_sender(envelope);
+    message.subject = parse_subject(envelope);
+    parse_envelope(envelope, &mut message);
+    Ok(message)
+}
+
+/// The IMAP name of a flag, e.g. "\\Seen".
+pub fn flag_name(flag: &Flag<'_>) -> String {
+    match flag {
+        Flag::Seen => "\\Seen".to_string(),
+        Flag::Answered => "\\Answered".to_string(),
+        Flag::Flagged => "\\Flagged".to_string(),
+        Flag::Deleted => "\\Deleted".to_string(),
+        Flag::Draft => "\\Draft".to_string(),
+        Flag::Recent => "\\Recent".to_string(),
+        Flag::MayCreate => "\\*".to_string(),
+        Flag::Custom(name) => name.to_string(),
     }
-    match raw_message.text() {
-        Some(text) => message.body = parse_text(text),
-        None => {
-            debug!("message did not have a text!");
-            // Unable to parse any type of message, go to the next one
+}
+
+/// Parse a complete RFC822 message, as delivered by the sources that do not
+/// provide an IMAP envelope (e.g. JMAP blobs or POP3).
+pub fn parse_rfc822(
+    email: &str,
+    seq_id: u32,
+    raw: &[u8],
+    format: BodyFormat,
+) -> Result<EmailMessage, ParseError> {
+    let parsed = mailparse::parse_mail(raw).map_err(|e| ParseError::Decode(e.to_string()))?;
+
+    let headers = parsed.get_headers();
+    // The sender header is optional, the author is the sender when it is missing
+    let senders = headers
+        .get_first_header("Sender")
+        .or_else(|| headers.get_first_header("From"))
+        .map(parse_address_header)
+        .unwrap_or_default();
+    let subject = headers.get_first_value("Subject").unwrap_or_default();
+    let addresses = |key: &str| {
+        headers
+            .get_first_header(key)
+            .map(parse_address_header)
+            .unwrap_or_default()
+    };
+    let message_id = |key: &str| {
+        headers
+            .get_first_value(key)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let mut message = EmailMessage {
+        account: email.to_string(),
+        senders,
+        subject,
+        seq_id,
+        from: addresses("From"),
+        to: addresses("To"),
+        cc: addresses("Cc"),
+        bcc: addresses("Bcc"),
+        reply_to: addresses("Reply-To"),
+        date: headers
+            .get_first_value("Date")
+            .and_then(|date| parse_date(&date)),
+        in_reply_to: message_id("In-Reply-To"),
+        message_id: message_id("Message-ID"),
+        references: headers
+            .get_first_value("References")
+            .map(|references| parse_message_ids(&references))
+            .unwrap_or_default(),
+        size: u32::try_from(raw.len()).ok(),
+        ..Default::default()
+    };
+    apply_content(&mut message, raw, format);
+    Ok(message)
+}
+
+/// Set the content of a message (body, attachments, classification, ...) from its
+/// complete RFC822 source.
+pub fn apply_content(message: &mut EmailMessage, raw: &[u8], format: BodyFormat) {
+    let mut content = parse_content(raw, format);
+    message.class = std::mem::take(&mut content.class);
+    message.list = content.list.take();
+    message.authentication = std::mem::take(&mut content.authentication);
+    message.bounce = content.bounce.take();
+    apply_body(message, content);
+}
+
+/// Set the body of a message (text, attachments, links, ...) from its unwrapped
+/// source, e.g. once it is decrypted. The classification, the authentication and
+/// the bounce of the outer message are kept, as they were checked on it.
+pub fn apply_unwrapped_content(message: &mut EmailMessage, raw: &[u8], format: BodyFormat) {
+    apply_body(message, parse_content(raw, format));
+}
+
+fn apply_body(message: &mut EmailMessage, content: MessageContent) {
+    message.new_content = replies::extract_new_content(&content.body);
+    message.body = content.body;
+    message.body_lossy = content.body_lossy;
+    message.events = content.events;
+    message.security = content.security;
+    message.links = content.links;
+    message.attachments = content.attachments;
+}
+
+/// Read the message ids (e.g. "<id@host>") of a header like References.
+fn parse_message_ids(value: &str) -> Vec<String> {
+    static MESSAGE_ID: OnceLock<Regex> = OnceLock::new();
+    let re = MESSAGE_ID.get_or_init(|| Regex::new(r"<[^<>\s]+>").unwrap());
+    re.find_iter(value)
+        .map(|id| id.as_str().to_string())
+        .collect()
+}
+
+/// Normalize an RFC 2822 date to RFC 3339 in UTC.
+fn parse_date(raw: &str) -> Option<String> {
+    let timestamp = match mailparse::dateparse(raw) {
+        Ok(timestamp) => timestamp,
+        Err(e) => {
+            warn!("Unable to parse date {}: {}", raw, e);
             return None;
         }
-    }
-    match raw_message.envelope() {
-        Some(envelope) => {
-            message.senders = parse_sender(envelope);
-            message.subject = parse_subject(envelope);
-        }
-        None => {
-            debug!("message did not have an envelope!");
-            // Unable to parse any type of envelope, go to the next one
-            return None;
+    };
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn parse_address_header(header: &mailparse::MailHeader<'_>) -> Vec<Address> {
+    match mailparse::addrparse_header(header) {
+        Ok(addresses) => addresses
+            .iter()
+            .flat_map(|address| match address {
+                mailparse::MailAddr::Single(info) => vec![info.clone()],
+                mailparse::MailAddr::Group(group) => group.addrs.clone(),
+            })
+            .map(|info| Address {
+                name: info.display_name,
+                email: info.addr,
+            })
+            .collect(),
+        Err(e) => {
+            error!("Unable to parse address header {}: {}", header.get_key(), e);
+            Vec::new()
         }
     }
-    // debug!("message {} parsed", message.seq_id);
-    return Some(message);
 }
 
 fn parse_sender(envelope: &Envelope<'_>) -> Vec<Address> {
-    // Parse sender
-    let mut senders = Vec::<Address>::new();
     match &envelope.sender {
-        Some(sender) => {
-            // Parse sender
-            for address in sender {
-                let email = format!(
-                    "{}@{}",
-                    String::from_utf8(address.mailbox.as_ref().unwrap().to_vec()).unwrap(),
-                    String::from_utf8(address.host.as_ref().unwrap().to_vec()).unwrap()
-                );
-                let raw_name = address.name.as_ref();
-                match raw_name {
-                    Some(name) => senders.push(Address {
-                        name: Some(String::from_utf8(name.to_vec()).unwrap()),
-                        email,
-                    }),
-                    None => senders.push(Address { name: None, email }),
-                }
-            }
-        }
+        Some(sender) => parse_envelope_addresses(sender),
         None => {
             debug!("message did not have a sender!");
-            // Unable to parse any type of envelope, go to the next one
+            Vec::new()
         }
     }
-    return senders;
 }
 
-fn parse_subject(envelope: &Envelope<'_>) -> String {
-    if let Some(ascii_subject) = &envelope.subject {
-        let subject = String::from_utf8(ascii_subject.to_vec()).unwrap();
-        if subject.starts_with("=?") && subject.ends_with("?=") {
-            // RFC2047 encoding detected
-            let result = codecs::decode_rfc2047(&subject);
-            match result {
-                Ok(decoded_subject) => {
-                    return decoded_subject;
+/// Read the recipients, date and message ids of the envelope.
+fn parse_envelope(envelope: &Envelope<'_>, message: &mut EmailMessage) {
+    let addresses = |field: &Option<Vec<ImapAddress<'_>>>| {
+        field
+            .as_deref()
+            .map(parse_envelope_addresses)
+            .unwrap_or_default()
+    };
+    let value = |field: &Option<Cow<'_, [u8]>>| {
+        field
+            .as_ref()
+            .map(|raw| decode_header(raw))
+            .filter(|value| !value.is_empty())
+    };
+    message.from = addresses(&envelope.from);
+    message.to = addresses(&envelope.to);
+    message.cc = addresses(&envelope.cc);
+    message.bcc = addresses(&envelope.bcc);
+    message.reply_to = addresses(&envelope.reply_to);
+    message.date = value(&envelope.date).and_then(|date| parse_date(&date));
+    message.in_reply_to = value(&envelope.in_reply_to);
+    message.message_id = value(&envelope.message_id);
+}
+
+/// Parse the addresses of an envelope field, decoding their display names.
+///
+/// RFC 3501 encodes the group syntax with addresses without a host: the start of a
+/// group has the group name as mailbox, and its end has no mailbox either. These
+/// markers are skipped, keeping the addresses of the group members.
+fn parse_envelope_addresses(addresses: &[ImapAddress<'_>]) -> Vec<Address> {
+    addresses
+        .iter()
+        .filter_map(|address| {
+            let mailbox = address.mailbox.as_ref().map(|m| decode_header(m));
+            let host = address.host.as_ref().map(|h| decode_header(h));
+            let email = match (mailbox, host) {
+                (Some(mailbox), Some(host)) if !host.is_empty() => {
+                    format!("{}@{}", mailbox, host)
                 }
-                Err(e) => {
-                    error!(
-                        "Unable to decode subject line: {}. Original subject line {}",
-                        e, subject
-                    );
-                    return subject;
+                (None, Some(host)) => {
+                    warn!("address without mailbox at host {}", host);
+                    return None;
                 }
-            }
-        } else {
-            return subject;
+                // Group markers
+                _ => return None,
+            };
+            let name = address
+                .name
+                .as_ref()
+                .map(|name| decode_header(name))
+                .filter(|name| !name.is_empty());
+            Some(Address { name, email })
+        })
+        .collect()
+}
+
+/// Read a raw header value, decoding its RFC 2047 encoded-words.
+///
+/// Invalid UTF-8 bytes are replaced rather than rejected.
+fn decode_header(raw: &[u8]) -> String {
+    let value = String::from_utf8_lossy(raw).trim().to_string();
+    if !value.contains("=?") {
+        return value;
+    }
+    match codecs::decode_rfc2047(&value) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            error!("Unable to decode header: {}. Original value {}", e, value);
+            value
         }
-    } else {
-        error!("unable to read subject from {:?}", envelope.subject);
-        return "Not yet".to_string();
     }
 }
 
-fn parse_text(text: &[u8]) -> String {
-    let parsed = mailparse::parse_mail(text);
-    match parsed {
+fn parse_subject(envelope: &Envelope<'_>) -> String {
+    match &envelope.subject {
+        Some(subject) => decode_header(subject),
+        None => {
+            error!("unable to read subject from {:?}", envelope.subject);
+            "Not yet".to_string()
+        }
+    }
+}
+
+/// The content extracted from the MIME tree of a message.
+struct MessageContent {
+    body: String,
+    body_lossy: bool,
+    events: Vec<CalendarEvent>,
+    class: MessageClass,
+    list: Option<ListInfo>,
+    authentication: Authentication,
+    bounce: Option<Bounce>,
+    security: Option<Security>,
+    links: Links,
+    attachments: Vec<Attachment>,
+}
+
+/// Extract the readable text and the attachments of a complete RFC822 message.
+fn parse_content(raw: &[u8], format: BodyFormat) -> MessageContent {
+    match mailparse::parse_mail(raw) {
         Ok(parsed) => {
-            let decoded_body = parsed.get_body();
-            match decoded_body {
-                Ok(decoded_body) => {
-                    if decoded_body.contains("<!DOCTYPE html>") || decoded_body.contains("<html>") {
-                        // Strip HTML (and do not wrap the lines)
-                        let text = html2text::from_read(decoded_body.as_bytes(), usize::MAX);
-                        return text;
-                    } else {
-                        return decoded_body;
-                    }
-                }
-                Err(e) => {
-                    error!("Unable to decoded body with mailparser: {}", e);
+            let body_part = find_body_part(&parsed, format);
+            let (body, lossy) = match body_part {
+                Some(part) => match parse_text(part, format) {
+                    Some(body) => (body, false),
+                    // Decode the transfer encoding only, and the charset manually
+                    None => match part.get_body_raw() {
+                        Ok(body) => parse_text_manually(&body, Some(&part.ctype.charset), format),
+                        Err(_) => parse_text_manually(raw, None, format),
+                    },
+                },
+                None => {
+                    debug!("message did not have a text part");
+                    ("".to_string(), false)
                 }
+            };
+            let (class, list) = classification::classify(&parsed.headers);
+            let bounce = bounces::parse_bounce(&parsed, &body);
+            let security = security::detect_security(&parsed, &body);
+            // Ciphertext is not a body, until the message is decrypted
+            let (body, lossy, links) = match &security {
+                Some(Security {
+                    encryption: Some(_),
+                    ..
+                }) => ("".to_string(), false, Links::default()),
+                _ => (body, lossy, links::extract_links(&parsed)),
+            };
+            MessageContent {
+                body,
+                body_lossy: lossy,
+                events: calendar::parse_calendar_parts(&parsed),
+                class,
+                list,
+                authentication: authentication::parse_authentication(&parsed.headers),
+                bounce,
+                security,
+                links,
+                attachments: attachments::parse_attachments(&parsed, body_part),
             }
         }
         Err(e) => {
@@ -165,24 +475,383 @@ fn parse_text(text: &[u8]) -> String {
                 "Unable to parse email with mailparser: {}. Trying manual parsing...",
                 e
             );
+            let (body, lossy) = parse_text_manually(raw, None, format);
+            MessageContent {
+                body,
+                body_lossy: lossy,
+                events: vec![],
+                class: MessageClass::default(),
+                list: None,
+                authentication: Authentication::default(),
+                bounce: None,
+                security: None,
+                links: Links::default(),
+                attachments: vec![],
+            }
         }
     }
+}
+
+/// Extract the sanitized HTML body of a complete RFC822 message, if it has one.
+pub fn parse_html_body(raw: &[u8], attachments: &[Attachment]) -> Option<String> {
+    let parsed = match mailparse::parse_mail(raw) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            error!("Unable to parse HTML body with mailparser: {}", e);
+            return None;
+        }
+    };
+    match find_html_part(&parsed)?.get_body() {
+        Ok(html) => Some(html::sanitize_html(&html, attachments)),
+        Err(e) => {
+            error!("Unable to decode HTML body with mailparser: {}", e);
+            None
+        }
+    }
+}
 
-    // Try manual parsing if there is a mailparse error
-    let utf_result = std::str::from_utf8(text);
-    match utf_result {
-        Ok(utf_text) => {
-            if utf_text.contains("<!DOCTYPE html>") || utf_text.contains("<html>") {
-                // Strip HTML (and do not wrap the lines)
-                let text = html2text::from_read(utf_text.as_bytes(), usize::MAX);
-                return text;
+/// Extract the attachments of a complete RFC822 message with their content.
+pub fn extract_attachments(raw: &[u8]) -> Vec<(Attachment, Vec<u8>)> {
+    match mailparse::parse_mail(raw) {
+        Ok(parsed) => {
+            let body_part = find_body_part(&parsed, BodyFormat::default());
+            attachments::parse_attachment_contents(&parsed, body_part)
+        }
+        Err(e) => {
+            error!("Unable to parse attachments with mailparser: {}", e);
+            vec![]
+        }
+    }
+}
+
+fn parse_text(part: &ParsedMail<'_>, format: BodyFormat) -> Option<String> {
+    match part.get_body() {
+        Ok(decoded_body) => {
+            if part.ctype.mimetype == "text/html" || is_html(&decoded_body) {
+                Some(render_html(&decoded_body, format))
             } else {
-                return utf_text.to_string();
+                Some(decoded_body)
             }
         }
         Err(e) => {
-            error!("Unable to decode body with UTF8: {}", e);
-            return "".to_string();
+            error!("Unable to decoded body with mailparser: {}", e);
+            None
         }
     }
 }
+
+/// Manual parsing, used if there is a mailparse error
+///
+/// The charset is the declared one if given, or the one of a Content-Type header
+/// found in the text, and it is sniffed otherwise. Returns the text and whether
+/// the decoding was lossy.
+fn parse_text_manually(text: &[u8], charset: Option<&str>, format: BodyFormat) -> (String, bool) {
+    static CHARSET: OnceLock<Regex> = OnceLock::new();
+    let header_charset = if charset.is_none() {
+        let re = CHARSET.get_or_init(|| {
+            Regex::new(r#"(?i)content-type:[^\r\n]*charset\s*=\s*"?([\w.:-]+)"#).unwrap()
+        });
+        re.captures(&String::from_utf8_lossy(text))
+            .map(|caps| caps[1].to_string())
+    } else {
+        None
+    };
+    let (decoded, lossy) = codecs::decode_text(text, charset.or(header_charset.as_deref()));
+    if lossy {
+        warn!("Body decoded with replacement characters");
+    }
+    if is_html(&decoded) {
+        (render_html(&decoded, format), lossy)
+    } else {
+        (decoded, lossy)
+    }
+}
+
+fn render_html(html: &str, format: BodyFormat) -> String {
+    match format {
+        // Strip HTML (and do not wrap the lines)
+        BodyFormat::Plain => html2text::from_read(html.as_bytes(), usize::MAX),
+        BodyFormat::Markdown => html2md::parse_html(html),
+        BodyFormat::Raw => html.to_string(),
+    }
+}
+
+fn is_html(text: &str) -> bool {
+    text.contains("<!DOCTYPE html>") || text.contains("<html>")
+}
+
+fn is_attachment(part: &ParsedMail<'_>) -> bool {
+    part.get_content_disposition().disposition == DispositionType::Attachment
+}
+
+/// Walk the MIME tree looking for the part that holds the message text.
+///
+/// In `multipart/alternative`, `text/plain` is preferred for the `Plain` format and
+/// `text/html` for the others, which keep its markup. The other type is the
+/// fallback. Other multiparts return the first inline text part found.
+fn find_body_part<'a>(part: &'a ParsedMail<'a>, format: BodyFormat) -> Option<&'a ParsedMail<'a>> {
+    let mimetype = part.ctype.mimetype.as_str();
+    if mimetype == "multipart/alternative" {
+        let candidates: Vec<&ParsedMail> = part
+            .subparts
+            .iter()
+            .filter(|subpart| !is_attachment(subpart))
+            .filter_map(|subpart| find_body_part(subpart, format))
+            .collect();
+        let preferred = match format {
+            BodyFormat::Plain => "text/plain",
+            BodyFormat::Markdown | BodyFormat::Raw => "text/html",
+        };
+        return candidates
+            .iter()
+            .find(|candidate| candidate.ctype.mimetype == preferred)
+            .or_else(|| candidates.first())
+            .copied();
+    }
+    if mimetype.starts_with("multipart/") {
+        return part
+            .subparts
+            .iter()
+            .filter(|subpart| !is_attachment(subpart))
+            .find_map(|subpart| find_body_part(subpart, format));
+    }
+    match mimetype {
+        "text/plain" | "text/html" if !is_attachment(part) => Some(part),
+        _ => None,
+    }
+}
+
+/// Walk the MIME tree looking for the first inline `text/html` part.
+fn find_html_part<'a>(part: &'a ParsedMail<'a>) -> Option<&'a ParsedMail<'a>> {
+    if is_attachment(part) {
+        return None;
+    }
+    if part.ctype.mimetype.starts_with("multipart/") {
+        return part.subparts.iter().find_map(find_html_part);
+    }
+    (part.ctype.mimetype == "text/html").then_some(part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALTERNATIVE: &[u8] = b"From: Sender <sender@test.com>\r
+Subject: Alternative\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"mixed\"\r
+\r
+--mixed\r
+Content-Type: multipart/alternative; boundary=\"alt\"\r
+\r
+--alt\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<html><body><p>Hello <b>HTML</b></p></body></html>\r
+--alt\r
+Content-Type: text/plain; charset=utf-8\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+Hello plain caf=C3=A9\r
+--alt--\r
+--mixed\r
+Content-Type: text/plain; name=\"notes.txt\"\r
+Content-Disposition: attachment; filename=\"notes.txt\"\r
+\r
+Attached notes\r
+--mixed--\r
+";
+
+    #[test]
+    fn test_parse_rfc822_prefers_plain_alternative() {
+        let message = parse_rfc822("test@test.com", 7, ALTERNATIVE, BodyFormat::Plain).unwrap();
+        assert_eq!(message.seq_id, 7);
+        assert_eq!(message.subject, "Alternative");
+        assert_eq!(
+            message.senders,
+            vec![Address {
+                name: Some("Sender".to_string()),
+                email: "sender@test.com".to_string()
+            }]
+        );
+        assert_eq!(message.body.trim_end(), "Hello plain café");
+        assert_eq!(message.attachments.len(), 1);
+        assert_eq!(
+            message.attachments[0].filename,
+            Some("notes.txt".to_string())
+        );
+        assert_eq!(message.attachments[0].size, 16);
+    }
+
+    #[test]
+    fn test_parse_rfc822_prefers_html_alternative() {
+        let message = parse_rfc822("test@test.com", 7, ALTERNATIVE, BodyFormat::Markdown).unwrap();
+        assert_eq!(message.body.trim_end(), "Hello **HTML**");
+        assert_eq!(message.attachments.len(), 1);
+
+        let message = parse_rfc822("test@test.com", 7, ALTERNATIVE, BodyFormat::Raw).unwrap();
+        assert!(message.body.contains("<b>HTML</b>"));
+    }
+
+    #[test]
+    fn test_parse_rfc822_html_fallback() {
+        let raw = b"Subject: Html\r
+Content-Type: multipart/alternative; boundary=\"alt\"\r
+\r
+--alt\r
+Content-Type: text/html; charset=utf-8\r
+Content-Transfer-Encoding: base64\r
+\r
+PHA+SGVsbG8gPGk+d29ybGQ8L2k+PC9wPg==\r
+--alt--\r
+";
+        let message = parse_rfc822("test@test.com", 1, raw, BodyFormat::Plain).unwrap();
+        assert_eq!(message.body.trim_end(), "Hello world");
+    }
+
+    #[test]
+    fn test_parse_rfc822_without_text_part() {
+        let raw = b"Subject: Image\r\nContent-Type: image/png\r\n\r\niVBORw0KGgo=\r\n";
+        let message = parse_rfc822("test@test.com", 1, raw, BodyFormat::Plain).unwrap();
+        assert_eq!(message.body, "");
+    }
+
+    fn imap_address<'a>(
+        name: Option<&'a [u8]>,
+        mailbox: Option<&'a [u8]>,
+        host: Option<&'a [u8]>,
+    ) -> ImapAddress<'a> {
+        ImapAddress {
+            name: name.map(Cow::Borrowed),
+            adl: None,
+            mailbox: mailbox.map(Cow::Borrowed),
+            host: host.map(Cow::Borrowed),
+        }
+    }
+
+    #[test]
+    fn test_parse_envelope_addresses() {
+        let addresses = vec![
+            imap_address(
+                Some(b"=?utf-8?B?Sm9zw6kgUMOpcmV6?="),
+                Some(b"jose"),
+                Some(b"test.com"),
+            ),
+            // Group start, member and group end
+            imap_address(None, Some(b"Team"), None),
+            imap_address(Some(b"Caf\xe9"), Some(b"member"), Some(b"test.com")),
+            imap_address(None, None, None),
+            // Missing mailbox
+            imap_address(None, None, Some(b"test.com")),
+        ];
+        assert_eq!(
+            parse_envelope_addresses(&addresses),
+            vec![
+                Address {
+                    name: Some("José Pérez".to_string()),
+                    email: "jose@test.com".to_string()
+                },
+                Address {
+                    name: Some("Caf\u{FFFD}".to_string()),
+                    email: "member@test.com".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rfc822_address_group() {
+        let raw = b"From: Team: =?ISO-8859-1?Q?Jos=E9?= <jose@test.com>, ana@test.com;\r
+Subject: Group\r
+\r
+Hello";
+        let message = parse_rfc822("test@test.com", 1, raw, BodyFormat::Plain).unwrap();
+        assert_eq!(
+            message.senders,
+            vec![
+                Address {
+                    name: Some("José".to_string()),
+                    email: "jose@test.com".to_string()
+                },
+                Address {
+                    name: None,
+                    email: "ana@test.com".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rfc822_envelope_fields() {
+        let raw = b"From: Ana <ana@test.com>\r
+Sender: list@test.com\r
+To: test@test.com, Other <other@test.com>\r
+Cc: cc@test.com\r
+Reply-To: reply@test.com\r
+Date: Tue, 1 Aug 2023 14:30:00 +0200\r
+Message-ID: <abc@test.com>\r
+In-Reply-To: <parent@test.com>\r
+References: <root@test.com>\r
+ <parent@test.com>\r
+Subject: Envelope\r
+\r
+Hello";
+        let message = parse_rfc822("test@test.com", 1, raw, BodyFormat::Plain).unwrap();
+        assert_eq!(message.senders[0].email, "list@test.com");
+        assert_eq!(message.from[0].email, "ana@test.com");
+        assert_eq!(message.to.len(), 2);
+        assert_eq!(message.to[1].name, Some("Other".to_string()));
+        assert_eq!(message.cc[0].email, "cc@test.com");
+        assert!(message.bcc.is_empty());
+        assert_eq!(message.reply_to[0].email, "reply@test.com");
+        assert_eq!(message.date, Some("2023-08-01T12:30:00Z".to_string()));
+        assert_eq!(message.message_id, Some("<abc@test.com>".to_string()));
+        assert_eq!(message.in_reply_to, Some("<parent@test.com>".to_string()));
+        assert_eq!(
+            message.references,
+            vec!["<root@test.com>", "<parent@test.com>"]
+        );
+    }
+
+    #[test]
+    fn test_parse_text_manually_charsets() {
+        let raw = b"Content-Type: text/plain; charset=\"windows-1252\"\r\n\r\nCaf\xe9 \x80";
+        let (body, lossy) = parse_text_manually(raw, None, BodyFormat::Plain);
+        assert!(body.ends_with("Café €"));
+        assert!(!lossy);
+
+        let (body, lossy) =
+            parse_text_manually(b"\x82\xb1\x82\xf1", Some("shift_jis"), BodyFormat::Plain);
+        assert_eq!(body, "こん");
+        assert!(!lossy);
+
+        let (body, lossy) = parse_text_manually(b"Caf\xc3", Some("utf-8"), BodyFormat::Plain);
+        assert_eq!(body, "Caf\u{FFFD}");
+        assert!(lossy);
+    }
+
+    #[test]
+    fn test_parse_html_body() {
+        assert_eq!(
+            parse_html_body(ALTERNATIVE, &[]),
+            Some("<p>Hello <b>HTML</b></p>\n".to_string())
+        );
+        assert_eq!(parse_html_body(b"Subject: Plain\r\n\r\nHello", &[]), None);
+    }
+
+    #[test]
+    fn test_render_html_formats() {
+        let html = "<p>Hello <b>world</b>, see <a href=\"https://test.com\">the docs</a></p>\
+            <ul><li>one</li><li>two</li></ul>\
+            <table><tr><th>Name</th><th>Total</th></tr><tr><td>Ana</td><td>3</td></tr></table>";
+
+        let markdown = render_html(html, BodyFormat::Markdown);
+        assert!(markdown.contains("Hello **world**, see [the docs](https://test.com)"));
+        assert!(markdown.contains("* one\n* two"));
+        assert!(markdown.contains("|Name|Total|\n|----|-----|\n|Ana |  3  |"));
+
+        let plain = render_html(html, BodyFormat::Plain);
+        assert!(plain.starts_with("Hello world, see"));
+        assert_eq!(render_html(html, BodyFormat::Raw), html);
+    }
+}
@@ -0,0 +1,144 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+/// Extract the new content of a reply, dropping the quoted thread and the signature.
+///
+/// The text is cut at the first quote header ("On ... wrote:" in several languages),
+/// Outlook separator or header block, or signature delimiter, and `>` quoted lines
+/// are dropped.
+pub fn extract_new_content(body: &str) -> String {
+    static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(Patterns::new);
+
+    let lines: Vec<&str> = body.lines().collect();
+    let mut new_content: Vec<&str> = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        let next = lines.get(i + 1).copied().unwrap_or_default();
+        let is_quote_header = patterns.quote_header.is_match(line)
+            || (!next.trim().is_empty()
+                && patterns
+                    .quote_header
+                    .is_match(&format!("{} {}", line, next)));
+        if is_quote_header
+            || patterns.separator.is_match(line)
+            || patterns.is_header_block(&lines, i)
+            || patterns.signature.is_match(line.trim_end_matches('\r'))
+        {
+            break;
+        }
+        if !line.trim_start().starts_with('>') {
+            new_content.push(line);
+        }
+    }
+    new_content.join("\n").trim().to_string()
+}
+
+struct Patterns {
+    quote_header: Regex,
+    separator: Regex,
+    header_from: Regex,
+    header_date: Regex,
+    header_recipient: Regex,
+    signature: Regex,
+}
+
+impl Patterns {
+    fn new() -> Self {
+        Self {
+            // A quote header may be wrapped, so it is matched on two lines at most
+            quote_header: Regex::new(
+                r"(?is)^\s*(on\s.+wrote|el\s.+escribi[óo]|le\s.+a\s+[ée]crit|am\s.+schrieb.*|il\s.+ha\s+scritto|em\s.+escreveu|op\s.+schreef.*)\s*:\s*$",
+            )
+            .unwrap(),
+            separator: Regex::new(
+                r"(?i)^\s*(-{2,}\s*(original message|mensaje original|message d'origine|urspr[üu]ngliche nachricht|messaggio originale|mensagem original)\s*-{2,}|_{20,})\s*$",
+            )
+            .unwrap(),
+            header_from: Regex::new(r"(?i)^\s*\*?(from|de|von|da|van)\*?\s*:").unwrap(),
+            header_date: Regex::new(
+                r"(?i)^\s*\*?(sent|date|enviado|fecha|envoy[ée]|gesendet|inviato|data|verzonden)\*?\s*:",
+            )
+            .unwrap(),
+            header_recipient: Regex::new(
+                r"(?i)^\s*\*?(to|subject|para|asunto|[àa]|objet|an|betreff|oggetto|assunto|aan|onderwerp)\*?\s*:",
+            )
+            .unwrap(),
+            signature: Regex::new(
+                r"(?i)^(--\s?|sent from my .+|enviado desde mi .+|envoy[ée] de mon .+|von meinem .+ gesendet)$",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Whether the line starts the Outlook headers of the original message, without a
+    /// separator line: a "From:" line after a blank line (or at the start), followed
+    /// by a "Sent:" or "Date:" field and a "To:" or "Subject:" field.
+    fn is_header_block(&self, lines: &[&str], i: usize) -> bool {
+        if !self.header_from.is_match(lines[i]) {
+            return false;
+        }
+        if i > 0 && !lines[i - 1].trim().is_empty() {
+            return false;
+        }
+        let fields = &lines[i + 1..lines.len().min(i + 5)];
+        fields.iter().any(|line| self.header_date.is_match(line))
+            && fields
+                .iter()
+                .any(|line| self.header_recipient.is_match(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_new_content_quote_headers() {
+        let body = "Sounds good!\n\nOn Tue, Aug 1, 2023 at 10:00 AM Ana <ana@test.com> wrote:\n> Shall we meet?\n> Ana";
+        assert_eq!(extract_new_content(body), "Sounds good!");
+
+        // Wrapped header
+        let body = "Sounds good!\nOn Tue, Aug 1, 2023 at 10:00 AM Ana\n<ana@test.com> wrote:\n> Shall we meet?";
+        assert_eq!(extract_new_content(body), "Sounds good!");
+
+        let body = "¡Perfecto!\n\nEl mar, 1 ago 2023 a las 10:00, Ana (<ana@test.com>) escribió:\n> ¿Quedamos?";
+        assert_eq!(extract_new_content(body), "¡Perfecto!");
+
+        let body = "Parfait !\n\nLe mar. 1 août 2023 à 10:00, Ana <ana@test.com> a écrit :\n> On se voit ?";
+        assert_eq!(extract_new_content(body), "Parfait !");
+
+        let body =
+            "Gut!\n\nAm Di., 1. Aug. 2023 um 10:00 Uhr schrieb Ana <ana@test.com>:\n> Treffen?";
+        assert_eq!(extract_new_content(body), "Gut!");
+    }
+
+    #[test]
+    fn test_extract_new_content_outlook() {
+        let body = "See below\n\n-----Original Message-----\nFrom: Ana\nSent: Tuesday\nSubject: Hi";
+        assert_eq!(extract_new_content(body), "See below");
+
+        let body = "See below\n\n________________________________\nFrom: Ana <ana@test.com>\nSent: Tuesday";
+        assert_eq!(extract_new_content(body), "See below");
+
+        let body = "See below\n\nFrom: Ana <ana@test.com>\nSent: Tuesday, August 1, 2023\nTo: Bob\nSubject: Hi";
+        assert_eq!(extract_new_content(body), "See below");
+
+        // Fields in the new content are not a header block
+        let body = "Details of the trip\nFrom: Madrid\nDate: Tuesday\n\nFrom: Ana\nDate: Monday\nPrice: 20";
+        assert_eq!(extract_new_content(body), body);
+    }
+
+    #[test]
+    fn test_extract_new_content_signatures_and_inline_quotes() {
+        let body = "> Shall we meet?\nYes, at 10.\n> Where?\nAt the office.\n\n-- \nBob\nACME Corp";
+        assert_eq!(extract_new_content(body), "Yes, at 10.\nAt the office.");
+
+        let body = "Yes\n\nSent from my iPhone";
+        assert_eq!(extract_new_content(body), "Yes");
+
+        // Regular text is kept as it is
+        let body = "From: here to there, the plan is ready.\nThanks";
+        assert_eq!(extract_new_content(body), body);
+    }
+}
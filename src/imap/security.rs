@@ -0,0 +1,182 @@
+use mailparse::ParsedMail;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CryptoScheme {
+    Smime,
+    Pgp, // PGP/MIME (RFC 3156) or inline
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Encryption {
+    pub scheme: CryptoScheme,
+    pub decrypted: bool, // The body was extracted from the decrypted content
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureStatus {
+    #[default]
+    Unverified, // e.g. the public key of the signer is unknown
+    Valid,
+    Invalid,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Signature {
+    pub scheme: CryptoScheme,
+    pub status: SignatureStatus,
+    pub trusted: bool, // The signer certificate or key is trusted, not only the signature valid
+    pub signer: Option<String>, // e.g. the email address of the certificate, or the key user id
+    pub reason: Option<String>, // Why the signature is invalid or unverified
+}
+
+/// The cryptographic protection of a message.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Security {
+    pub encryption: Option<Encryption>,
+    pub signature: Option<Signature>,
+}
+
+/// Detect the S/MIME (RFC 8551) and OpenPGP (RFC 3156, or inline) structure of a message.
+///
+/// Only the outermost layer is detected, e.g. a message that is signed and then
+/// encrypted is reported as encrypted, until it is decrypted.
+pub fn detect_security(parsed: &ParsedMail<'_>, body: &str) -> Option<Security> {
+    let mimetype = parsed.ctype.mimetype.as_str();
+    let param = |name: &str| {
+        parsed
+            .ctype
+            .params
+            .get(name)
+            .map(|value| value.to_lowercase())
+            .unwrap_or_default()
+    };
+    let encrypted = |scheme| {
+        Some(Security {
+            encryption: Some(Encryption {
+                scheme,
+                decrypted: false,
+            }),
+            signature: None,
+        })
+    };
+    let signed = |scheme| {
+        Some(Security {
+            encryption: None,
+            signature: Some(Signature {
+                scheme,
+                status: SignatureStatus::Unverified,
+                trusted: false,
+                signer: None,
+                reason: None,
+            }),
+        })
+    };
+
+    match mimetype {
+        "application/pkcs7-mime" | "application/x-pkcs7-mime" => {
+            match param("smime-type").as_str() {
+                "signed-data" => signed(CryptoScheme::Smime),
+                "certs-only" => None,
+                // Missing in some clients, which only name the file smime.p7m
+                _ => encrypted(CryptoScheme::Smime),
+            }
+        }
+        "multipart/encrypted" if param("protocol") == "application/pgp-encrypted" => {
+            encrypted(CryptoScheme::Pgp)
+        }
+        "multipart/signed" => match param("protocol").as_str() {
+            "application/pkcs7-signature" | "application/x-pkcs7-signature" => {
+                signed(CryptoScheme::Smime)
+            }
+            "application/pgp-signature" => signed(CryptoScheme::Pgp),
+            _ => None,
+        },
+        // Inline PGP, only when the armored block is the body (e.g. not a quoted one)
+        _ if body.trim_start().starts_with("-----BEGIN PGP MESSAGE-----") => {
+            encrypted(CryptoScheme::Pgp)
+        }
+        _ if body
+            .trim_start()
+            .starts_with("-----BEGIN PGP SIGNED MESSAGE-----") =>
+        {
+            signed(CryptoScheme::Pgp)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect_raw(raw: &str) -> Option<Security> {
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let body = parsed.get_body().unwrap();
+        detect_security(&parsed, &body)
+    }
+
+    fn scheme(security: &Option<Security>) -> (Option<CryptoScheme>, Option<CryptoScheme>) {
+        match security {
+            Some(security) => (
+                security
+                    .encryption
+                    .as_ref()
+                    .map(|encryption| encryption.scheme),
+                security
+                    .signature
+                    .as_ref()
+                    .map(|signature| signature.scheme),
+            ),
+            None => (None, None),
+        }
+    }
+
+    #[test]
+    fn test_detect_security() {
+        let cases = [
+            (
+                "Content-Type: application/pkcs7-mime; smime-type=enveloped-data; name=smime.p7m\r\n\r\nMIIB",
+                (Some(CryptoScheme::Smime), None),
+            ),
+            (
+                "Content-Type: application/x-pkcs7-mime; name=smime.p7m\r\n\r\nMIIB",
+                (Some(CryptoScheme::Smime), None),
+            ),
+            (
+                "Content-Type: application/pkcs7-mime; smime-type=signed-data\r\n\r\nMIIB",
+                (None, Some(CryptoScheme::Smime)),
+            ),
+            (
+                "Content-Type: multipart/signed; protocol=\"application/x-pkcs7-signature\"; boundary=b\r\n\r\n--b--\r\n",
+                (None, Some(CryptoScheme::Smime)),
+            ),
+            (
+                "Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\"; boundary=b\r\n\r\n--b--\r\n",
+                (Some(CryptoScheme::Pgp), None),
+            ),
+            (
+                "Content-Type: multipart/signed; protocol=\"application/pgp-signature\"; boundary=b\r\n\r\n--b--\r\n",
+                (None, Some(CryptoScheme::Pgp)),
+            ),
+            (
+                "Subject: Hi\r\n\r\n-----BEGIN PGP MESSAGE-----\r\n\r\nhF4D\r\n-----END PGP MESSAGE-----\r\n",
+                (Some(CryptoScheme::Pgp), None),
+            ),
+            (
+                "Subject: Hi\r\n\r\n-----BEGIN PGP SIGNED MESSAGE-----\r\nHash: SHA256\r\n\r\nHi\r\n",
+                (None, Some(CryptoScheme::Pgp)),
+            ),
+            ("Subject: Hi\r\n\r\nHi\r\n", (None, None)),
+            (
+                "Subject: Hi\r\n\r\nMy messages start with -----BEGIN PGP MESSAGE-----, why?\r\n",
+                (None, None),
+            ),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(scheme(&detect_raw(raw)), expected, "{}", raw);
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+const CORE_CAPABILITY: &str = "urn:ietf:params:jmap:core";
+const MAIL_CAPABILITY: &str = "urn:ietf:params:jmap:mail";
+const PAGE_SIZE: usize = 256; // Ids per query page, emails per get and changes per call
+
+/// The JMAP session resource (RFC 8620, section 2).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub api_url: String,
+    pub download_url: String,
+    #[serde(default)]
+    pub event_source_url: Option<String>,
+    pub primary_accounts: HashMap<String, String>,
+    pub state: String,
+}
+
+impl Session {
+    /// The id of the account that holds the mail data.
+    pub fn mail_account_id(&self) -> Result<&str> {
+        self.primary_accounts
+            .get(MAIL_CAPABILITY)
+            .map(|id| id.as_str())
+            .ok_or_else(|| anyhow::Error::msg("JMAP session does not have a mail account"))
+    }
+}
+
+/// The subset of the JMAP Email object required to fetch the raw message.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailRef {
+    pub id: String,
+    pub blob_id: String,
+    #[serde(default)]
+    pub mailbox_ids: HashMap<String, bool>,
+}
+
+/// The ids of the emails of a mailbox, oldest first, alongside the Email state they
+/// were read at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MailboxEmails {
+    pub ids: Vec<String>,
+    pub state: String,
+}
+
+/// The ids of the emails created, updated (e.g. moved to another mailbox) and
+/// destroyed since a state.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EmailChanges {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub destroyed: Vec<String>,
+    pub state: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct JmapClient {
+    http: reqwest::Client,
+    session_url: String,
+    username: String,
+    password: String,
+}
+
+impl JmapClient {
+    pub fn new(session_url: String, username: String, password: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            session_url,
+            username,
+            password,
+        }
+    }
+
+    /// Fetch the session resource.
+    pub async fn session(&self) -> Result<Session> {
+        let session = self
+            .http
+            .get(&self.session_url)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Session>()
+            .await?;
+        Ok(session)
+    }
+
+    /// Find the id of the mailbox with the given name ("INBOX" matches the inbox role).
+    pub async fn find_mailbox(&self, session: &Session, name: &str) -> Result<Option<String>> {
+        let filter = if name.eq_ignore_ascii_case("INBOX") {
+            json!({ "role": "inbox" })
+        } else {
+            json!({ "name": name })
+        };
+        let responses = self
+            .call(
+                session,
+                json!([[
+                    "Mailbox/query",
+                    { "accountId": session.mail_account_id()?, "filter": filter },
+                    "q"
+                ]]),
+            )
+            .await?;
+        let ids = method_response(&responses, "Mailbox/query")?["ids"].clone();
+        let ids: Vec<String> = serde_json::from_value(ids)?;
+        Ok(ids.into_iter().next())
+    }
+
+    /// Get the ids of every email of a mailbox, used when there is no previous state to
+    /// resume from. The query is read in pages.
+    pub async fn query_mailbox(
+        &self,
+        session: &Session,
+        mailbox_id: &str,
+    ) -> Result<MailboxEmails> {
+        let account_id = session.mail_account_id()?;
+        let mut mailbox = MailboxEmails {
+            ids: vec![],
+            state: String::new(),
+        };
+        loop {
+            let query = json!([
+                "Email/query",
+                {
+                    "accountId": account_id,
+                    "filter": { "inMailbox": mailbox_id },
+                    "sort": [{ "property": "receivedAt", "isAscending": true }],
+                    "position": mailbox.ids.len(),
+                    "limit": PAGE_SIZE
+                },
+                "q"
+            ]);
+            // The state is read before the query, so no email created meanwhile is missed
+            let method_calls = if mailbox.ids.is_empty() {
+                json!([
+                    ["Email/get", { "accountId": account_id, "ids": [] }, "s"],
+                    query
+                ])
+            } else {
+                json!([query])
+            };
+            let responses = self.call(session, method_calls).await?;
+            if mailbox.ids.is_empty() {
+                mailbox.state = serde_json::from_value(
+                    method_response(&responses, "Email/get")?["state"].clone(),
+                )?;
+            }
+            let ids: Vec<String> =
+                serde_json::from_value(method_response(&responses, "Email/query")?["ids"].clone())?;
+            let last_page = ids.len() < PAGE_SIZE;
+            mailbox.ids.extend(ids);
+            if last_page {
+                return Ok(mailbox);
+            }
+        }
+    }
+
+    /// Get the emails with the given ids, in the same order. Missing emails are skipped.
+    pub async fn get_emails(&self, session: &Session, ids: &[String]) -> Result<Vec<EmailRef>> {
+        let account_id = session.mail_account_id()?;
+        let mut emails = vec![];
+        for chunk in ids.chunks(PAGE_SIZE) {
+            let responses = self
+                .call(
+                    session,
+                    json!([[
+                        "Email/get",
+                        {
+                            "accountId": account_id,
+                            "ids": chunk,
+                            "properties": ["id", "blobId", "mailboxIds"]
+                        },
+                        "g"
+                    ]]),
+                )
+                .await?;
+            let mut list: Vec<EmailRef> =
+                serde_json::from_value(method_response(&responses, "Email/get")?["list"].clone())?;
+            // The list is not required to follow the order of the ids
+            list.sort_by_key(|email| chunk.iter().position(|id| *id == email.id));
+            emails.append(&mut list);
+        }
+        Ok(emails)
+    }
+
+    /// Get the ids of the emails changed since the given state.
+    ///
+    /// Returns `None` when the server cannot tell the changes (e.g. `cannotCalculateChanges`
+    /// once the state is too old), so the caller has to resync (RFC 8620, section 5.2).
+    pub async fn changes(
+        &self,
+        session: &Session,
+        since_state: &str,
+    ) -> Result<Option<EmailChanges>> {
+        let account_id = session.mail_account_id()?;
+        let mut changes = EmailChanges {
+            state: since_state.to_string(),
+            ..Default::default()
+        };
+        loop {
+            let responses = self
+                .call(
+                    session,
+                    json!([[
+                        "Email/changes",
+                        {
+                            "accountId": account_id,
+                            "sinceState": changes.state,
+                            "maxChanges": PAGE_SIZE
+                        },
+                        "c"
+                    ]]),
+                )
+                .await?;
+            if let Some(error) = responses.iter().find(|response| response[0] == "error") {
+                warn!(
+                    "JMAP changes since state {} failed: {}",
+                    since_state, error[1]
+                );
+                return Ok(None);
+            }
+            let response = method_response(&responses, "Email/changes")?;
+            let ids = |name: &str| -> Vec<String> {
+                serde_json::from_value(response[name].clone()).unwrap_or_default()
+            };
+            changes.created.extend(ids("created"));
+            changes.updated.extend(ids("updated"));
+            changes.destroyed.extend(ids("destroyed"));
+            changes.state = serde_json::from_value(response["newState"].clone())?;
+            if !response["hasMoreChanges"].as_bool().unwrap_or(false) {
+                return Ok(Some(changes));
+            }
+        }
+    }
+
+    /// Download the raw RFC822 representation of an email.
+    pub async fn download(&self, session: &Session, email: &EmailRef) -> Result<Vec<u8>> {
+        let url = session
+            .download_url
+            .replace("{accountId}", session.mail_account_id()?)
+            .replace("{blobId}", &email.blob_id)
+            .replace("{type}", "message/rfc822")
+            .replace("{name}", &format!("{}.eml", email.id));
+        let bytes = self
+            .http
+            .get(url)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Wait until the server pushes an Email state change or the timeout expires.
+    ///
+    /// Returns `false` when the server does not support push, so the caller can poll instead.
+    pub async fn wait_for_changes(&self, session: &Session, timeout: Duration) -> Result<bool> {
+        let event_source_url = match &session.event_source_url {
+            Some(url) => url
+                .replace("{types}", "Email")
+                .replace("{closeafter}", "state")
+                .replace("{ping}", "0"),
+            None => return Ok(false),
+        };
+        let mut response = self
+            .http
+            .get(event_source_url)
+            .basic_auth(&self.username, Some(&self.password))
+            .header("Accept", "text/event-stream")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let wait = async {
+            let mut buffer = String::new();
+            while let Some(chunk) = response.chunk().await? {
+                buffer.push_str(&String::from_utf8_lossy(&chunk));
+                if buffer.lines().any(|line| line.trim() == "event: state") {
+                    debug!("-- JMAP push state change received");
+                    break;
+                }
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(result) => result?,
+            Err(_) => debug!("-- JMAP push timed out"),
+        }
+        Ok(true)
+    }
+
+    async fn call(&self, session: &Session, method_calls: Value) -> Result<Vec<Value>> {
+        let request = json!({
+            "using": [CORE_CAPABILITY, MAIL_CAPABILITY],
+            "methodCalls": method_calls,
+        });
+        let response: Value = self
+            .http
+            .post(&session.api_url)
+            .basic_auth(&self.username, Some(&self.password))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let responses: Vec<Value> = serde_json::from_value(response["methodResponses"].clone())?;
+        Ok(responses)
+    }
+}
+
+fn method_response<'a>(responses: &'a [Value], name: &str) -> Result<&'a Value> {
+    for response in responses {
+        match response[0].as_str() {
+            Some("error") => {
+                return Err(anyhow::Error::msg(format!(
+                    "JMAP method error: {}",
+                    response[1]
+                )))
+            }
+            Some(method) if method == name => return Ok(&response[1]),
+            _ => (),
+        }
+    }
+    Err(anyhow::Error::msg(format!(
+        "JMAP response is missing {}",
+        name
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn mock_session(server: &MockServer) -> (JmapClient, Session) {
+        Mock::given(method("GET"))
+            .and(path("/.well-known/jmap"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "apiUrl": format!("{}/api", server.uri()),
+                "downloadUrl": format!("{}/download/{{accountId}}/{{blobId}}/{{name}}?type={{type}}", server.uri()),
+                "eventSourceUrl": null,
+                "primaryAccounts": { MAIL_CAPABILITY: "u1" },
+                "state": "session1"
+            })))
+            .mount(server)
+            .await;
+        let client = JmapClient::new(
+            format!("{}/.well-known/jmap", server.uri()),
+            "test@test.com".to_string(),
+            "password".to_string(),
+        );
+        let session = client.session().await.unwrap();
+        (client, session)
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let server = MockServer::start().await;
+        let (_, session) = mock_session(&server).await;
+        assert_eq!(session.api_url, format!("{}/api", server.uri()));
+        assert_eq!(session.mail_account_id().unwrap(), "u1");
+        assert_eq!(session.event_source_url, None);
+    }
+
+    #[tokio::test]
+    async fn test_query_mailbox_in_pages() {
+        let server = MockServer::start().await;
+        let (client, session) = mock_session(&server).await;
+        let first_page: Vec<String> = (0..PAGE_SIZE).map(|i| format!("e{}", i)).collect();
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(json!({
+                "methodCalls": [
+                    ["Email/get", { "ids": [] }, "s"],
+                    ["Email/query", { "filter": { "inMailbox": "mb1" }, "position": 0 }, "q"]
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [
+                    ["Email/get", { "state": "s1", "list": [] }, "s"],
+                    ["Email/query", { "ids": first_page }, "q"]
+                ]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(json!({
+                "methodCalls": [["Email/query", { "position": PAGE_SIZE }, "q"]]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["Email/query", { "ids": ["last"] }, "q"]]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mailbox = client.query_mailbox(&session, "mb1").await.unwrap();
+        assert_eq!(mailbox.state, "s1");
+        assert_eq!(mailbox.ids.len(), PAGE_SIZE + 1);
+        assert_eq!(mailbox.ids[0], "e0");
+        assert_eq!(mailbox.ids[PAGE_SIZE], "last");
+    }
+
+    #[tokio::test]
+    async fn test_get_emails_and_download() {
+        let server = MockServer::start().await;
+        let (client, session) = mock_session(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(json!({
+                "methodCalls": [["Email/get", { "ids": ["e1", "e2"] }, "g"]]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [
+                    ["Email/get", {
+                        "state": "s1",
+                        "list": [
+                            { "id": "e2", "blobId": "b2", "mailboxIds": { "mb1": true } },
+                            { "id": "e1", "blobId": "b1", "mailboxIds": { "mb1": true } }
+                        ]
+                    }, "g"]
+                ]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/download/u1/b1/e1.eml"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Subject: Hi\r\n\r\nHello"))
+            .mount(&server)
+            .await;
+
+        let ids = vec!["e1".to_string(), "e2".to_string()];
+        let emails = client.get_emails(&session, &ids).await.unwrap();
+        assert_eq!(emails.len(), 2);
+        assert_eq!(emails[0].blob_id, "b1");
+
+        let raw = client.download(&session, &emails[0]).await.unwrap();
+        assert_eq!(raw, b"Subject: Hi\r\n\r\nHello");
+    }
+
+    #[tokio::test]
+    async fn test_changes() {
+        let server = MockServer::start().await;
+        let (client, session) = mock_session(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(json!({
+                "methodCalls": [["Email/changes", { "sinceState": "s1" }, "c"]]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [
+                    ["Email/changes", {
+                        "newState": "s2", "hasMoreChanges": true,
+                        "created": ["e2"], "updated": ["e1"], "destroyed": []
+                    }, "c"]
+                ]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(json!({
+                "methodCalls": [["Email/changes", { "sinceState": "s2" }, "c"]]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [
+                    ["Email/changes", {
+                        "newState": "s3", "hasMoreChanges": false,
+                        "created": [], "updated": [], "destroyed": ["e0"]
+                    }, "c"]
+                ]
+            })))
+            .mount(&server)
+            .await;
+
+        let changes = client.changes(&session, "s1").await.unwrap();
+        assert_eq!(
+            changes,
+            Some(EmailChanges {
+                created: vec!["e2".to_string()],
+                updated: vec!["e1".to_string()],
+                destroyed: vec!["e0".to_string()],
+                state: "s3".to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_method_error() {
+        let server = MockServer::start().await;
+        let (client, session) = mock_session(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["error", { "type": "cannotCalculateChanges" }, "c"]]
+            })))
+            .mount(&server)
+            .await;
+
+        assert_eq!(client.changes(&session, "s1").await.unwrap(), None);
+        assert!(client
+            .get_emails(&session, &["e1".to_string()])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_changes_without_push() {
+        let server = MockServer::start().await;
+        let (client, session) = mock_session(&server).await;
+        let pushed = client
+            .wait_for_changes(&session, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(!pushed);
+    }
+}
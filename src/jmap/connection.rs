@@ -0,0 +1,404 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tracing::debug;
+
+use crate::{
+    source::{MailSource, RawMessage},
+    store::{self, Account, JmapSettings},
+};
+
+use super::client::{EmailRef, JmapClient, Session};
+
+pub struct JmapSource {
+    account: Account,
+    settings: JmapSettings,
+    store: Arc<dyn store::Store>,
+    client: JmapClient,
+    session: Option<(Session, String)>, // The session with the selected mailbox id
+    state: Option<(String, HashSet<String>)>, // The Email state with the ids in the mailbox
+}
+
+impl JmapSource {
+    pub fn new(account: Account, settings: JmapSettings, store: Arc<dyn store::Store>) -> Self {
+        let client = JmapClient::new(
+            settings.session_url.clone(),
+            account.email.clone(),
+            account.password.clone(),
+        );
+        Self {
+            account,
+            settings,
+            store,
+            client,
+            session: None,
+            state: None,
+        }
+    }
+
+    /// Download the raw messages of the emails.
+    async fn download(&self, emails: &[EmailRef]) -> Result<Vec<RawMessage>> {
+        let (session, _) = self
+            .session
+            .as_ref()
+            .ok_or_else(|| anyhow::Error::msg("JMAP session is not loaded"))?;
+        let mut raw_messages = vec![];
+        for email_ref in emails.iter() {
+            raw_messages.push(RawMessage::Rfc822 {
+                id: email_ref.id.clone(),
+                // JMAP ids are opaque strings, there is no numeric UID to report
+                seq_id: 0,
+                data: self.client.download(session, email_ref).await?,
+            });
+        }
+        Ok(raw_messages)
+    }
+}
+
+#[async_trait]
+impl MailSource for JmapSource {
+    async fn connect(&mut self) -> Result<()> {
+        let session = self.client.session().await?;
+        debug!("-- JMAP session loaded for account {}", self.account.email);
+
+        let mailbox_id = self
+            .client
+            .find_mailbox(&session, &self.account.mailbox)
+            .await?
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!("Mailbox {} not found", self.account.mailbox))
+            })?;
+        self.session = Some((session, mailbox_id));
+        Ok(())
+    }
+
+    async fn fetch_new(&mut self) -> Result<Vec<RawMessage>> {
+        let email = &self.account.email;
+        let (session, mailbox_id) = self
+            .session
+            .as_ref()
+            .ok_or_else(|| anyhow::Error::msg("JMAP session is not loaded"))?;
+
+        // The Email state is the checkpoint, the latest emails are fetched when missing
+        let (emails, state, known_ids) = match self.store.load_jmap_state(email).await? {
+            Some(state) => {
+                debug!("Fetching emails for '{}' since state '{}'", email, state);
+                let mut known_ids = self.store.load_jmap_ids(email).await?;
+                if known_ids.is_empty() {
+                    // Not known yet (e.g. the state was stored by a previous version)
+                    known_ids = self
+                        .client
+                        .query_mailbox(session, mailbox_id)
+                        .await?
+                        .ids
+                        .into_iter()
+                        .collect();
+                }
+                let changes = match self.client.changes(session, &state).await? {
+                    Some(changes) => changes,
+                    None => {
+                        // The state is dropped with the known ids, which are replaced by
+                        // the ones in the mailbox now, publishing the ones not known yet
+                        debug!("Resyncing emails for '{}' from state '{}'", email, state);
+                        let mailbox = self.client.query_mailbox(session, mailbox_id).await?;
+                        let new_ids: Vec<String> = mailbox
+                            .ids
+                            .iter()
+                            .filter(|id| !known_ids.contains(*id))
+                            .cloned()
+                            .collect();
+                        let emails = self.client.get_emails(session, &new_ids).await?;
+                        self.state = Some((mailbox.state, mailbox.ids.into_iter().collect()));
+                        return self.download(&emails).await;
+                    }
+                };
+                for id in changes.destroyed.iter() {
+                    known_ids.remove(id);
+                }
+                // Updated emails include the ones moved to the mailbox
+                let mut changed = changes.created;
+                changed.extend(changes.updated);
+                let mut seen = HashSet::new();
+                changed.retain(|id| seen.insert(id.clone()));
+                let mut emails = vec![];
+                for email_ref in self.client.get_emails(session, &changed).await? {
+                    if !in_mailbox(&email_ref, mailbox_id) {
+                        known_ids.remove(&email_ref.id);
+                    } else if known_ids.insert(email_ref.id.clone()) {
+                        emails.push(email_ref);
+                    }
+                }
+                (emails, changes.state, known_ids)
+            }
+            None => {
+                debug!(
+                    "Fetching the latest {} emails for '{}' in mailbox '{}'",
+                    self.settings.initial_window, email, mailbox_id
+                );
+                let mailbox = self.client.query_mailbox(session, mailbox_id).await?;
+                let start = mailbox
+                    .ids
+                    .len()
+                    .saturating_sub(self.settings.initial_window);
+                let emails = self
+                    .client
+                    .get_emails(session, &mailbox.ids[start..])
+                    .await?;
+                (emails, mailbox.state, mailbox.ids.into_iter().collect())
+            }
+        };
+
+        self.state = Some((state, known_ids));
+        self.download(&emails).await
+    }
+
+    async fn checkpoint(&mut self) -> Result<()> {
+        if let Some((state, known_ids)) = self.state.take() {
+            self.store
+                .store_jmap_ids(&self.account.email, &known_ids)
+                .await?;
+            self.store
+                .store_jmap_state(&self.account.email, &state)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_changes(&mut self) -> Result<()> {
+        let (session, _) = self
+            .session
+            .take()
+            .ok_or_else(|| anyhow::Error::msg("JMAP session is not loaded"))?;
+
+        // Wait for a push notification (unless the server does not support it)
+        debug!("-- JMAP waiting for changes");
+        let pushed = self
+            .client
+            .wait_for_changes(
+                &session,
+                Duration::from_secs(self.account.idle_time_seconds),
+            )
+            .await?;
+        if !pushed {
+            debug!("-- JMAP push is not supported, polling instead");
+        }
+        Ok(())
+    }
+}
+
+fn in_mailbox(email: &EmailRef, mailbox_id: &str) -> bool {
+    email.mailbox_ids.get(mailbox_id).copied().unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mocks::MockStore;
+    use crate::store::{AccountPolicy, SourceSettings, Store};
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_fetch_new_includes_moved_emails() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/jmap"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "apiUrl": format!("{}/api", server.uri()),
+                "downloadUrl": format!("{}/download/{{accountId}}/{{blobId}}/{{name}}", server.uri()),
+                "primaryAccounts": { "urn:ietf:params:jmap:mail": "u1" },
+                "state": "session1"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(
+                json!({ "methodCalls": [["Mailbox/query", {}, "q"]] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["Mailbox/query", { "ids": ["mb1"] }, "q"]]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(
+                json!({ "methodCalls": [["Email/changes", {}, "c"]] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["Email/changes", {
+                    "newState": "s2", "hasMoreChanges": false,
+                    "created": ["new"], "updated": ["seen", "moved", "archived"], "destroyed": []
+                }, "c"]]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(
+                json!({ "methodCalls": [["Email/get", {}, "g"]] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["Email/get", { "state": "s2", "list": [
+                    { "id": "new", "blobId": "b1", "mailboxIds": { "mb1": true } },
+                    { "id": "seen", "blobId": "b2", "mailboxIds": { "mb1": true } },
+                    { "id": "moved", "blobId": "b3", "mailboxIds": { "mb1": true } },
+                    { "id": "archived", "blobId": "b4", "mailboxIds": { "mb2": true } }
+                ] }, "g"]]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/download/u1/b1/new.eml"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Subject: New"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/download/u1/b3/moved.eml"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Subject: Moved"))
+            .mount(&server)
+            .await;
+
+        let settings = JmapSettings {
+            session_url: format!("{}/.well-known/jmap", server.uri()),
+            initial_window: 500,
+        };
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Jmap(settings.clone()),
+            policy: AccountPolicy::default(),
+        };
+        let store = Arc::new(MockStore::default());
+        store.store_jmap_state(&account.email, "s1").await.unwrap();
+        let known: HashSet<String> = ["seen".to_string(), "archived".to_string()].into();
+        store.store_jmap_ids(&account.email, &known).await.unwrap();
+        let mut source = JmapSource::new(account.clone(), settings, store.clone());
+
+        source.connect().await.unwrap();
+        let raw_messages = source.fetch_new().await.unwrap();
+        source.checkpoint().await.unwrap();
+
+        // The email marked as seen is not published again
+        let ids: Vec<String> = raw_messages
+            .iter()
+            .map(|raw_message| match raw_message {
+                RawMessage::Rfc822 { id, .. } => id.clone(),
+                RawMessage::Imap { .. } => unreachable!(),
+            })
+            .collect();
+        assert_eq!(ids, vec!["new", "moved"]);
+        assert_eq!(
+            store.load_jmap_state(&account.email).await.unwrap(),
+            Some("s2".to_string())
+        );
+        let known: HashSet<String> = ["new", "seen", "moved"].map(String::from).into();
+        assert_eq!(store.load_jmap_ids(&account.email).await.unwrap(), known);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_new_resyncs_when_changes_fail() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/.well-known/jmap"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "apiUrl": format!("{}/api", server.uri()),
+                "downloadUrl": format!("{}/download/{{accountId}}/{{blobId}}/{{name}}", server.uri()),
+                "primaryAccounts": { "urn:ietf:params:jmap:mail": "u1" },
+                "state": "session1"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(
+                json!({ "methodCalls": [["Mailbox/query", {}, "q"]] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["Mailbox/query", { "ids": ["mb1"] }, "q"]]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(
+                json!({ "methodCalls": [["Email/changes", {}, "c"]] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["error", { "type": "cannotCalculateChanges" }, "c"]]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(
+                json!({ "methodCalls": [["Email/get", {}, "s"], ["Email/query", {}, "q"]] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [
+                    ["Email/get", { "state": "s5", "list": [] }, "s"],
+                    ["Email/query", { "ids": ["seen", "new"] }, "q"]
+                ]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api"))
+            .and(body_partial_json(
+                json!({ "methodCalls": [["Email/get", { "ids": ["new"] }, "g"]] }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "methodResponses": [["Email/get", { "state": "s5", "list": [
+                    { "id": "new", "blobId": "b1", "mailboxIds": { "mb1": true } }
+                ] }, "g"]]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/download/u1/b1/new.eml"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("Subject: New"))
+            .mount(&server)
+            .await;
+
+        let settings = JmapSettings {
+            session_url: format!("{}/.well-known/jmap", server.uri()),
+            initial_window: 500,
+        };
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Jmap(settings.clone()),
+            policy: AccountPolicy::default(),
+        };
+        let store = Arc::new(MockStore::default());
+        store.store_jmap_state(&account.email, "s1").await.unwrap();
+        let known: HashSet<String> = ["seen".to_string(), "gone".to_string()].into();
+        store.store_jmap_ids(&account.email, &known).await.unwrap();
+        let mut source = JmapSource::new(account.clone(), settings, store.clone());
+
+        source.connect().await.unwrap();
+        let raw_messages = source.fetch_new().await.unwrap();
+        source.checkpoint().await.unwrap();
+
+        // Only the email not known yet is published, and the checkpoint is replaced
+        assert_eq!(raw_messages.len(), 1);
+        assert!(matches!(&raw_messages[0], RawMessage::Rfc822 { id, .. } if id == "new"));
+        assert_eq!(
+            store.load_jmap_state(&account.email).await.unwrap(),
+            Some("s5".to_string())
+        );
+        let known: HashSet<String> = ["seen", "new"].map(String::from).into();
+        assert_eq!(store.load_jmap_ids(&account.email).await.unwrap(), known);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::fs;
+use tracing::debug;
+
+use crate::{
+    source::{MailSource, RawMessage},
+    store::{self, Account, LocalSettings},
+};
+
+use super::{maildir, mbox};
+
+pub struct MaildirSource {
+    account: Account,
+    path: PathBuf,
+    pending: Vec<maildir::MaildirEntry>,
+}
+
+impl MaildirSource {
+    pub fn new(account: Account, settings: LocalSettings) -> Self {
+        Self {
+            account,
+            path: PathBuf::from(settings.path),
+            pending: vec![],
+        }
+    }
+}
+
+#[async_trait]
+impl MailSource for MaildirSource {
+    async fn connect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn fetch_new(&mut self) -> Result<Vec<RawMessage>> {
+        debug!(
+            "Fetching emails for '{}' from maildir '{}'",
+            self.account.email,
+            self.path.display()
+        );
+        self.pending = maildir::list_new(&self.path).await?;
+        let mut raw_messages = vec![];
+        for entry in self.pending.iter() {
+            raw_messages.push(RawMessage::Rfc822 {
+                id: entry.unique.clone(),
+                // Local messages do not have a UID
+                seq_id: 0,
+                data: fs::read(&entry.path).await?,
+            });
+        }
+        Ok(raw_messages)
+    }
+
+    async fn checkpoint(&mut self) -> Result<()> {
+        // Moving a message from new/ to cur/ is the checkpoint
+        for entry in std::mem::take(&mut self.pending) {
+            maildir::mark_current(&self.path, &entry).await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_changes(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct MboxSource {
+    account: Account,
+    path: PathBuf,
+    store: Arc<dyn store::Store>,
+    offset: Option<u64>,
+}
+
+impl MboxSource {
+    pub fn new(account: Account, settings: LocalSettings, store: Arc<dyn store::Store>) -> Self {
+        Self {
+            account,
+            path: PathBuf::from(settings.path),
+            store,
+            offset: None,
+        }
+    }
+}
+
+#[async_trait]
+impl MailSource for MboxSource {
+    async fn connect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn fetch_new(&mut self) -> Result<Vec<RawMessage>> {
+        let offset = self.store.load_mbox_offset(&self.account.email).await?;
+        debug!(
+            "Fetching emails for '{}' from mbox '{}' at offset {}",
+            self.account.email,
+            self.path.display(),
+            offset
+        );
+        let chunk = mbox::read_from(&self.path, offset).await?;
+        self.offset = Some(chunk.offset);
+        Ok(chunk
+            .messages
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| RawMessage::Rfc822 {
+                id: format!("{}#{}", offset, index),
+                // Local messages do not have a UID
+                seq_id: 0,
+                data,
+            })
+            .collect())
+    }
+
+    async fn checkpoint(&mut self) -> Result<()> {
+        if let Some(offset) = self.offset.take() {
+            self.store
+                .store_mbox_offset(&self.account.email, offset)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_changes(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tokio::fs;
+
+/// A message delivered to the `new/` directory of a Maildir.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaildirEntry {
+    pub path: PathBuf,
+    pub unique: String,
+}
+
+/// List the messages that have not been seen yet, oldest first.
+pub async fn list_new(maildir: &Path) -> Result<Vec<MaildirEntry>> {
+    let mut entries = vec![];
+    let mut dir = fs::read_dir(maildir.join("new")).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let unique = entry.file_name().to_string_lossy().to_string();
+        // Dot files are left by some delivery agents and are not messages
+        if unique.starts_with('.') {
+            continue;
+        }
+        entries.push(MaildirEntry {
+            path: entry.path(),
+            unique,
+        });
+    }
+    // Unique names start with the delivery time, so sorting keeps the arrival order
+    entries.sort_by(|a, b| a.unique.cmp(&b.unique));
+    Ok(entries)
+}
+
+/// Move a message from `new/` to `cur/`, without setting any flag.
+pub async fn mark_current(maildir: &Path, entry: &MaildirEntry) -> Result<()> {
+    let target = maildir.join("cur").join(format!("{}:2,", entry.unique));
+    fs::rename(&entry.path, target).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_list_new_and_mark_current() {
+        let maildir = tempfile::tempdir().unwrap();
+        for dir in ["new", "cur", "tmp"] {
+            fs::create_dir(maildir.path().join(dir)).await.unwrap();
+        }
+        fs::write(maildir.path().join("new/2.host"), "Subject: 2\r\n\r\n")
+            .await
+            .unwrap();
+        fs::write(maildir.path().join("new/1.host"), "Subject: 1\r\n\r\n")
+            .await
+            .unwrap();
+        fs::write(maildir.path().join("new/.lock"), "")
+            .await
+            .unwrap();
+
+        let entries = list_new(maildir.path()).await.unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| e.unique.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1.host", "2.host"]
+        );
+
+        mark_current(maildir.path(), &entries[0]).await.unwrap();
+        assert!(maildir.path().join("cur/1.host:2,").exists());
+        assert_eq!(list_new(maildir.path()).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,205 @@
+use std::path::Path;
+
+use anyhow::Result;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+};
+
+const READ_SIZE: usize = 1024 * 1024;
+// The messages read at once, the rest are read by the next call
+const MAX_READ_SIZE: usize = 16 * 1024 * 1024;
+
+/// The complete messages appended to an mbox file after a byte offset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MboxChunk {
+    pub messages: Vec<Vec<u8>>,
+    pub offset: u64, // Where the next read should start
+}
+
+/// Read the messages appended to an mbox file since the given offset.
+///
+/// The file is read from the start again when it was truncated or rotated. It is
+/// read in chunks, and stops once about `MAX_READ_SIZE` bytes of complete messages
+/// were read, so a large backlog is split between several calls.
+pub async fn read_from(path: &Path, offset: u64) -> Result<MboxChunk> {
+    let mut file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+    let offset = if length < offset { 0 } else { offset };
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut data = vec![];
+    let mut buffer = vec![0; READ_SIZE];
+    // The separators found so far, only the lines appended since are scanned
+    let mut separators = 0;
+    let mut scanned = 0;
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..read]);
+        let (found, end) = count_separators(&data, scanned);
+        separators += found;
+        scanned = end;
+        // A message larger than the limit is still read whole
+        let complete = separators > 1 || (separators == 1 && ends_with_blank_line(&data));
+        if data.len() >= MAX_READ_SIZE && complete {
+            break;
+        }
+    }
+
+    let (messages, consumed) = split_messages(&data);
+    Ok(MboxChunk {
+        messages,
+        offset: offset + consumed as u64,
+    })
+}
+
+/// Count the "From " separator lines that start at or after a position, up to the
+/// last complete line, returning the count and where the next scan should start.
+fn count_separators(data: &[u8], from: usize) -> (usize, usize) {
+    let mut count = 0;
+    let mut position = from;
+    for line in data[from..].split_inclusive(|b| *b == b'\n') {
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        if is_separator(data, position, line) {
+            count += 1;
+        }
+        position += line.len();
+    }
+    (count, position)
+}
+
+/// Whether a line is a "From " separator, i.e. it starts the data or follows a blank line.
+fn is_separator(data: &[u8], position: usize, line: &[u8]) -> bool {
+    line.starts_with(b"From ") && (position == 0 || ends_with_blank_line(&data[..position]))
+}
+
+/// Split mbox data in messages, returning how many bytes were consumed.
+///
+/// The last message is only consumed once it ends with a blank line, since the
+/// delivery agent could still be writing it.
+fn split_messages(data: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut starts = vec![];
+    let mut position = 0;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        if is_separator(data, position, line) {
+            starts.push(position);
+        }
+        position += line.len();
+    }
+
+    let mut messages = vec![];
+    let mut consumed = 0;
+    for (index, start) in starts.iter().enumerate() {
+        let end = match starts.get(index + 1) {
+            Some(next) => *next,
+            None if ends_with_blank_line(data) => data.len(),
+            None => break,
+        };
+        messages.push(unquote(&data[*start..end]));
+        consumed = end;
+    }
+    (messages, consumed)
+}
+
+/// Whether the data ends with a blank line, with LF or CRLF line endings.
+fn ends_with_blank_line(data: &[u8]) -> bool {
+    data.ends_with(b"\n\n") || data.ends_with(b"\n\r\n")
+}
+
+/// Remove the "From " separator line and the mboxrd quoting of the body lines.
+fn unquote(message: &[u8]) -> Vec<u8> {
+    let mut unquoted = vec![];
+    for line in message.split_inclusive(|b| *b == b'\n').skip(1) {
+        let quotes = line.iter().take_while(|b| **b == b'>').count();
+        if quotes > 0 && line[quotes..].starts_with(b"From ") {
+            unquoted.extend_from_slice(&line[1..]);
+        } else {
+            unquoted.extend_from_slice(line);
+        }
+    }
+    // The blank line before the next separator belongs to the mbox format
+    if unquoted.ends_with(b"\r\n\r\n") {
+        unquoted.truncate(unquoted.len() - 2);
+    } else if unquoted.ends_with(b"\n\n") {
+        unquoted.pop();
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MBOX: &[u8] = b"From a@test.com Mon Jan  1 00:00:00 2024\n\
+Subject: First\n\
+\n\
+>From the start\n\
+\n\
+From b@test.com Mon Jan  1 00:00:01 2024\n\
+Subject: Second\n\
+\n\
+Body\n\
+\n";
+
+    #[test]
+    fn test_split_messages() {
+        let (messages, consumed) = split_messages(MBOX);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], b"Subject: First\n\nFrom the start\n");
+        assert_eq!(messages[1], b"Subject: Second\n\nBody\n");
+        assert_eq!(consumed, MBOX.len());
+    }
+
+    #[test]
+    fn test_split_messages_incomplete() {
+        let data = b"From a@test.com Mon Jan  1 00:00:00 2024\nSubject: First\n\nBody\n\nFrom b@test.com Mon Jan  1 00:00:01 2024\nSubject: Sec";
+        let (messages, consumed) = split_messages(data);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(&data[consumed..consumed + 5], b"From ");
+    }
+
+    #[test]
+    fn test_count_separators() {
+        // Scanned in two parts, split in the middle of the second separator line
+        let split = MBOX.windows(6).position(|w| w == b"From b").unwrap() + 10;
+        let (first, scanned) = count_separators(&MBOX[..split], 0);
+        assert_eq!(first, 1);
+        assert!(scanned <= split);
+        let (second, scanned) = count_separators(MBOX, scanned);
+        assert_eq!(second, 1);
+        assert_eq!(scanned, MBOX.len());
+    }
+
+    #[test]
+    fn test_split_messages_crlf() {
+        let data = b"From a@test.com Mon Jan  1 00:00:00 2024\r\nSubject: First\r\n\r\nBody\r\n\r\nFrom b@test.com Mon Jan  1 00:00:01 2024\r\nSubject: Second\r\n\r\n>From here\r\n\r\n";
+        let (messages, consumed) = split_messages(data);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], b"Subject: First\r\n\r\nBody\r\n");
+        assert_eq!(messages[1], b"Subject: Second\r\n\r\nFrom here\r\n");
+        assert_eq!(consumed, data.len());
+    }
+
+    #[tokio::test]
+    async fn test_read_from_offset() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), MBOX).unwrap();
+
+        let chunk = read_from(file.path(), 0).await.unwrap();
+        assert_eq!(chunk.messages.len(), 2);
+        assert_eq!(chunk.offset, MBOX.len() as u64);
+
+        let chunk = read_from(file.path(), chunk.offset).await.unwrap();
+        assert!(chunk.messages.is_empty());
+
+        // A rotated file is read from the start
+        let chunk = read_from(file.path(), MBOX.len() as u64 + 10)
+            .await
+            .unwrap();
+        assert_eq!(chunk.messages.len(), 2);
+    }
+}
@@ -0,0 +1,7 @@
+mod connection;
+mod maildir;
+mod mbox;
+
+pub use connection::*;
+pub use maildir::*;
+pub use mbox::*;
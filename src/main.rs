@@ -6,11 +6,24 @@ use tracing::{debug, error, info};
 
 use anyhow::Result;
 
+pub mod archive;
+pub mod blob;
 pub mod config;
+pub mod crypto;
+pub mod dkim;
 pub mod fixtures;
 pub mod imap;
+pub mod jmap;
+pub mod local;
+#[cfg(test)]
+pub mod mocks;
+pub mod pop3;
+pub mod publisher;
 pub mod queue;
+pub mod smtp;
+pub mod source;
 pub mod store;
+pub mod threads;
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
@@ -41,16 +54,59 @@ pub async fn main() -> Result<()> {
         Arc::new(queue::RedisQueue::new(config.redis_server.to_string()).await);
     info!("Queue set up at {}", config.redis_server);
 
+    let blob_store = config.blob.clone().map(|blob| {
+        info!("Blob store set up at {}", blob_location(&blob));
+        build_blob_store(blob)
+    });
+    let archive = config.archive.clone().map(|archive| {
+        info!(
+            "Archive set up at {} with a retention of {:?} days",
+            blob_location(&archive.store),
+            archive.retention_days
+        );
+        Arc::new(archive::Archive::new(
+            build_blob_store(archive.store),
+            archive.retention_days,
+        ))
+    });
+    if let Some(archive) = archive.clone() {
+        task::spawn(archive::run_retention(archive));
+    }
+    let resolver: Option<Arc<dyn dkim::DnsResolver>> = match dkim::SystemResolver::new() {
+        Ok(resolver) => Some(Arc::new(resolver)),
+        Err(e) => {
+            error!(
+                "Unable to set up the DNS resolver, DKIM is not verified: {}",
+                e
+            );
+            None
+        }
+    };
+    let publisher = Arc::new(publisher::Publisher::new(
+        store.clone(),
+        queue,
+        blob_store,
+        resolver,
+        config.keystore_path.clone().map(crypto::Keystore::new),
+        archive,
+    ));
+
+    // Receive pushed messages if the inbound listener is configured
+    let inbound_task = config.inbound.clone().map(|inbound| {
+        info!("Starting inbound listener on {}...", inbound.listen_addr);
+        task::spawn(smtp::serve(inbound, store.clone(), publisher.clone()))
+    });
+
     let accounts_res = store.load_accounts_by_host("*".to_string()).await;
     match accounts_res {
         Ok(accounts) => {
             debug!("Accounts loaded: {:?}", accounts);
             let mut tasks = vec![];
             for account in accounts {
-                let task = task::spawn(imap::idle_inbox(
+                let task = task::spawn(source::run(
                     account.clone(),
                     store.clone(),
-                    queue.clone(),
+                    publisher.clone(),
                 ));
                 tasks.push(task);
             }
@@ -58,6 +114,9 @@ pub async fn main() -> Result<()> {
             for task in tasks {
                 let _ = task.await.unwrap();
             }
+            if let Some(task) = inbound_task {
+                let _ = task.await.unwrap();
+            }
         }
         Err(e) => {
             error!("Error while loading accounts: {:?}", e);
@@ -66,3 +125,27 @@ pub async fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn build_blob_store(blob: config::BlobConfig) -> Arc<dyn blob::BlobStore> {
+    match blob {
+        config::BlobConfig::Filesystem { path } => Arc::new(blob::FileBlobStore::new(path)),
+        config::BlobConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+        } => Arc::new(blob::S3BlobStore::new(
+            endpoint, bucket, region, access_key, secret_key,
+        )),
+    }
+}
+
+fn blob_location(blob: &config::BlobConfig) -> String {
+    match blob {
+        config::BlobConfig::Filesystem { path } => path.clone(),
+        config::BlobConfig::S3 {
+            endpoint, bucket, ..
+        } => format!("{}/{}", endpoint, bucket),
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::dkim::DnsResolver;
+use crate::queue::{BounceEvent, DeadLetter, Queue, QueueMessage};
+use crate::store::{Account, Store};
+
+/// In-memory store, for the tests that cannot rely on a Redis instance.
+#[derive(Default)]
+pub struct MockStore {
+    pub accounts: Mutex<HashMap<String, Account>>,
+    pub sequences: Mutex<HashMap<String, u32>>,
+    pub jmap_states: Mutex<HashMap<String, String>>,
+    pub jmap_ids: Mutex<HashMap<String, HashSet<String>>>,
+    pub pop3_uidls: Mutex<HashMap<String, HashSet<String>>>,
+    pub mbox_offsets: Mutex<HashMap<String, u64>>,
+    pub threads: Mutex<HashMap<(String, String), String>>,
+    pub thread_sizes: Mutex<HashMap<(String, String), u32>>,
+    pub thread_positions: Mutex<HashMap<(String, String), u32>>,
+}
+
+#[async_trait]
+impl Store for MockStore {
+    async fn load_accounts_by_host(&self, host: String) -> Result<Vec<Account>> {
+        let accounts = self.accounts.lock().unwrap();
+        Ok(accounts
+            .values()
+            .filter(|account| host == "*" || account.email.ends_with(&format!("@{}", host)))
+            .cloned()
+            .collect())
+    }
+
+    async fn load_account_by_email(&self, email: String) -> Result<Option<Account>> {
+        Ok(self.accounts.lock().unwrap().get(&email).cloned())
+    }
+
+    async fn store_account(&self, account: Account) -> Result<Option<String>> {
+        let value = serde_json::to_string(&account)?;
+        self.accounts
+            .lock()
+            .unwrap()
+            .insert(account.email.clone(), account);
+        Ok(Some(value))
+    }
+
+    async fn destroy_account(&self, email: String) -> Result<()> {
+        self.accounts.lock().unwrap().remove(&email);
+        Ok(())
+    }
+
+    async fn clear_host_accounts(&self, host: String) -> Result<()> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .retain(|email, _| !email.ends_with(&format!("@{}", host)));
+        Ok(())
+    }
+
+    async fn load_last_sequence(&self, email: &str) -> Result<u32> {
+        Ok(*self.sequences.lock().unwrap().get(email).unwrap_or(&0))
+    }
+
+    async fn store_last_sequence(&self, email: &str, last_sequence: u32) -> Result<()> {
+        self.sequences
+            .lock()
+            .unwrap()
+            .insert(email.to_string(), last_sequence);
+        Ok(())
+    }
+
+    async fn load_jmap_state(&self, email: &str) -> Result<Option<String>> {
+        Ok(self.jmap_states.lock().unwrap().get(email).cloned())
+    }
+
+    async fn store_jmap_state(&self, email: &str, state: &str) -> Result<()> {
+        self.jmap_states
+            .lock()
+            .unwrap()
+            .insert(email.to_string(), state.to_string());
+        Ok(())
+    }
+
+    async fn load_jmap_ids(&self, email: &str) -> Result<HashSet<String>> {
+        Ok(self
+            .jmap_ids
+            .lock()
+            .unwrap()
+            .get(email)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn store_jmap_ids(&self, email: &str, ids: &HashSet<String>) -> Result<()> {
+        self.jmap_ids
+            .lock()
+            .unwrap()
+            .insert(email.to_string(), ids.clone());
+        Ok(())
+    }
+
+    async fn load_pop3_uidls(&self, email: &str) -> Result<HashSet<String>> {
+        Ok(self
+            .pop3_uidls
+            .lock()
+            .unwrap()
+            .get(email)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn store_pop3_uidls(&self, email: &str, uidls: &HashSet<String>) -> Result<()> {
+        self.pop3_uidls
+            .lock()
+            .unwrap()
+            .insert(email.to_string(), uidls.clone());
+        Ok(())
+    }
+
+    async fn load_mbox_offset(&self, email: &str) -> Result<u64> {
+        Ok(*self.mbox_offsets.lock().unwrap().get(email).unwrap_or(&0))
+    }
+
+    async fn store_mbox_offset(&self, email: &str, offset: u64) -> Result<()> {
+        self.mbox_offsets
+            .lock()
+            .unwrap()
+            .insert(email.to_string(), offset);
+        Ok(())
+    }
+
+    async fn load_thread_id(&self, email: &str, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .threads
+            .lock()
+            .unwrap()
+            .get(&(email.to_string(), key.to_string()))
+            .cloned())
+    }
+
+    async fn store_thread_id(&self, email: &str, keys: &[String], thread_id: &str) -> Result<()> {
+        let mut threads = self.threads.lock().unwrap();
+        for key in keys {
+            threads.insert((email.to_string(), key.clone()), thread_id.to_string());
+        }
+        Ok(())
+    }
+
+    async fn increment_thread_size(&self, email: &str, thread_id: &str) -> Result<u32> {
+        let mut sizes = self.thread_sizes.lock().unwrap();
+        let size = sizes
+            .entry((email.to_string(), thread_id.to_string()))
+            .or_insert(0);
+        *size += 1;
+        Ok(*size)
+    }
+
+    async fn merge_thread_size(&self, email: &str, from: &str, into: &str) -> Result<()> {
+        let mut sizes = self.thread_sizes.lock().unwrap();
+        let size = sizes
+            .remove(&(email.to_string(), from.to_string()))
+            .unwrap_or(0);
+        *sizes
+            .entry((email.to_string(), into.to_string()))
+            .or_insert(0) += size;
+        Ok(())
+    }
+
+    async fn load_thread_position(&self, email: &str, key: &str) -> Result<Option<u32>> {
+        Ok(self
+            .thread_positions
+            .lock()
+            .unwrap()
+            .get(&(email.to_string(), key.to_string()))
+            .copied())
+    }
+
+    async fn store_thread_position(&self, email: &str, key: &str, position: u32) -> Result<()> {
+        self.thread_positions
+            .lock()
+            .unwrap()
+            .insert((email.to_string(), key.to_string()), position);
+        Ok(())
+    }
+}
+
+/// Queue that keeps the published messages, so the tests can inspect them.
+#[derive(Default)]
+pub struct MockQueue {
+    pub failing_accounts: HashSet<String>, // The messages of these accounts are not published
+    pub messages: Mutex<Vec<QueueMessage>>,
+    pub bounces: Mutex<Vec<BounceEvent>>,
+    pub dead_letters: Mutex<Vec<DeadLetter>>,
+}
+
+#[async_trait]
+impl Queue for MockQueue {
+    async fn publish_message(&self, message: QueueMessage) -> Result<()> {
+        if self
+            .failing_accounts
+            .contains(&message.email_message.account)
+        {
+            return Err(anyhow::Error::msg("Queue is not available"));
+        }
+        self.messages.lock().unwrap().push(message);
+        Ok(())
+    }
+
+    async fn publish_bounce(&self, bounce: BounceEvent) -> Result<()> {
+        self.bounces.lock().unwrap().push(bounce);
+        Ok(())
+    }
+
+    async fn push_dead_letter(&self, letter: DeadLetter) -> Result<()> {
+        self.dead_letters.lock().unwrap().push(letter);
+        Ok(())
+    }
+
+    async fn load_dead_letters(&self, account: &str) -> Result<Vec<DeadLetter>> {
+        Ok(self
+            .dead_letters
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|letter| letter.account == account)
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_dead_letter(&self, letter: &DeadLetter) -> Result<()> {
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        if let Some(index) = dead_letters.iter().position(|kept| kept == letter) {
+            dead_letters.remove(index);
+        }
+        Ok(())
+    }
+}
+
+/// DNS resolver answering from static TXT records.
+#[derive(Default)]
+pub struct MockResolver {
+    pub records: HashMap<String, Vec<String>>,
+}
+
+impl MockResolver {
+    pub fn with_record(mut self, name: &str, record: &str) -> Self {
+        self.records
+            .entry(name.to_string())
+            .or_default()
+            .push(record.to_string());
+        self
+    }
+}
+
+#[async_trait]
+impl DnsResolver for MockResolver {
+    async fn txt_lookup(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self.records.get(name).cloned().unwrap_or_default())
+    }
+}
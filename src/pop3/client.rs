@@ -0,0 +1,235 @@
+use anyhow::Result;
+use md5::{Digest, Md5};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tracing::debug;
+
+/// A minimal POP3 client (RFC 1939) over any async stream.
+pub struct Pop3Client<S> {
+    stream: BufStream<S>,
+    timestamp: Option<String>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Pop3Client<S> {
+    /// Read the server greeting, keeping the APOP timestamp if advertised.
+    pub async fn connect(stream: S) -> Result<Self> {
+        let mut client = Self {
+            stream: BufStream::new(stream),
+            timestamp: None,
+        };
+        let greeting = client.read_response().await?;
+        client.timestamp = match (greeting.find('<'), greeting.rfind('>')) {
+            (Some(start), Some(end)) if start < end => Some(greeting[start..=end].to_string()),
+            _ => None,
+        };
+        Ok(client)
+    }
+
+    /// Issue STLS and give back the underlying stream so it can be wrapped with TLS.
+    pub async fn stls(mut self) -> Result<(S, Option<String>)> {
+        self.command("STLS").await?;
+        Ok((self.stream.into_inner(), self.timestamp))
+    }
+
+    /// Resume a session over an upgraded stream, without waiting for a new greeting.
+    pub fn resume(stream: S, timestamp: Option<String>) -> Self {
+        Self {
+            stream: BufStream::new(stream),
+            timestamp,
+        }
+    }
+
+    /// Authenticate with the USER and PASS commands.
+    pub async fn login(&mut self, user: &str, password: &str) -> Result<()> {
+        self.command(&format!("USER {}", user)).await?;
+        self.command(&format!("PASS {}", password)).await?;
+        Ok(())
+    }
+
+    /// Authenticate with the APOP command, which never sends the password in clear.
+    pub async fn apop(&mut self, user: &str, password: &str) -> Result<()> {
+        let timestamp = self
+            .timestamp
+            .clone()
+            .ok_or_else(|| anyhow::Error::msg("POP3 server does not support APOP"))?;
+        self.command(&format!(
+            "APOP {} {}",
+            user,
+            apop_digest(&timestamp, password)
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// List the message numbers with their unique ids.
+    pub async fn uidl(&mut self) -> Result<Vec<(u32, String)>> {
+        let listing = self.multiline("UIDL").await?;
+        let listing = String::from_utf8_lossy(&listing);
+        let mut uidls = vec![];
+        for line in listing.lines() {
+            let mut parts = line.split_whitespace();
+            if let (Some(number), Some(uidl)) = (parts.next(), parts.next()) {
+                uidls.push((number.parse()?, uidl.to_string()));
+            }
+        }
+        Ok(uidls)
+    }
+
+    /// Retrieve the raw RFC822 message.
+    pub async fn retr(&mut self, number: u32) -> Result<Vec<u8>> {
+        self.multiline(&format!("RETR {}", number)).await
+    }
+
+    /// Mark a message as deleted, it is removed from the server on QUIT.
+    pub async fn dele(&mut self, number: u32) -> Result<()> {
+        self.command(&format!("DELE {}", number)).await?;
+        Ok(())
+    }
+
+    /// End the session, committing the deletions.
+    pub async fn quit(mut self) -> Result<()> {
+        self.command("QUIT").await?;
+        Ok(())
+    }
+
+    async fn command(&mut self, command: &str) -> Result<String> {
+        let name = command.split(' ').next().unwrap_or_default();
+        debug!("-- POP3 {}", name);
+        self.stream.write_all(command.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        self.read_response().await
+    }
+
+    async fn multiline(&mut self, command: &str) -> Result<Vec<u8>> {
+        self.command(command).await?;
+        let mut data = vec![];
+        loop {
+            let mut line = vec![];
+            if self.stream.read_until(b'\n', &mut line).await? == 0 {
+                return Err(anyhow::Error::msg("POP3 connection closed"));
+            }
+            if line == b".\r\n" || line == b".\n" {
+                break;
+            }
+            // Lines starting with the termination octet are byte-stuffed
+            if line.starts_with(b"..") {
+                line.remove(0);
+            }
+            data.extend_from_slice(&line);
+        }
+        Ok(data)
+    }
+
+    async fn read_response(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow::Error::msg("POP3 connection closed"));
+        }
+        let line = line.trim_end().to_string();
+        match line.strip_prefix("+OK") {
+            Some(response) => Ok(response.trim().to_string()),
+            None => Err(anyhow::Error::msg(format!("POP3 error: {}", line))),
+        }
+    }
+}
+
+fn apop_digest(timestamp: &str, password: &str) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(timestamp.as_bytes());
+    hasher.update(password.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    /// Play a scripted POP3 server, checking the commands received.
+    async fn serve(mut server: DuplexStream, script: Vec<(&'static str, &'static str)>) {
+        let mut reader = tokio::io::BufReader::new(&mut server);
+        for (expected, response) in script {
+            if !expected.is_empty() {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                assert_eq!(line.trim_end(), expected);
+            }
+            reader
+                .get_mut()
+                .write_all(response.as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_apop_digest() {
+        // Example from RFC 1939
+        assert_eq!(
+            apop_digest("<1896.697170952@dbc.mtview.ca.us>", "tanstaaf"),
+            "c4c9334bac560ecc979e58001b3e22fb"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apop_uidl_retr_dele_quit() {
+        let (client_stream, server_stream) = duplex(4096);
+        let server = tokio::spawn(serve(
+            server_stream,
+            vec![
+                (
+                    "",
+                    "+OK POP3 server ready <1896.697170952@dbc.mtview.ca.us>\r\n",
+                ),
+                (
+                    "APOP mrose c4c9334bac560ecc979e58001b3e22fb",
+                    "+OK maildrop has 2 messages\r\n",
+                ),
+                (
+                    "UIDL",
+                    "+OK\r\n1 whqtswO00WBw418f9t5JxYwZ\r\n2 QhdPYR:00WBw1Ph7x7\r\n.\r\n",
+                ),
+                (
+                    "RETR 1",
+                    "+OK\r\nSubject: Hi\r\n\r\n..dotted\r\nbody\r\n.\r\n",
+                ),
+                ("DELE 1", "+OK message 1 deleted\r\n"),
+                ("QUIT", "+OK bye\r\n"),
+            ],
+        ));
+
+        let mut client = Pop3Client::connect(client_stream).await.unwrap();
+        client.apop("mrose", "tanstaaf").await.unwrap();
+        let uidls = client.uidl().await.unwrap();
+        assert_eq!(
+            uidls,
+            vec![
+                (1, "whqtswO00WBw418f9t5JxYwZ".to_string()),
+                (2, "QhdPYR:00WBw1Ph7x7".to_string())
+            ]
+        );
+        let message = client.retr(1).await.unwrap();
+        assert_eq!(message, b"Subject: Hi\r\n\r\n.dotted\r\nbody\r\n");
+        client.dele(1).await.unwrap();
+        client.quit().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_error() {
+        let (client_stream, server_stream) = duplex(4096);
+        let server = tokio::spawn(serve(
+            server_stream,
+            vec![
+                ("", "+OK ready\r\n"),
+                ("USER test@test.com", "+OK\r\n"),
+                ("PASS wrong", "-ERR invalid password\r\n"),
+            ],
+        ));
+
+        let mut client = Pop3Client::connect(client_stream).await.unwrap();
+        assert!(client.apop("test@test.com", "wrong").await.is_err());
+        assert!(client.login("test@test.com", "wrong").await.is_err());
+        server.await.unwrap();
+    }
+}
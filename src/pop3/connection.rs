@@ -0,0 +1,135 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use async_native_tls::TlsStream;
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::{
+    source::{MailSource, RawMessage},
+    store::{self, Account, Pop3Security, Pop3Settings},
+};
+
+use super::client::Pop3Client;
+
+async fn get_session(
+    account: &Account,
+    settings: &Pop3Settings,
+) -> Result<Pop3Client<TlsStream<TcpStream>>> {
+    let tcp_stream = TcpStream::connect((settings.host.clone(), settings.port)).await?;
+    let tls = async_native_tls::TlsConnector::new();
+    let mut session = match settings.security {
+        Pop3Security::Tls => {
+            let tls_stream = tls.connect(settings.host.clone(), tcp_stream).await?;
+            Pop3Client::connect(tls_stream).await?
+        }
+        Pop3Security::StartTls => {
+            let (tcp_stream, timestamp) = Pop3Client::connect(tcp_stream).await?.stls().await?;
+            let tls_stream = tls.connect(settings.host.clone(), tcp_stream).await?;
+            Pop3Client::resume(tls_stream, timestamp)
+        }
+    };
+    debug!("-- connected to {}:{}", settings.host, settings.port);
+
+    if settings.apop {
+        session.apop(&account.email, &account.password).await?;
+    } else {
+        session.login(&account.email, &account.password).await?;
+    }
+    debug!("-- logged in a {}", account.email);
+
+    Ok(session)
+}
+
+pub struct Pop3Source {
+    account: Account,
+    settings: Pop3Settings,
+    store: Arc<dyn store::Store>,
+    session: Option<Pop3Client<TlsStream<TcpStream>>>,
+    uidls: Option<HashSet<String>>,
+    retrieved: Vec<u32>,
+}
+
+impl Pop3Source {
+    pub fn new(account: Account, settings: Pop3Settings, store: Arc<dyn store::Store>) -> Self {
+        Self {
+            account,
+            settings,
+            store,
+            session: None,
+            uidls: None,
+            retrieved: vec![],
+        }
+    }
+
+    fn session(&mut self) -> Result<&mut Pop3Client<TlsStream<TcpStream>>> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| anyhow::Error::msg("POP3 session is not connected"))
+    }
+}
+
+#[async_trait]
+impl MailSource for Pop3Source {
+    async fn connect(&mut self) -> Result<()> {
+        self.session = Some(get_session(&self.account, &self.settings).await?);
+        Ok(())
+    }
+
+    async fn fetch_new(&mut self) -> Result<Vec<RawMessage>> {
+        let email = self.account.email.clone();
+        let seen_uidls = self.store.load_pop3_uidls(&email).await?;
+        let server_uidls = self.session()?.uidl().await?;
+        debug!(
+            "Fetching emails for '{}' with {} messages on the server",
+            email,
+            server_uidls.len()
+        );
+
+        // Forget the uidls that are gone from the server so the set does not grow forever
+        let mut uidls: HashSet<String> = HashSet::new();
+        let mut raw_messages = vec![];
+        self.retrieved.clear();
+        for (number, uidl) in server_uidls {
+            if !seen_uidls.contains(&uidl) {
+                raw_messages.push(RawMessage::Rfc822 {
+                    id: uidl.clone(),
+                    // POP3 message numbers are only valid within a session, there is no UID to report
+                    seq_id: 0,
+                    data: self.session()?.retr(number).await?,
+                });
+                self.retrieved.push(number);
+                if self.settings.delete_after_retrieval {
+                    continue;
+                }
+            }
+            uidls.insert(uidl);
+        }
+        self.uidls = Some(uidls);
+        Ok(raw_messages)
+    }
+
+    async fn checkpoint(&mut self) -> Result<()> {
+        if self.settings.delete_after_retrieval {
+            for number in std::mem::take(&mut self.retrieved) {
+                self.session()?.dele(number).await?;
+            }
+        }
+        if let Some(uidls) = self.uidls.take() {
+            self.store
+                .store_pop3_uidls(&self.account.email, &uidls)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_changes(&mut self) -> Result<()> {
+        // POP3 has no push, log out so deletions are committed and the maildrop is unlocked
+        if let Some(session) = self.session.take() {
+            debug!("-- logging out");
+            session.quit().await?;
+        }
+        Ok(())
+    }
+}
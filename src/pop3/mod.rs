@@ -0,0 +1,5 @@
+mod client;
+mod connection;
+
+pub use client::*;
+pub use connection::*;
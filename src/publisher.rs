@@ -0,0 +1,596 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{SecondsFormat, Utc};
+use tracing::{debug, error};
+
+use crate::{
+    archive::Archive,
+    blob::BlobStore,
+    crypto::{self, Keystore},
+    dkim::{self, DnsResolver},
+    imap::{self, EmailMessage, ParseError},
+    queue::{self, BlobRef, BounceEvent, DeadLetter, QueueMessage},
+    source::MailSource,
+    store::{Account, Store},
+    threads,
+};
+
+/// Publishes parsed messages to the queue, after the per-account processing.
+pub struct Publisher {
+    store: Arc<dyn Store>,
+    queue: Arc<dyn queue::Queue>,
+    blob_store: Option<Arc<dyn BlobStore>>,
+    resolver: Option<Arc<dyn DnsResolver>>,
+    keystore: Option<Keystore>,
+    archive: Option<Arc<Archive>>,
+}
+
+impl Publisher {
+    pub fn new(
+        store: Arc<dyn Store>,
+        queue: Arc<dyn queue::Queue>,
+        blob_store: Option<Arc<dyn BlobStore>>,
+        resolver: Option<Arc<dyn DnsResolver>>,
+        keystore: Option<Keystore>,
+        archive: Option<Arc<Archive>>,
+    ) -> Self {
+        Self {
+            store,
+            queue,
+            blob_store,
+            resolver,
+            keystore,
+            archive,
+        }
+    }
+
+    /// Archive the raw RFC822 source of a message, if the archive is enabled.
+    ///
+    /// Messages are archived before they are parsed, so the ones that fail to parse
+    /// can be inspected, and a failure to archive does not stop the processing.
+    pub async fn archive(&self, account: &Account, id: &str, raw: &[u8]) {
+        if let Some(archive) = &self.archive {
+            if let Err(e) = archive.store(account, id, raw).await {
+                error!("Unable to archive message {}: {:?}", id, e);
+            }
+        }
+    }
+
+    /// Keep a message that could not be parsed as a dead letter, so it can be replayed.
+    ///
+    /// A message without raw source (e.g. missing from the IMAP response) is kept with
+    /// its mailbox and id only, to be fetched again.
+    pub async fn dead_letter(
+        &self,
+        account: &Account,
+        id: &str,
+        seq_id: u32,
+        error: ParseError,
+        raw: Option<&[u8]>,
+    ) -> Result<()> {
+        self.queue
+            .push_dead_letter(DeadLetter {
+                account: account.email.clone(),
+                mailbox: account.mailbox.clone(),
+                id: id.to_string(),
+                seq_id,
+                error,
+                failed_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                raw: raw.map(|raw| STANDARD.encode(raw)),
+            })
+            .await
+    }
+
+    /// Parse and publish the dead letters of an account again, e.g. once the parser
+    /// is fixed, returning how many were published.
+    ///
+    /// Every letter is removed from the queue only once it was replayed, so none is
+    /// lost when the processing stops in the middle (it may be published twice). The
+    /// letters that still fail to parse are kept, with their new error. The letters
+    /// without raw source are fetched again from the source, when still in the
+    /// mailbox of the account.
+    pub async fn replay_dead_letters(
+        &self,
+        account: &Account,
+        source: &mut dyn MailSource,
+    ) -> Result<usize> {
+        let mut replayed = 0;
+        for letter in self.queue.load_dead_letters(&account.email).await? {
+            let raw = match letter.raw.as_deref() {
+                Some(raw) => STANDARD.decode(raw)?,
+                None if letter.mailbox == account.mailbox => {
+                    match source.refetch(&letter.id).await? {
+                        Some(raw) => {
+                            self.archive(account, &letter.id, &raw).await;
+                            raw
+                        }
+                        None => {
+                            debug!("-- dead letter {} could not be fetched again", letter.id);
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    debug!("-- dead letter {} is from another mailbox", letter.id);
+                    continue;
+                }
+            };
+            if self.replay(account, &letter, &raw).await? {
+                replayed += 1;
+            }
+            self.queue.remove_dead_letter(&letter).await?;
+        }
+        Ok(replayed)
+    }
+
+    /// Replay a dead letter, returning whether it was published.
+    async fn replay(&self, account: &Account, letter: &DeadLetter, raw: &[u8]) -> Result<bool> {
+        let format = account.policy.body_format;
+        match imap::parse_rfc822(&account.email, letter.seq_id, raw, format) {
+            Ok(message) => {
+                self.publish(account, message, raw).await?;
+                Ok(true)
+            }
+            Err(error) => {
+                debug!("-- dead letter {} failed again: {}", letter.id, error);
+                self.queue
+                    .push_dead_letter(DeadLetter {
+                        error,
+                        failed_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                        raw: Some(STANDARD.encode(raw)),
+                        ..letter.clone()
+                    })
+                    .await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Publish a message, given the raw RFC822 source it was parsed from.
+    pub async fn publish(
+        &self,
+        account: &Account,
+        mut email_message: EmailMessage,
+        raw: &[u8],
+    ) -> Result<()> {
+        // IMAP skips these messages before downloading them, this covers the other
+        // sources and the replayed messages
+        let size = email_message.size.map(|size| size as usize);
+        if !account.policy.allows_message(&email_message.flags, size) {
+            debug!(
+                "-- message {} skipped by the policy of account {}",
+                email_message.seq_id, account.email
+            );
+            return Ok(());
+        }
+        // Bounces are reported automatically, so they are not dropped as such
+        if let Some(bounce) = email_message.bounce {
+            debug!(
+                "-- message {} is a bounce for {} recipients",
+                email_message.seq_id,
+                bounce.recipients.len()
+            );
+            return self
+                .queue
+                .publish_bounce(BounceEvent {
+                    account: email_message.account,
+                    seq_id: email_message.seq_id,
+                    date: email_message.date,
+                    bounce,
+                })
+                .await;
+        }
+        if account.policy.drop_classes.contains(&email_message.class) {
+            debug!(
+                "-- message {} dropped as {:?} for account {}",
+                email_message.seq_id, email_message.class, account.email
+            );
+            return Ok(());
+        }
+        let thread =
+            threads::assign_thread(self.store.as_ref(), &account.email, &email_message, raw)
+                .await?;
+        email_message.thread_id = Some(thread.id);
+        email_message.thread_position = Some(thread.position);
+        email_message
+            .authentication
+            .trust(&account.policy.trusted_authserv_ids);
+        if account.policy.verify_dkim {
+            if let Some(resolver) = &self.resolver {
+                let authentication = &mut email_message.authentication;
+                authentication.dkim_verified = dkim::verify(resolver.as_ref(), raw).await;
+                authentication.update_verdict();
+            }
+        }
+        // The content of encrypted messages is extracted once decrypted
+        let unwrapped = match email_message.security {
+            Some(_) => {
+                crypto::unwrap_message(self.keystore.as_ref(), account, &mut email_message, raw)
+                    .await
+            }
+            None => None,
+        };
+        let raw = unwrapped.as_deref().unwrap_or(raw);
+        if account.policy.html_body {
+            email_message.html_body = imap::parse_html_body(raw, &email_message.attachments);
+        }
+        let blobs = match &self.blob_store {
+            Some(blob_store) => store_attachments(blob_store.as_ref(), account, raw).await,
+            None => vec![],
+        };
+        self.queue
+            .publish_message(QueueMessage {
+                email_message,
+                blobs,
+            })
+            .await
+    }
+}
+
+/// Write the attachments allowed by the account policy to the blob store.
+///
+/// An attachment that cannot be stored is logged and left out of the blobs, the
+/// message is still published.
+async fn store_attachments(
+    blob_store: &dyn BlobStore,
+    account: &Account,
+    raw: &[u8],
+) -> Vec<BlobRef> {
+    let mut blobs = vec![];
+    for (attachment, content) in imap::extract_attachments(raw) {
+        if !account
+            .policy
+            .attachments
+            .allows(&attachment.mime_type, attachment.size)
+        {
+            debug!(
+                "Attachment {} not allowed for account {}",
+                attachment, account.email
+            );
+            continue;
+        }
+        // Keys are content-addressed, so the same attachment is only stored once
+        let key = format!("attachments/{}/{}", account.email, attachment.sha256);
+        match blob_store
+            .put_blob(&key, &attachment.mime_type, &content)
+            .await
+        {
+            Ok(()) => blobs.push(BlobRef {
+                key,
+                sha256: attachment.sha256,
+            }),
+            Err(e) => error!("Unable to store attachment {}: {:?}", key, e),
+        }
+    }
+    blobs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::blob::FileBlobStore;
+    use crate::imap::{BodyFormat, MessageClass, Verdict};
+    use crate::mocks::{MockQueue, MockResolver, MockStore};
+    use crate::store::{AccountPolicy, AttachmentPolicy, SourceSettings};
+
+    const MESSAGE: &[u8] = b"Subject: Invoice\r
+Content-Type: multipart/mixed; boundary=\"mixed\"\r
+\r
+--mixed\r
+Content-Type: text/plain\r
+\r
+See attached\r
+--mixed\r
+Content-Type: application/pdf; name=\"invoice.pdf\"\r
+Content-Transfer-Encoding: base64\r
+\r
+JVBERi0=\r
+--mixed\r
+Content-Type: application/zip; name=\"archive.zip\"\r
+\r
+PK\r
+--mixed--\r
+";
+
+    #[tokio::test]
+    async fn test_publish_with_attachments() {
+        let root = tempfile::tempdir().unwrap();
+        let blob_store = Arc::new(FileBlobStore::new(
+            root.path().to_string_lossy().to_string(),
+        ));
+        let queue = Arc::new(MockQueue::default());
+        let publisher = Publisher::new(
+            Arc::new(MockStore::default()),
+            queue.clone(),
+            Some(blob_store.clone()),
+            None,
+            None,
+            None,
+        );
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Smtp,
+            policy: AccountPolicy {
+                attachments: AttachmentPolicy {
+                    max_size: None,
+                    allowed_content_types: vec!["application/pdf".to_string()],
+                },
+                ..Default::default()
+            },
+        };
+
+        let message = imap::parse_rfc822(&account.email, 1, MESSAGE, BodyFormat::Plain).unwrap();
+        publisher.publish(&account, message, MESSAGE).await.unwrap();
+
+        let message = queue.messages.lock().unwrap()[0].clone();
+        assert_eq!(message.email_message.attachments.len(), 2);
+        assert_eq!(message.blobs.len(), 1);
+        let blob = &message.blobs[0];
+        assert_eq!(blob.sha256, message.email_message.attachments[0].sha256);
+        assert_eq!(
+            blob.key,
+            format!("attachments/test@test.com/{}", blob.sha256)
+        );
+        assert_eq!(
+            blob_store.get_blob(&blob.key).await.unwrap(),
+            Some(b"%PDF-".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_skipped_by_policy() {
+        let queue = Arc::new(MockQueue::default());
+        let publisher = Publisher::new(
+            Arc::new(MockStore::default()),
+            queue.clone(),
+            None,
+            None,
+            None,
+            None,
+        );
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Smtp,
+            policy: AccountPolicy {
+                skip_seen: true,
+                max_message_size: Some(MESSAGE.len() - 1),
+                drop_classes: vec![MessageClass::AutoReply],
+                ..Default::default()
+            },
+        };
+
+        let message = imap::parse_rfc822(&account.email, 1, MESSAGE, BodyFormat::Plain).unwrap();
+        publisher.publish(&account, message, MESSAGE).await.unwrap();
+        let mut message = imap::parse_rfc822(
+            &account.email,
+            2,
+            b"Subject: Hi\r\n\r\nHi",
+            BodyFormat::Plain,
+        )
+        .unwrap();
+        message.flags = vec!["\\Seen".to_string()];
+        publisher.publish(&account, message, MESSAGE).await.unwrap();
+        let message = imap::parse_rfc822(
+            &account.email,
+            3,
+            b"Subject: Hi\r\n\r\nHi",
+            BodyFormat::Plain,
+        )
+        .unwrap();
+        publisher.publish(&account, message, MESSAGE).await.unwrap();
+        let auto_reply = b"Auto-Submitted: auto-replied\r\nSubject: Away\r\n\r\nAway";
+        let message = imap::parse_rfc822(&account.email, 4, auto_reply, BodyFormat::Plain).unwrap();
+        publisher
+            .publish(&account, message, auto_reply)
+            .await
+            .unwrap();
+
+        let messages = queue.messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].email_message.seq_id, 3);
+    }
+
+    #[tokio::test]
+    async fn test_publish_verifies_dkim() {
+        let queue = Arc::new(MockQueue::default());
+        let publisher = Publisher::new(
+            Arc::new(MockStore::default()),
+            queue.clone(),
+            None,
+            Some(Arc::new(MockResolver::default())),
+            None,
+            None,
+        );
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Smtp,
+            policy: AccountPolicy {
+                verify_dkim: true,
+                trusted_authserv_ids: vec!["mx.test.com".to_string()],
+                ..Default::default()
+            },
+        };
+        // The server of the sender reported a pass, but the body was changed later
+        let raw = b"Authentication-Results: mx.test.com; dkim=pass header.d=test.com\r
+DKIM-Signature: v=1; a=rsa-sha256; d=test.com; s=rsa; h=from; bh=AAAA; b=AAAA\r
+From: ana@test.com\r
+\r
+Changed";
+
+        let mut message = imap::parse_rfc822(&account.email, 1, raw, BodyFormat::Plain).unwrap();
+        message
+            .authentication
+            .trust(&account.policy.trusted_authserv_ids);
+        assert_eq!(message.authentication.verdict, Verdict::Pass);
+        publisher.publish(&account, message, raw).await.unwrap();
+
+        let message = queue.messages.lock().unwrap()[0].clone();
+        let authentication = message.email_message.authentication;
+        assert_eq!(authentication.dkim_verified.len(), 1);
+        assert_eq!(authentication.dkim_verified[0].result, "fail");
+        assert_eq!(authentication.verdict, Verdict::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_publish_bounce() {
+        let queue = Arc::new(MockQueue::default());
+        let publisher = Publisher::new(
+            Arc::new(MockStore::default()),
+            queue.clone(),
+            None,
+            None,
+            None,
+            None,
+        );
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Smtp,
+            policy: AccountPolicy {
+                drop_classes: vec![MessageClass::AutoReply],
+                ..Default::default()
+            },
+        };
+        let raw = b"From: MAILER-DAEMON@mx.test.com\r
+Auto-Submitted: auto-replied\r
+Date: Tue, 1 Aug 2023 10:00:00 +0000\r
+X-Failed-Recipients: missing@other.com\r
+\r
+550 5.1.1 User unknown\r
+";
+
+        let message = imap::parse_rfc822(&account.email, 1, raw, BodyFormat::Plain).unwrap();
+        publisher.publish(&account, message, raw).await.unwrap();
+
+        assert!(queue.messages.lock().unwrap().is_empty());
+        let bounces = queue.bounces.lock().unwrap();
+        assert_eq!(bounces.len(), 1);
+        assert_eq!(bounces[0].account, "test@test.com");
+        assert_eq!(bounces[0].date, Some("2023-08-01T10:00:00Z".to_string()));
+        let recipient = &bounces[0].bounce.recipients[0];
+        assert_eq!(recipient.recipient, "missing@other.com");
+        assert_eq!(recipient.status, Some("5.1.1".to_string()));
+        assert!(recipient.permanent);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_replay() {
+        let queue = Arc::new(MockQueue::default());
+        let publisher = Publisher::new(
+            Arc::new(MockStore::default()),
+            queue.clone(),
+            None,
+            None,
+            None,
+            None,
+        );
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Imap,
+            policy: AccountPolicy::default(),
+        };
+        let broken = b" Subject: Overhanging\r\n\r\nHello";
+        let error = imap::parse_rfc822(&account.email, 1, broken, BodyFormat::Plain).unwrap_err();
+        assert!(matches!(error, ParseError::Decode(_)));
+        publisher
+            .dead_letter(&account, "1", 1, error, Some(broken))
+            .await
+            .unwrap();
+        publisher
+            .dead_letter(&account, "2", 2, ParseError::MissingEnvelope, Some(MESSAGE))
+            .await
+            .unwrap();
+        publisher
+            .dead_letter(&account, "3", 3, ParseError::MissingBody, None)
+            .await
+            .unwrap();
+        publisher
+            .dead_letter(&account, "4", 4, ParseError::MissingBody, None)
+            .await
+            .unwrap();
+
+        // The message without envelope is published from its headers, the message
+        // without body is fetched again and the one still missing is kept
+        let mut source = TestSource {
+            messages: HashMap::from([("3".to_string(), MESSAGE.to_vec())]),
+        };
+        assert_eq!(
+            publisher
+                .replay_dead_letters(&account, &mut source)
+                .await
+                .unwrap(),
+            2
+        );
+        let messages = queue.messages.lock().unwrap();
+        let seq_ids: Vec<u32> = messages
+            .iter()
+            .map(|message| message.email_message.seq_id)
+            .collect();
+        assert_eq!(seq_ids, vec![2, 3]);
+        let mut dead_letters = queue.dead_letters.lock().unwrap().clone();
+        dead_letters.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(dead_letters.len(), 2);
+        assert_eq!(dead_letters[0].id, "1");
+        let raw = dead_letters[0].raw.as_ref().unwrap();
+        assert_eq!(STANDARD.decode(raw).unwrap(), broken);
+        assert!(matches!(dead_letters[0].error, ParseError::Decode(_)));
+        assert_eq!(dead_letters[1].id, "4");
+        assert!(dead_letters[1].raw.is_none());
+    }
+
+    /// Source that can only fetch its messages again.
+    struct TestSource {
+        messages: HashMap<String, Vec<u8>>,
+    }
+
+    #[async_trait::async_trait]
+    impl MailSource for TestSource {
+        async fn connect(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn fetch_new(&mut self) -> Result<Vec<crate::source::RawMessage>> {
+            Ok(vec![])
+        }
+
+        async fn checkpoint(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn wait_for_changes(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn refetch(&mut self, id: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.messages.get(id).cloned())
+        }
+    }
+}
@@ -5,11 +5,44 @@ use serde_derive::{Deserialize, Serialize};
 use std::fmt;
 use std::sync::Arc;
 
-use crate::imap::EmailMessage;
+use crate::imap::{Bounce, EmailMessage, ParseError};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct QueueMessage {
     pub email_message: EmailMessage,
+    pub blobs: Vec<BlobRef>, // Attachments available in the blob store
+}
+
+/// A reference to an attachment written to the blob store.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BlobRef {
+    pub key: String,
+    pub sha256: String, // Matches the sha256 of the attachment metadata
+}
+
+/// A bounce received by an account, published apart from the messages.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct BounceEvent {
+    pub account: String,
+    pub seq_id: u32,
+    pub date: Option<String>, // RFC 3339, in UTC
+    #[serde(flatten)]
+    pub bounce: Bounce,
+}
+
+/// A message that could not be parsed, kept with its raw source so it can be replayed.
+///
+/// When the source is missing (e.g. an IMAP response without body), the message is
+/// fetched again from its mailbox by id when replayed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DeadLetter {
+    pub account: String,
+    pub mailbox: String,
+    pub id: String, // The id of the message in its source, e.g. the IMAP UIDVALIDITY and UID
+    pub seq_id: u32,
+    pub error: ParseError,
+    pub failed_at: String,   // RFC 3339, in UTC
+    pub raw: Option<String>, // Base64 of the raw RFC822 source, if it was fetched
 }
 
 impl fmt::Display for QueueMessage {
@@ -22,6 +55,18 @@ impl fmt::Display for QueueMessage {
 pub trait Queue: Send + Sync {
     /// Publish a message to the queue.
     async fn publish_message(&self, message: QueueMessage) -> Result<()>;
+
+    /// Publish a bounce event to the queue.
+    async fn publish_bounce(&self, bounce: BounceEvent) -> Result<()>;
+
+    /// Keep a message that could not be parsed, until it is taken to be replayed.
+    async fn push_dead_letter(&self, letter: DeadLetter) -> Result<()>;
+
+    /// Get the dead letters of an account, which are kept until removed.
+    async fn load_dead_letters(&self, account: &str) -> Result<Vec<DeadLetter>>;
+
+    /// Remove a dead letter, once it was replayed.
+    async fn remove_dead_letter(&self, letter: &DeadLetter) -> Result<()>;
 }
 
 #[derive(Clone, Debug)]
@@ -48,4 +93,46 @@ impl Queue for RedisQueue {
 
         Ok(())
     }
+
+    async fn publish_bounce(&self, bounce: BounceEvent) -> Result<()> {
+        let bounce_str = serde_json::to_string(&bounce)?;
+
+        let mut con = self.redis_client.get_async_connection().await?;
+        let _: () = con.publish("bounces", bounce_str).await?;
+
+        Ok(())
+    }
+
+    async fn push_dead_letter(&self, letter: DeadLetter) -> Result<()> {
+        let key = format!("dead_letters:{}", letter.account);
+        let letter_str = serde_json::to_string(&letter)?;
+
+        let mut con = self.redis_client.get_async_connection().await?;
+        let _: () = con.rpush(key, letter_str).await?;
+
+        Ok(())
+    }
+
+    async fn load_dead_letters(&self, account: &str) -> Result<Vec<DeadLetter>> {
+        let key = format!("dead_letters:{}", account);
+
+        let mut con = self.redis_client.get_async_connection().await?;
+        let letter_strs: Vec<String> = con.lrange(&key, 0, -1).await?;
+        let mut letters = vec![];
+        for letter_str in letter_strs {
+            letters.push(serde_json::from_str(&letter_str)?);
+        }
+        Ok(letters)
+    }
+
+    async fn remove_dead_letter(&self, letter: &DeadLetter) -> Result<()> {
+        let key = format!("dead_letters:{}", letter.account);
+        // Serialized as when it was pushed, so it is found by value
+        let letter_str = serde_json::to_string(letter)?;
+
+        let mut con = self.redis_client.get_async_connection().await?;
+        let _: () = con.lrem(key, 1, letter_str).await?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,5 @@
+mod server;
+mod session;
+
+pub use server::*;
+pub use session::*;
@@ -0,0 +1,96 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_native_tls::{Identity, TlsAcceptor};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use tracing::{debug, error, info};
+
+use crate::{config::InboundConfig, publisher::Publisher, store};
+
+use super::session::{Outcome, SmtpSession};
+
+async fn get_acceptor(config: &InboundConfig) -> Result<Option<TlsAcceptor>> {
+    match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = tokio::fs::read(cert_path).await?;
+            let key = tokio::fs::read(key_path).await?;
+            let identity = Identity::from_pkcs8(&cert, &key)?;
+            let acceptor = native_tls::TlsAcceptor::new(identity)?;
+            Ok(Some(acceptor.into()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Accept inbound SMTP/LMTP connections forever, up to `max_connections` at once.
+pub async fn serve(
+    config: InboundConfig,
+    store: Arc<dyn store::Store>,
+    publisher: Arc<Publisher>,
+) -> Result<()> {
+    let acceptor = get_acceptor(&config).await?.map(Arc::new);
+    let listener = TcpListener::bind(&config.listen_addr).await?;
+    info!(
+        "Listening for {:?} on {} (STARTTLS {})",
+        config.protocol,
+        config.listen_addr,
+        if acceptor.is_some() { "on" } else { "off" }
+    );
+
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    loop {
+        let (mut tcp_stream, peer) = listener.accept().await?;
+        debug!("-- SMTP connection from {}", peer);
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("-- SMTP connection from {} turned away", peer);
+                let line = format!(
+                    "421 4.3.2 {} Too many connections, try again later\r\n",
+                    config.hostname
+                );
+                let _ = tcp_stream.write_all(line.as_bytes()).await;
+                continue;
+            }
+        };
+        let session = SmtpSession::new(
+            config.clone(),
+            store.clone(),
+            publisher.clone(),
+            acceptor.is_some(),
+        );
+        let acceptor = acceptor.clone();
+        let timeout = Duration::from_secs(config.timeout_seconds);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(session, tcp_stream, acceptor, timeout).await {
+                error!("Error in SMTP session with {}: {:?}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn handle_connection(
+    mut session: SmtpSession,
+    tcp_stream: TcpStream,
+    acceptor: Option<Arc<TlsAcceptor>>,
+    timeout: Duration,
+) -> Result<()> {
+    match session.run(tcp_stream, true).await? {
+        Outcome::Closed => Ok(()),
+        Outcome::StartTls(tcp_stream) => {
+            let acceptor =
+                acceptor.ok_or_else(|| anyhow::Error::msg("STARTTLS is not configured"))?;
+            let tls_stream = tokio::time::timeout(timeout, acceptor.accept(tcp_stream))
+                .await
+                .map_err(|_| anyhow::Error::msg("TLS handshake timed out"))??;
+            debug!("-- SMTP session upgraded to TLS");
+            session.run(tls_stream, false).await?;
+            Ok(())
+        }
+    }
+}
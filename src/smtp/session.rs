@@ -0,0 +1,699 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tracing::{debug, error};
+
+use crate::{
+    config::{InboundConfig, InboundProtocol},
+    imap::{self, ParseError},
+    publisher::Publisher,
+    store::{self, Account, SourceSettings},
+};
+
+const MAX_LINE_LENGTH: usize = 4096;
+// RFC 5321 requires accepting at least 100 recipients per transaction
+const MAX_RECIPIENTS: usize = 100;
+
+/// How a session ended.
+pub enum Outcome<S> {
+    Closed,
+    /// The client asked for STARTTLS, the stream has to be upgraded and the session resumed
+    StartTls(S),
+}
+
+/// What to do with the stream once the conversation ends.
+enum Next {
+    Close,
+    StartTls,
+}
+
+/// The client did not send a line within the timeout.
+#[derive(Debug)]
+struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SMTP client timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+pub struct SmtpSession {
+    config: InboundConfig,
+    store: Arc<dyn store::Store>,
+    publisher: Arc<Publisher>,
+    tls_available: bool,
+    greeted: bool,
+    mail_from: Option<String>,
+    recipients: Vec<Account>,
+}
+
+impl SmtpSession {
+    pub fn new(
+        config: InboundConfig,
+        store: Arc<dyn store::Store>,
+        publisher: Arc<Publisher>,
+        tls_available: bool,
+    ) -> Self {
+        Self {
+            config,
+            store,
+            publisher,
+            tls_available,
+            greeted: false,
+            mail_from: None,
+            recipients: vec![],
+        }
+    }
+
+    /// Serve the session, sending the greeting unless it is resumed after STARTTLS.
+    ///
+    /// The session is closed with a 421 reply once the client does not send a line
+    /// within the timeout, so idle or slow clients do not hold a connection forever.
+    pub async fn run<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: S,
+        greet: bool,
+    ) -> Result<Outcome<S>> {
+        let mut stream = BufStream::new(stream);
+        match self.converse(&mut stream, greet).await {
+            Ok(Next::Close) => Ok(Outcome::Closed),
+            Ok(Next::StartTls) => Ok(Outcome::StartTls(stream.into_inner())),
+            Err(e) if e.is::<TimedOut>() => {
+                debug!("-- SMTP session timed out");
+                let line = format!(
+                    "421 4.4.2 {} Timeout, closing connection",
+                    self.config.hostname
+                );
+                reply(&mut stream, &line).await?;
+                Ok(Outcome::Closed)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn converse<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut BufStream<S>,
+        greet: bool,
+    ) -> Result<Next> {
+        if greet {
+            let service = match self.config.protocol {
+                InboundProtocol::Smtp => "ESMTP",
+                InboundProtocol::Lmtp => "LMTP",
+            };
+            let greeting = format!("220 {} {} pregonero", self.config.hostname, service);
+            reply(stream, &greeting).await?;
+        }
+
+        loop {
+            let mut line = vec![];
+            if read_line(stream, &mut line, self.timeout()).await? == 0 {
+                return Ok(Next::Close);
+            }
+            if line.len() > MAX_LINE_LENGTH {
+                // Discard the rest of the line, without buffering it
+                while !line.ends_with(b"\n") {
+                    line.clear();
+                    if read_line(stream, &mut line, self.timeout()).await? == 0 {
+                        return Ok(Next::Close);
+                    }
+                }
+                reply(stream, "500 5.5.2 Line too long").await?;
+                continue;
+            }
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            let (verb, argument) = match line.split_once(char::is_whitespace) {
+                Some((verb, argument)) => (verb, argument.trim()),
+                None => (line.as_str(), ""),
+            };
+            // Only the verb is uppercased, the argument is kept as sent
+            let verb = verb.to_uppercase();
+            debug!("-- SMTP {}", verb);
+
+            match verb.as_str() {
+                "EHLO" | "HELO" if self.config.protocol == InboundProtocol::Lmtp => {
+                    reply(stream, "500 5.5.1 Use LHLO").await?;
+                }
+                "LHLO" if self.config.protocol == InboundProtocol::Smtp => {
+                    reply(stream, "500 5.5.1 Use EHLO").await?;
+                }
+                "EHLO" | "LHLO" => {
+                    self.reset();
+                    self.greeted = true;
+                    let mut lines = vec![
+                        self.config.hostname.clone(),
+                        format!("SIZE {}", self.config.max_message_size),
+                        "8BITMIME".to_string(),
+                        "ENHANCEDSTATUSCODES".to_string(),
+                    ];
+                    if self.tls_available {
+                        lines.push("STARTTLS".to_string());
+                    }
+                    reply_multiline(stream, 250, &lines).await?;
+                }
+                "HELO" => {
+                    self.reset();
+                    self.greeted = true;
+                    reply(stream, &format!("250 {}", self.config.hostname)).await?;
+                }
+                "STARTTLS" if self.tls_available => {
+                    reply(stream, "220 2.0.0 Ready to start TLS").await?;
+                    self.reset();
+                    self.greeted = false;
+                    self.tls_available = false;
+                    return Ok(Next::StartTls);
+                }
+                "MAIL" if !self.greeted => {
+                    reply(stream, "503 5.5.1 Say hello first").await?;
+                }
+                "MAIL" => match parse_path(argument, "FROM:") {
+                    Some((from, parameters)) => {
+                        if declared_size(&parameters) > self.config.max_message_size {
+                            reply(stream, "552 5.3.4 Message size exceeds fixed limit").await?;
+                        } else {
+                            self.reset();
+                            self.mail_from = Some(from);
+                            reply(stream, "250 2.1.0 Ok").await?;
+                        }
+                    }
+                    None => reply(stream, "501 5.5.4 Syntax: MAIL FROM:<address>").await?,
+                },
+                "RCPT" if self.mail_from.is_none() => {
+                    reply(stream, "503 5.5.1 Need MAIL command").await?;
+                }
+                "RCPT" if self.recipients.len() >= MAX_RECIPIENTS => {
+                    reply(stream, "452 4.5.3 Too many recipients").await?;
+                }
+                "RCPT" => match parse_path(argument, "TO:") {
+                    Some((recipient, _)) => match self.accepts(&recipient).await? {
+                        Some(account) => {
+                            self.recipients.push(account);
+                            reply(stream, "250 2.1.5 Ok").await?;
+                        }
+                        None => reply(stream, "550 5.1.1 Mailbox unavailable").await?,
+                    },
+                    None => reply(stream, "501 5.5.4 Syntax: RCPT TO:<address>").await?,
+                },
+                "DATA" if self.recipients.is_empty() => {
+                    reply(stream, "503 5.5.1 Need RCPT command").await?;
+                }
+                "DATA" => {
+                    reply(stream, "354 End data with <CR><LF>.<CR><LF>").await?;
+                    let data =
+                        read_data(stream, self.config.max_message_size, self.timeout()).await?;
+                    match data {
+                        Some(data) => {
+                            let id = delivery_id();
+                            let deliveries = self.deliver(&id, &data).await;
+                            self.reply_deliveries(stream, &id, &data, deliveries)
+                                .await?;
+                        }
+                        None => {
+                            // LMTP replies once per recipient
+                            let replies = match self.config.protocol {
+                                InboundProtocol::Lmtp => self.recipients.len(),
+                                InboundProtocol::Smtp => 1,
+                            };
+                            for _ in 0..replies {
+                                reply(stream, "552 5.3.4 Message size exceeds fixed limit").await?;
+                            }
+                        }
+                    }
+                    self.reset();
+                }
+                "RSET" => {
+                    self.reset();
+                    reply(stream, "250 2.0.0 Ok").await?;
+                }
+                "NOOP" => reply(stream, "250 2.0.0 Ok").await?,
+                "QUIT" => {
+                    reply(stream, "221 2.0.0 Bye").await?;
+                    return Ok(Next::Close);
+                }
+                _ => reply(stream, "502 5.5.2 Command not implemented").await?,
+            }
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_seconds)
+    }
+
+    fn reset(&mut self) {
+        self.mail_from = None;
+        self.recipients.clear();
+    }
+
+    /// Only the accounts that use the SMTP source receive messages.
+    async fn accepts(&self, recipient: &str) -> Result<Option<Account>> {
+        let account = self
+            .store
+            .load_account_by_email(recipient.to_lowercase())
+            .await?;
+        Ok(account.filter(|account| account.source == SourceSettings::Smtp))
+    }
+
+    /// Publish the message for every recipient, returning one outcome per recipient.
+    ///
+    /// The message is archived for every recipient first, under the id generated for
+    /// the delivery, since pushed messages do not have one.
+    async fn deliver(&self, id: &str, data: &[u8]) -> Vec<Result<(), ParseError>> {
+        let mut deliveries = vec![];
+        for account in self.recipients.iter() {
+            let email = &account.email;
+            self.publisher.archive(account, id, data).await;
+            let delivery = match imap::parse_rfc822(email, 0, data, account.policy.body_format) {
+                Ok(message) => {
+                    let result = self.publisher.publish(account, message, data).await;
+                    result.map_err(|e| {
+                        error!("Unable to publish message for {}: {:?}", email, e);
+                        ParseError::Publish(e.to_string())
+                    })
+                }
+                Err(e) => {
+                    error!("unable to parse message for {} (rejected): {}", email, e);
+                    Err(e)
+                }
+            };
+            deliveries.push(delivery);
+        }
+        deliveries
+    }
+
+    /// Reply to the DATA command with the outcome of the deliveries.
+    ///
+    /// LMTP replies once per recipient. SMTP has a single reply for all of them, so
+    /// once the message is queued for any recipient it is accepted, and the failed
+    /// deliveries are kept as dead letters: a retry by the client would publish the
+    /// message again for the others.
+    async fn reply_deliveries<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        id: &str,
+        data: &[u8],
+        deliveries: Vec<Result<(), ParseError>>,
+    ) -> Result<()> {
+        if self.config.protocol == InboundProtocol::Lmtp {
+            for delivery in deliveries.iter() {
+                reply(stream, delivery_reply(delivery)).await?;
+            }
+            return Ok(());
+        }
+        if !deliveries.iter().any(|delivery| delivery.is_ok()) {
+            return reply(stream, delivery_reply(&deliveries[0])).await;
+        }
+        for (account, delivery) in self.recipients.iter().zip(deliveries) {
+            if let Err(error) = delivery {
+                let result = self
+                    .publisher
+                    .dead_letter(account, id, 0, error, Some(data))
+                    .await;
+                if let Err(e) = result {
+                    // The message is still in the archive, if enabled
+                    error!("Unable to keep dead letter for {}: {:?}", account.email, e);
+                }
+            }
+        }
+        reply(stream, "250 2.0.0 Ok: queued").await
+    }
+}
+
+fn delivery_reply(delivery: &Result<(), ParseError>) -> &'static str {
+    match delivery {
+        Ok(()) => "250 2.0.0 Ok: queued",
+        Err(ParseError::Publish(_)) => "451 4.3.0 Temporary failure",
+        Err(_) => "554 5.6.0 Message could not be parsed",
+    }
+}
+
+/// A unique id for a delivered message, e.g. "20240101T100000.123456789Z-42-7".
+fn delivery_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{}-{}-{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.9fZ"),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, line: &str) -> Result<()> {
+    stream.write_all(line.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn reply_multiline<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    code: u16,
+    lines: &[String],
+) -> Result<()> {
+    for (index, line) in lines.iter().enumerate() {
+        let separator = if index + 1 == lines.len() { ' ' } else { '-' };
+        stream
+            .write_all(format!("{}{}{}\r\n", code, separator, line).as_bytes())
+            .await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Parse "FROM:<address> PARAMETERS", the null reverse path is allowed.
+fn parse_path(argument: &str, prefix: &str) -> Option<(String, Vec<String>)> {
+    let start = argument.get(..prefix.len())?;
+    if !start.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = argument[prefix.len()..].trim_start();
+    let rest = rest.strip_prefix('<')?;
+    let end = rest.find('>')?;
+    let parameters = rest[end + 1..]
+        .split_whitespace()
+        .map(|p| p.to_string())
+        .collect();
+    Some((rest[..end].to_string(), parameters))
+}
+
+fn declared_size(parameters: &[String]) -> usize {
+    parameters
+        .iter()
+        .find_map(|p| {
+            p.to_uppercase()
+                .strip_prefix("SIZE=")
+                .and_then(|size| size.parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+/// Read a line, or its first `MAX_LINE_LENGTH + 1` bytes when it is longer, failing
+/// with `TimedOut` when it is not received within the timeout.
+async fn read_line<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    line: &mut Vec<u8>,
+    timeout: Duration,
+) -> Result<usize> {
+    let mut limited = stream.take(MAX_LINE_LENGTH as u64 + 1);
+    match tokio::time::timeout(timeout, limited.read_until(b'\n', line)).await {
+        Ok(read) => Ok(read?),
+        Err(_) => Err(TimedOut.into()),
+    }
+}
+
+/// Read the DATA section, returning `None` when it exceeds the size limit.
+async fn read_data<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    max_message_size: usize,
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>> {
+    let mut data = vec![];
+    let mut exceeded = false;
+    // Long lines are read in several parts, only the first one can be the terminator
+    let mut line_start = true;
+    loop {
+        let mut line = vec![];
+        if read_line(stream, &mut line, timeout).await? == 0 {
+            return Err(anyhow::Error::msg("SMTP connection closed during DATA"));
+        }
+        let starts_line = line_start;
+        line_start = line.ends_with(b"\n");
+        if starts_line && (line == b".\r\n" || line == b".\n") {
+            break;
+        }
+        // Lines starting with the termination octet are byte-stuffed
+        if starts_line && line.starts_with(b"..") {
+            line.remove(0);
+        }
+        if data.len() + line.len() > max_message_size {
+            // Keep reading until the end of the data, so the session stays in sync
+            exceeded = true;
+            data.clear();
+        }
+        if !exceeded {
+            data.extend_from_slice(&line);
+        }
+    }
+    Ok(if exceeded { None } else { Some(data) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::blob::{BlobStore, FileBlobStore};
+    use crate::mocks::{MockQueue, MockStore};
+    use crate::store::{Account, AccountPolicy, Store};
+    use tokio::io::{duplex, AsyncReadExt};
+
+    async fn converse(protocol: InboundProtocol, input: &str) -> (String, Arc<MockQueue>) {
+        let queue = Arc::new(MockQueue::default());
+        let output = converse_with(protocol, input, None, queue.clone()).await;
+        (output, queue)
+    }
+
+    fn test_config(protocol: InboundProtocol) -> InboundConfig {
+        InboundConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            protocol,
+            hostname: "mx.test.com".to_string(),
+            max_message_size: 1024,
+            max_connections: 1,
+            timeout_seconds: 5,
+            tls_cert_path: None,
+            tls_key_path: None,
+        }
+    }
+
+    async fn converse_with(
+        protocol: InboundProtocol,
+        input: &str,
+        archive: Option<Arc<Archive>>,
+        queue: Arc<MockQueue>,
+    ) -> String {
+        let config = test_config(protocol);
+        let store = Arc::new(MockStore::default());
+        for email in ["inbox@test.com", "other@test.com"] {
+            store
+                .store_account(Account {
+                    email: email.to_string(),
+                    password: "".to_string(),
+                    mailbox: "INBOX".to_string(),
+                    imap_host: "".to_string(),
+                    idle_time_seconds: 0,
+                    wait_time_seconds: 0,
+                    source: SourceSettings::Smtp,
+                    policy: AccountPolicy::default(),
+                })
+                .await
+                .unwrap();
+        }
+        let publisher = Arc::new(Publisher::new(
+            store.clone(),
+            queue.clone(),
+            None,
+            None,
+            None,
+            archive,
+        ));
+        let mut session = SmtpSession::new(config, store, publisher, false);
+        let (mut client, server) = duplex(8192);
+        client.write_all(input.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        session.run(server, true).await.unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn test_smtp_delivery() {
+        let (output, queue) = converse(
+            InboundProtocol::Smtp,
+            "EHLO client\r\nMAIL FROM:<sender@test.com> SIZE=100\r\nRCPT TO:<unknown@test.com>\r\nRCPT TO:<Inbox@test.com>\r\nDATA\r\nSubject: Hi\r\n\r\n..Hello\r\n.\r\nQUIT\r\n",
+        )
+        .await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "220 mx.test.com ESMTP pregonero");
+        assert!(lines.contains(&"250-SIZE 1024"));
+        assert!(lines.contains(&"550 5.1.1 Mailbox unavailable"));
+        assert!(lines.contains(&"250 2.0.0 Ok: queued"));
+        assert_eq!(lines.last(), Some(&"221 2.0.0 Bye"));
+
+        let messages = queue.messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].email_message.account, "inbox@test.com");
+        assert_eq!(messages[0].email_message.subject, "Hi");
+        assert_eq!(messages[0].email_message.body, ".Hello\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_commands() {
+        let (output, _) = converse(
+            InboundProtocol::Smtp,
+            &format!(
+                " \u{e9}\r\n\u{131}\u{e9}\r\n  helo client\r\nMAIL \u{fb00}ROM:<a@test.com>\r\n{}\r\nNOOP\r\n",
+                "A".repeat(5000)
+            ),
+        )
+        .await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[1..],
+            [
+                "502 5.5.2 Command not implemented",
+                "502 5.5.2 Command not implemented",
+                "250 mx.test.com",
+                "501 5.5.4 Syntax: MAIL FROM:<address>",
+                "500 5.5.2 Line too long",
+                "250 2.0.0 Ok",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_too_many_recipients() {
+        let (output, _) = converse(
+            InboundProtocol::Smtp,
+            &format!(
+                "HELO client\r\nMAIL FROM:<>\r\n{}",
+                "RCPT TO:<inbox@test.com>\r\n".repeat(MAX_RECIPIENTS + 1)
+            ),
+        )
+        .await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines.iter().filter(|line| **line == "250 2.1.5 Ok").count(),
+            MAX_RECIPIENTS
+        );
+        assert_eq!(lines.last(), Some(&"452 4.5.3 Too many recipients"));
+    }
+
+    #[tokio::test]
+    async fn test_partial_delivery() {
+        let input = "HELO client\r\nMAIL FROM:<>\r\nRCPT TO:<inbox@test.com>\r\nRCPT TO:<other@test.com>\r\nDATA\r\nSubject: Hi\r\n\r\nHello\r\n.\r\nQUIT\r\n";
+        let failing = || MockQueue {
+            failing_accounts: ["other@test.com".to_string()].into(),
+            ..Default::default()
+        };
+
+        // SMTP accepts the message once, keeping the failed delivery as a dead letter
+        let queue = Arc::new(failing());
+        let output = converse_with(InboundProtocol::Smtp, input, None, queue.clone()).await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[lines.len() - 2], "250 2.0.0 Ok: queued");
+        assert!(!output.contains("451"));
+        assert_eq!(queue.messages.lock().unwrap().len(), 1);
+        let dead_letters = queue.dead_letters.lock().unwrap().clone();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].account, "other@test.com");
+        assert!(matches!(dead_letters[0].error, ParseError::Publish(_)));
+
+        // LMTP replies for every recipient, so the client retries the failed one only
+        let queue = Arc::new(failing());
+        let output = converse_with(
+            InboundProtocol::Lmtp,
+            &input.replace("HELO", "LHLO"),
+            None,
+            queue.clone(),
+        )
+        .await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[lines.len() - 3..lines.len() - 1],
+            ["250 2.0.0 Ok: queued", "451 4.3.0 Temporary failure"]
+        );
+        assert!(queue.dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_times_out() {
+        let config = InboundConfig {
+            timeout_seconds: 1,
+            ..test_config(InboundProtocol::Smtp)
+        };
+        let store = Arc::new(MockStore::default());
+        let publisher = Arc::new(Publisher::new(
+            store.clone(),
+            Arc::new(MockQueue::default()),
+            None,
+            None,
+            None,
+            None,
+        ));
+        let mut session = SmtpSession::new(config, store, publisher, false);
+        // The client sends a partial line and then waits, without closing the stream
+        let (mut client, server) = duplex(8192);
+        client.write_all(b"HELO client\r\nMAIL FROM").await.unwrap();
+        assert!(matches!(
+            session.run(server, true).await.unwrap(),
+            Outcome::Closed
+        ));
+        let mut output = vec![0; 1024];
+        let read = client.read(&mut output).await.unwrap();
+        let output = String::from_utf8_lossy(&output[..read]).to_string();
+        assert!(output.ends_with("421 4.4.2 mx.test.com Timeout, closing connection\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_delivery_is_archived() {
+        let root = tempfile::tempdir().unwrap();
+        let blob_store = Arc::new(FileBlobStore::new(
+            root.path().to_string_lossy().to_string(),
+        ));
+        let archive = Arc::new(Archive::new(blob_store.clone(), None));
+        let output = converse_with(
+            InboundProtocol::Lmtp,
+            "LHLO client\r\nMAIL FROM:<>\r\nRCPT TO:<inbox@test.com>\r\nDATA\r\nSubject: Hi\r\n\r\nHello\r\n.\r\nQUIT\r\n",
+            Some(archive),
+            Arc::new(MockQueue::default()),
+        )
+        .await;
+        assert!(output.contains("250 2.0.0 Ok: queued"));
+
+        let blobs = blob_store
+            .list_blobs("archive/inbox@test.com/INBOX/")
+            .await
+            .unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(
+            blob_store.get_blob(&blobs[0].key).await.unwrap(),
+            Some(b"Subject: Hi\r\n\r\nHello\r\n".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lmtp_size_limit() {
+        let body = "a".repeat(2048);
+        let (output, queue) = converse(
+            InboundProtocol::Lmtp,
+            &format!(
+                "EHLO client\r\nLHLO client\r\nMAIL FROM:<> SIZE=4096\r\nMAIL FROM:<>\r\nRCPT TO:<inbox@test.com>\r\nDATA\r\n{}\r\n.\r\nQUIT\r\n",
+                body
+            ),
+        )
+        .await;
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "220 mx.test.com LMTP pregonero");
+        assert_eq!(lines[1], "500 5.5.1 Use LHLO");
+        assert!(lines.contains(&"552 5.3.4 Message size exceeds fixed limit"));
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|l| l.starts_with("552 5.3.4 Message size"))
+                .count(),
+            2
+        );
+        assert!(queue.messages.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_imap::types::Fetch;
+use async_trait::async_trait;
+use tokio::time::sleep;
+use tracing::{debug, error};
+
+use crate::{
+    imap::{self, EmailMessage},
+    jmap, local, pop3,
+    publisher::Publisher,
+    store::{self, Account, LocalFormat, SourceSettings},
+};
+
+/// A message as retrieved by a mail source, before parsing.
+pub enum RawMessage {
+    /// An IMAP FETCH response, parsed from its envelope and body text
+    Imap { fetch: Fetch, uid_validity: u32 },
+    /// A complete RFC822 message
+    Rfc822 {
+        id: String,
+        seq_id: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl RawMessage {
+    fn parse(&self, account: &Account) -> Result<EmailMessage, imap::ParseError> {
+        let format = account.policy.body_format;
+        match self {
+            RawMessage::Imap { fetch, .. } => imap::parse_message(&account.email, fetch, format),
+            RawMessage::Rfc822 { seq_id, data, .. } => {
+                imap::parse_rfc822(&account.email, *seq_id, data, format)
+            }
+        }
+    }
+
+    /// The raw RFC822 source of the message, if it was fetched.
+    fn data(&self) -> Option<&[u8]> {
+        match self {
+            RawMessage::Imap { fetch, .. } => fetch.body(),
+            RawMessage::Rfc822 { data, .. } => Some(data),
+        }
+    }
+
+    fn seq_id(&self) -> u32 {
+        match self {
+            RawMessage::Imap { fetch, .. } => fetch.uid.unwrap_or_default(),
+            RawMessage::Rfc822 { seq_id, .. } => *seq_id,
+        }
+    }
+
+    fn id(&self) -> String {
+        match self {
+            // UIDs are reused when the UIDVALIDITY of the mailbox changes
+            RawMessage::Imap {
+                fetch,
+                uid_validity,
+            } => format!("{}-{}", uid_validity, fetch.uid.unwrap_or_default()),
+            RawMessage::Rfc822 { id, .. } => id.clone(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait MailSource: Send {
+    /// Connect and authenticate against the mailbox.
+    async fn connect(&mut self) -> Result<()>;
+
+    /// Fetch the messages received after the last checkpoint.
+    async fn fetch_new(&mut self) -> Result<Vec<RawMessage>>;
+
+    /// Persist the checkpoint, once the fetched messages were published.
+    async fn checkpoint(&mut self) -> Result<()>;
+
+    /// Wait for the mailbox to change (if supported), leaving the source disconnected.
+    async fn wait_for_changes(&mut self) -> Result<()>;
+
+    /// Fetch the raw source of a message again by its id, if the source supports it.
+    async fn refetch(&mut self, _id: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// Build the mail source selected by an account, if the account has to be polled.
+///
+/// Accounts that receive pushed messages (e.g. through SMTP) do not have a source.
+pub fn from_account(
+    account: &Account,
+    store: Arc<dyn store::Store>,
+) -> Option<Box<dyn MailSource>> {
+    let source: Box<dyn MailSource> = match &account.source {
+        SourceSettings::Imap => Box::new(imap::ImapSource::new(account.clone(), store)),
+        SourceSettings::Jmap(settings) => Box::new(jmap::JmapSource::new(
+            account.clone(),
+            settings.clone(),
+            store,
+        )),
+        SourceSettings::Pop3(settings) => Box::new(pop3::Pop3Source::new(
+            account.clone(),
+            settings.clone(),
+            store,
+        )),
+        SourceSettings::Local(settings) => match settings.format {
+            LocalFormat::Maildir => {
+                Box::new(local::MaildirSource::new(account.clone(), settings.clone()))
+            }
+            LocalFormat::Mbox => Box::new(local::MboxSource::new(
+                account.clone(),
+                settings.clone(),
+                store,
+            )),
+        },
+        SourceSettings::Smtp => return None,
+    };
+    Some(source)
+}
+
+/// Process the mailbox of an account forever, publishing every new message.
+///
+/// The dead letters of the account are replayed once connected, e.g. after a parser fix.
+pub async fn run(
+    account: Account,
+    store: Arc<dyn store::Store>,
+    publisher: Arc<Publisher>,
+) -> Result<()> {
+    let mut source = match from_account(&account, store) {
+        Some(source) => source,
+        None => {
+            debug!("-- account {} does not need polling", account.email);
+            return Ok(());
+        }
+    };
+    let mut replay = true;
+    loop {
+        source.connect().await?;
+        debug!("-- connected with account {}", account.email);
+        // Once connected, so the letters without source can be fetched again
+        if replay {
+            let replayed = publisher
+                .replay_dead_letters(&account, source.as_mut())
+                .await?;
+            debug!(
+                "-- {} dead letters replayed for account {}",
+                replayed, account.email
+            );
+            replay = false;
+        }
+
+        let raw_messages = source.fetch_new().await?;
+        publish(&account, &raw_messages, &publisher).await?;
+        source.checkpoint().await?;
+
+        source.wait_for_changes().await?;
+
+        // Introduce a delay before the next iteration to avoid busy-waiting
+        // This delay could be a bit longer to prevent bans, or even randomized
+        sleep(Duration::from_secs(account.wait_time_seconds)).await;
+    }
+}
+
+async fn publish(
+    account: &Account,
+    raw_messages: &[RawMessage],
+    publisher: &Publisher,
+) -> Result<()> {
+    let mut parsed = 0;
+    let mut dead_letters = 0;
+    for raw_message in raw_messages.iter() {
+        if let Some(data) = raw_message.data() {
+            publisher.archive(account, &raw_message.id(), data).await;
+        }
+        match raw_message.parse(account) {
+            Ok(message) => {
+                let data = raw_message.data().unwrap_or_default();
+                publisher.publish(account, message, data).await?;
+                parsed += 1;
+            }
+            Err(e) => {
+                // Kept before the checkpoint moves past the message
+                error!(
+                    "unable to parse message {} (dead letter): {}",
+                    raw_message.id(),
+                    e
+                );
+                publisher
+                    .dead_letter(
+                        account,
+                        &raw_message.id(),
+                        raw_message.seq_id(),
+                        e,
+                        raw_message.data(),
+                    )
+                    .await?;
+                dead_letters += 1;
+            }
+        }
+    }
+
+    debug!(
+        "--  parsed {} | dead letters {} | total {}",
+        parsed,
+        dead_letters,
+        raw_messages.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::blob::FileBlobStore;
+    use crate::mocks::{MockQueue, MockStore};
+
+    #[tokio::test]
+    async fn test_maildir_source_publish_and_checkpoint() {
+        let maildir = tempfile::tempdir().unwrap();
+        for dir in ["new", "cur", "tmp"] {
+            std::fs::create_dir(maildir.path().join(dir)).unwrap();
+        }
+        std::fs::write(
+            maildir.path().join("new/1.host"),
+            "From: Sender <sender@test.com>\r\nSubject: Hello\r\n\r\nHello world",
+        )
+        .unwrap();
+
+        let settings = store::LocalSettings {
+            path: maildir.path().to_string_lossy().to_string(),
+            format: LocalFormat::Maildir,
+        };
+        let account = Account {
+            email: "test@test.com".to_string(),
+            password: "password".to_string(),
+            mailbox: "INBOX".to_string(),
+            imap_host: "".to_string(),
+            idle_time_seconds: 15,
+            wait_time_seconds: 30,
+            source: SourceSettings::Local(settings.clone()),
+            policy: store::AccountPolicy::default(),
+        };
+        let mut source = local::MaildirSource::new(account.clone(), settings);
+        let queue = Arc::new(MockQueue::default());
+        let blobs = tempfile::tempdir().unwrap();
+        let archive = Arc::new(Archive::new(
+            Arc::new(FileBlobStore::new(
+                blobs.path().to_string_lossy().to_string(),
+            )),
+            None,
+        ));
+        let publisher = Publisher::new(
+            Arc::new(MockStore::default()),
+            queue.clone(),
+            None,
+            None,
+            None,
+            Some(archive.clone()),
+        );
+
+        source.connect().await.unwrap();
+        let raw_messages = source.fetch_new().await.unwrap();
+        publish(&account, &raw_messages, &publisher).await.unwrap();
+        source.checkpoint().await.unwrap();
+        // Archived before parsing, under the Maildir file name
+        assert!(archive
+            .get("test@test.com", "INBOX", "1.host")
+            .await
+            .unwrap()
+            .is_some());
+
+        let messages = queue.messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].email_message.account, "test@test.com");
+        assert_eq!(messages[0].email_message.subject, "Hello");
+        assert_eq!(
+            messages[0].email_message.senders[0].email,
+            "sender@test.com"
+        );
+        assert_eq!(messages[0].email_message.body, "Hello world");
+        assert!(maildir.path().join("cur/1.host:2,").exists());
+    }
+}
@@ -2,18 +2,145 @@ use anyhow::Result;
 use async_trait::async_trait;
 use redis::{AsyncCommands, Client};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fmt;
 use std::sync::Arc;
 use tracing::debug;
 
+use crate::imap::{BodyFormat, MessageClass};
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Account {
     pub email: String,
     pub password: String,
-    pub mailbox: String,   // INBOX by default
+    pub mailbox: String, // INBOX by default
+    #[serde(default)]
     pub imap_host: String, // TODO: could be detected depending on the @provider part of the username
     pub idle_time_seconds: u64,
     pub wait_time_seconds: u64,
+    #[serde(default)]
+    pub source: SourceSettings, // IMAP by default
+    #[serde(default)]
+    pub policy: AccountPolicy,
+}
+
+/// How the messages of an account are processed before being published.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AccountPolicy {
+    #[serde(default)]
+    pub attachments: AttachmentPolicy,
+    #[serde(default)]
+    pub skip_seen: bool, // Do not publish the messages already read in another client
+    #[serde(default)]
+    pub max_message_size: Option<usize>, // In bytes, messages of any size are published when missing
+    #[serde(default)]
+    pub html_body: bool, // Publish the sanitized HTML body along the plain text
+    #[serde(default)]
+    pub body_format: BodyFormat, // How HTML bodies are rendered into the message body
+    #[serde(default)]
+    pub drop_classes: Vec<MessageClass>, // e.g. ["auto-reply", "bulk"], none are dropped when empty
+    #[serde(default)]
+    pub verify_dkim: bool, // Verify the DKIM signatures, besides reading Authentication-Results
+    #[serde(default)]
+    pub trusted_authserv_ids: Vec<String>, // e.g. ["mx.google.com"], the Authentication-Results of other servers are ignored
+}
+
+impl AccountPolicy {
+    /// Whether a message should be published, given its flags and size (if known).
+    pub fn allows_message(&self, flags: &[String], size: Option<usize>) -> bool {
+        if self.skip_seen && flags.iter().any(|flag| flag.eq_ignore_ascii_case("\\Seen")) {
+            return false;
+        }
+        !matches!((self.max_message_size, size), (Some(max_size), Some(size)) if size > max_size)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AttachmentPolicy {
+    #[serde(default)]
+    pub max_size: Option<usize>, // In bytes, attachments of any size are stored when missing
+    #[serde(default)]
+    pub allowed_content_types: Vec<String>, // e.g. "application/pdf" or "image/*", all when empty
+}
+
+impl AttachmentPolicy {
+    /// Whether an attachment should be written to the blob store.
+    pub fn allows(&self, mime_type: &str, size: usize) -> bool {
+        if self.max_size.is_some_and(|max_size| size > max_size) {
+            return false;
+        }
+        self.allowed_content_types.is_empty()
+            || self
+                .allowed_content_types
+                .iter()
+                .any(|allowed| match allowed.strip_suffix("/*") {
+                    Some(prefix) => mime_type
+                        .split('/')
+                        .next()
+                        .is_some_and(|kind| kind.eq_ignore_ascii_case(prefix)),
+                    None => allowed.eq_ignore_ascii_case(mime_type),
+                })
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceSettings {
+    #[default]
+    Imap,
+    Jmap(JmapSettings),
+    Pop3(Pop3Settings),
+    Local(LocalSettings),
+    Smtp, // Messages are pushed to the inbound SMTP/LMTP listener
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct JmapSettings {
+    pub session_url: String,
+    #[serde(default = "default_jmap_initial_window")]
+    pub initial_window: usize, // The latest emails published on the first sync of the mailbox
+}
+
+fn default_jmap_initial_window() -> usize {
+    500
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Pop3Settings {
+    pub host: String,
+    #[serde(default = "default_pop3_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: Pop3Security,
+    #[serde(default)]
+    pub apop: bool, // USER/PASS is used otherwise
+    #[serde(default)]
+    pub delete_after_retrieval: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pop3Security {
+    #[default]
+    Tls, // Implicit TLS, usually on port 995
+    StartTls, // STLS upgrade, usually on port 110
+}
+
+fn default_pop3_port() -> u16 {
+    995
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LocalSettings {
+    pub path: String,
+    pub format: LocalFormat,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalFormat {
+    Maildir,
+    Mbox,
 }
 
 impl fmt::Display for Account {
@@ -43,11 +170,53 @@ pub trait Store: Send + Sync {
     /// Destroy all accounts belonging to a host
     async fn clear_host_accounts(&self, host: String) -> Result<()>;
 
-    /// Destroy all accounts belonging to a host
+    /// Get the highest IMAP UID processed for an account, or 0 when none was
     async fn load_last_sequence(&self, email: &str) -> Result<u32>;
 
-    /// Destroy all accounts belonging to a host
+    /// Store the highest IMAP UID processed for an account
     async fn store_last_sequence(&self, email: &str, last_sequence: u32) -> Result<()>;
+
+    /// Get the last JMAP email state processed for an account, if any.
+    async fn load_jmap_state(&self, email: &str) -> Result<Option<String>>;
+
+    /// Store the last JMAP email state processed for an account.
+    async fn store_jmap_state(&self, email: &str, state: &str) -> Result<()>;
+
+    /// Get the JMAP ids of the emails known in the mailbox of an account.
+    async fn load_jmap_ids(&self, email: &str) -> Result<HashSet<String>>;
+
+    /// Replace the JMAP ids of the emails known in the mailbox of an account.
+    async fn store_jmap_ids(&self, email: &str, ids: &HashSet<String>) -> Result<()>;
+
+    /// Get the POP3 unique ids of the messages already processed for an account.
+    async fn load_pop3_uidls(&self, email: &str) -> Result<HashSet<String>>;
+
+    /// Replace the POP3 unique ids of the messages already processed for an account.
+    async fn store_pop3_uidls(&self, email: &str, uidls: &HashSet<String>) -> Result<()>;
+
+    /// Get the byte offset up to which the mbox of an account was processed.
+    async fn load_mbox_offset(&self, email: &str) -> Result<u64>;
+
+    /// Store the byte offset up to which the mbox of an account was processed.
+    async fn store_mbox_offset(&self, email: &str, offset: u64) -> Result<()>;
+
+    /// Get the thread of a message id (or normalized subject) already seen for an account.
+    async fn load_thread_id(&self, email: &str, key: &str) -> Result<Option<String>>;
+
+    /// Assign message ids (or normalized subjects) to a thread of an account.
+    async fn store_thread_id(&self, email: &str, keys: &[String], thread_id: &str) -> Result<()>;
+
+    /// Count a new message in a thread, returning its position (starting at 1).
+    async fn increment_thread_size(&self, email: &str, thread_id: &str) -> Result<u32>;
+
+    /// Add the size of a thread to the one it was merged into.
+    async fn merge_thread_size(&self, email: &str, from: &str, into: &str) -> Result<()>;
+
+    /// Get the position in its thread of a message already counted for an account.
+    async fn load_thread_position(&self, email: &str, key: &str) -> Result<Option<u32>>;
+
+    /// Store the position in its thread of a message of an account.
+    async fn store_thread_position(&self, email: &str, key: &str, position: u32) -> Result<()>;
 }
 
 #[derive(Clone, Debug)]
@@ -88,12 +257,9 @@ impl Store for RedisStore {
             // Fetch and parse the account data for the keys
             for key in keys {
                 let account_json: Option<String> = con.get(&key).await.unwrap();
-                match account_json {
-                    Some(json) => {
-                        let account: Account = serde_json::from_str(&json)?;
-                        accounts.push(account);
-                    }
-                    None => (),
+                if let Some(json) = account_json {
+                    let account: Account = serde_json::from_str(&json)?;
+                    accounts.push(account);
                 }
             }
 
@@ -173,9 +339,10 @@ impl Store for RedisStore {
         match last_sequence {
             Some(last_sequence_string) => match last_sequence_string.parse::<u32>() {
                 Ok(last_sequence) => Ok(last_sequence),
-                Err(_) => Ok(1),
+                Err(_) => Ok(0),
             },
-            None => Ok(1),
+            // No message was processed yet
+            None => Ok(0),
         }
     }
 
@@ -186,12 +353,215 @@ impl Store for RedisStore {
         con.set::<_, _, ()>(&key, last_sequence).await.unwrap();
         Ok(())
     }
+
+    async fn load_jmap_state(&self, email: &str) -> Result<Option<String>> {
+        debug!("Load JMAP state for email '{}'", email);
+        let key = format!("jmap_state:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let state: Option<String> = con.get(&key).await.unwrap();
+        Ok(state)
+    }
+
+    async fn store_jmap_state(&self, email: &str, state: &str) -> Result<()> {
+        debug!("Store JMAP state {} for email {}", state, email);
+        let key = format!("jmap_state:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        con.set::<_, _, ()>(&key, state).await.unwrap();
+        Ok(())
+    }
+
+    async fn load_jmap_ids(&self, email: &str) -> Result<HashSet<String>> {
+        debug!("Load JMAP ids for email '{}'", email);
+        let key = format!("jmap_ids:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let ids: HashSet<String> = con.smembers(&key).await.unwrap();
+        Ok(ids)
+    }
+
+    async fn store_jmap_ids(&self, email: &str, ids: &HashSet<String>) -> Result<()> {
+        debug!("Store {} JMAP ids for email {}", ids.len(), email);
+        let key = format!("jmap_ids:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        con.del::<_, ()>(&key).await.unwrap();
+        if !ids.is_empty() {
+            con.sadd::<_, _, ()>(&key, ids).await.unwrap();
+        }
+        Ok(())
+    }
+
+    async fn load_pop3_uidls(&self, email: &str) -> Result<HashSet<String>> {
+        debug!("Load POP3 uidls for email '{}'", email);
+        let key = format!("pop3_uidls:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let uidls: HashSet<String> = con.smembers(&key).await.unwrap();
+        Ok(uidls)
+    }
+
+    async fn store_pop3_uidls(&self, email: &str, uidls: &HashSet<String>) -> Result<()> {
+        debug!("Store {} POP3 uidls for email {}", uidls.len(), email);
+        let key = format!("pop3_uidls:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        con.del::<_, ()>(&key).await.unwrap();
+        if !uidls.is_empty() {
+            con.sadd::<_, _, ()>(&key, uidls).await.unwrap();
+        }
+        Ok(())
+    }
+
+    async fn load_mbox_offset(&self, email: &str) -> Result<u64> {
+        debug!("Load mbox offset for email '{}'", email);
+        let key = format!("mbox_offset:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let offset: Option<u64> = con.get(&key).await.unwrap();
+        Ok(offset.unwrap_or(0))
+    }
+
+    async fn store_mbox_offset(&self, email: &str, offset: u64) -> Result<()> {
+        debug!("Store mbox offset {} for email {}", offset, email);
+        let key = format!("mbox_offset:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        con.set::<_, _, ()>(&key, offset).await.unwrap();
+        Ok(())
+    }
+
+    async fn load_thread_id(&self, email: &str, key: &str) -> Result<Option<String>> {
+        debug!("Load thread of {} for email '{}'", key, email);
+        let threads_key = format!("threads:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let thread_id: Option<String> = con.hget(&threads_key, key).await.unwrap();
+        Ok(thread_id)
+    }
+
+    async fn store_thread_id(&self, email: &str, keys: &[String], thread_id: &str) -> Result<()> {
+        debug!("Store thread {} for email {}", thread_id, email);
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let threads_key = format!("threads:{}", email);
+        let items: Vec<(&str, &str)> = keys.iter().map(|key| (key.as_str(), thread_id)).collect();
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        con.hset_multiple::<_, _, _, ()>(&threads_key, &items)
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    async fn increment_thread_size(&self, email: &str, thread_id: &str) -> Result<u32> {
+        debug!("Increment size of thread {} for email {}", thread_id, email);
+        let key = format!("thread_sizes:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let size: u32 = con.hincr(&key, thread_id, 1).await.unwrap();
+        Ok(size)
+    }
+
+    async fn merge_thread_size(&self, email: &str, from: &str, into: &str) -> Result<()> {
+        debug!("Merge thread {} into {} for email {}", from, into, email);
+        let key = format!("thread_sizes:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let size: Option<u32> = con.hget(&key, from).await.unwrap();
+        con.hincr::<_, _, _, ()>(&key, into, size.unwrap_or(0))
+            .await
+            .unwrap();
+        con.hdel::<_, _, ()>(&key, from).await.unwrap();
+        Ok(())
+    }
+
+    async fn load_thread_position(&self, email: &str, key: &str) -> Result<Option<u32>> {
+        debug!("Load thread position of {} for email '{}'", key, email);
+        let positions_key = format!("thread_positions:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        let position: Option<u32> = con.hget(&positions_key, key).await.unwrap();
+        Ok(position)
+    }
+
+    async fn store_thread_position(&self, email: &str, key: &str, position: u32) -> Result<()> {
+        debug!(
+            "Store thread position {} of {} for email {}",
+            position, key, email
+        );
+        let positions_key = format!("thread_positions:{}", email);
+        let mut con = self.redis_client.get_async_connection().await.unwrap();
+        con.hset::<_, _, _, ()>(&positions_key, key, position)
+            .await
+            .unwrap();
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_account_source_settings() {
+        let account: Account = serde_json::from_str(
+            r#"{
+                "email": "test@test.com",
+                "password": "password",
+                "mailbox": "INBOX",
+                "imap_host": "imap.test.com",
+                "idle_time_seconds": 15,
+                "wait_time_seconds": 30
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(account.source, SourceSettings::Imap);
+
+        let account: Account = serde_json::from_str(
+            r#"{
+                "email": "test@test.com",
+                "password": "password",
+                "mailbox": "INBOX",
+                "idle_time_seconds": 15,
+                "wait_time_seconds": 30,
+                "source": { "type": "pop3", "host": "pop.test.com", "security": "starttls" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            account.source,
+            SourceSettings::Pop3(Pop3Settings {
+                host: "pop.test.com".to_string(),
+                port: 995,
+                security: Pop3Security::StartTls,
+                apop: false,
+                delete_after_retrieval: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_attachment_policy_allows() {
+        let policy = AttachmentPolicy::default();
+        assert!(policy.allows("application/zip", usize::MAX));
+
+        let policy = AttachmentPolicy {
+            max_size: Some(1024),
+            allowed_content_types: vec!["application/pdf".to_string(), "image/*".to_string()],
+        };
+        assert!(policy.allows("application/pdf", 1024));
+        assert!(policy.allows("image/png", 10));
+        assert!(!policy.allows("application/pdf", 1025));
+        assert!(!policy.allows("application/zip", 10));
+    }
+
+    #[test]
+    fn test_account_policy_allows_message() {
+        let seen = vec!["\\Seen".to_string()];
+        let policy = AccountPolicy::default();
+        assert!(policy.allows_message(&seen, Some(usize::MAX)));
+
+        let policy = AccountPolicy {
+            skip_seen: true,
+            max_message_size: Some(1024),
+            ..Default::default()
+        };
+        assert!(policy.allows_message(&[], Some(1024)));
+        assert!(policy.allows_message(&["\\Flagged".to_string()], None));
+        assert!(!policy.allows_message(&seen, Some(10)));
+        assert!(!policy.allows_message(&[], Some(1025)));
+    }
+
     #[tokio::test]
     async fn test_store_account_and_load_by_email_and_destroy() {
         let store = RedisStore::new("redis://localhost:6380/0".to_string()).await;
@@ -203,6 +573,8 @@ mod tests {
             imap_host: "imap.test.com".to_string(),
             idle_time_seconds: 15,
             wait_time_seconds: 30,
+            source: SourceSettings::Imap,
+            policy: AccountPolicy::default(),
         };
 
         // Store the account
@@ -244,6 +616,8 @@ mod tests {
             imap_host: "imap.test.com".to_string(),
             idle_time_seconds: 15,
             wait_time_seconds: 30,
+            source: SourceSettings::Imap,
+            policy: AccountPolicy::default(),
         };
 
         let account2 = Account {
@@ -253,6 +627,8 @@ mod tests {
             imap_host: "imap.test.com".to_string(),
             idle_time_seconds: 15,
             wait_time_seconds: 30,
+            source: SourceSettings::Imap,
+            policy: AccountPolicy::default(),
         };
 
         // Store accounts
@@ -265,8 +641,8 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(accounts.len(), 2);
-        assert_eq!(accounts.contains(&account1), true);
-        assert_eq!(accounts.contains(&account2), true);
+        assert!(accounts.contains(&account1));
+        assert!(accounts.contains(&account2));
 
         // Clear host accounts
         store
@@ -300,4 +676,101 @@ mod tests {
         let loaded_sequence = store.load_last_sequence(&email).await.unwrap();
         assert_eq!(loaded_sequence, last_sequence);
     }
+
+    #[tokio::test]
+    async fn test_store_and_load_jmap_state() {
+        let store = RedisStore::new("redis://localhost:6380/3".to_string()).await;
+
+        let email = "test@test.com".to_string();
+        let state = "s42".to_string();
+
+        // Call store_jmap_state to store the value in Redis
+        store.store_jmap_state(&email, &state).await.unwrap();
+
+        // Call load_jmap_state and check if the stored value is returned
+        let loaded_state = store.load_jmap_state(&email).await.unwrap();
+        assert_eq!(loaded_state, Some(state));
+
+        // The ids known in the mailbox are stored next to the state
+        let ids: HashSet<String> = ["M1".to_string(), "M2".to_string()].into();
+        store.store_jmap_ids(&email, &ids).await.unwrap();
+        assert_eq!(store.load_jmap_ids(&email).await.unwrap(), ids);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_load_pop3_uidls() {
+        let store = RedisStore::new("redis://localhost:6380/4".to_string()).await;
+
+        let email = "test@test.com".to_string();
+        let uidls: HashSet<String> = ["uid1".to_string(), "uid2".to_string()].into();
+
+        // Call store_pop3_uidls to store the value in Redis
+        store.store_pop3_uidls(&email, &uidls).await.unwrap();
+
+        // Call load_pop3_uidls and check if the stored value is returned
+        let loaded_uidls = store.load_pop3_uidls(&email).await.unwrap();
+        assert_eq!(loaded_uidls, uidls);
+
+        // Storing again replaces the previous uidls
+        let uidls: HashSet<String> = ["uid3".to_string()].into();
+        store.store_pop3_uidls(&email, &uidls).await.unwrap();
+        assert_eq!(store.load_pop3_uidls(&email).await.unwrap(), uidls);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_load_mbox_offset() {
+        let store = RedisStore::new("redis://localhost:6380/5".to_string()).await;
+
+        let email = "test@test.com".to_string();
+        let offset = 4096;
+
+        // Call store_mbox_offset to store the value in Redis
+        store.store_mbox_offset(&email, offset).await.unwrap();
+
+        // Call load_mbox_offset and check if the stored value is returned
+        let loaded_offset = store.load_mbox_offset(&email).await.unwrap();
+        assert_eq!(loaded_offset, offset);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_load_thread_id() {
+        let store = RedisStore::new("redis://localhost:6380/6".to_string()).await;
+
+        let email = "test@test.com".to_string();
+        let keys = vec!["id:<a@test.com>".to_string(), "subject:hello".to_string()];
+
+        // Call store_thread_id to map the keys to the thread in Redis
+        store.store_thread_id(&email, &keys, "t1").await.unwrap();
+
+        // Call load_thread_id and check if the thread is returned for every key
+        for key in keys.iter() {
+            let thread_id = store.load_thread_id(&email, key).await.unwrap();
+            assert_eq!(thread_id, Some("t1".to_string()));
+        }
+        let thread_id = store.load_thread_id(&email, "id:<b@test.com>").await;
+        assert_eq!(thread_id.unwrap(), None);
+
+        // Positions are counted per thread
+        let first = store.increment_thread_size(&email, "t1").await.unwrap();
+        let second = store.increment_thread_size(&email, "t1").await.unwrap();
+        assert_eq!(second, first + 1);
+
+        // The size of a merged thread is added to the other one
+        let other = store.increment_thread_size(&email, "t2").await.unwrap();
+        store.merge_thread_size(&email, "t2", "t1").await.unwrap();
+        let third = store.increment_thread_size(&email, "t1").await.unwrap();
+        assert_eq!(third, second + other + 1);
+        let restarted = store.increment_thread_size(&email, "t2").await.unwrap();
+        assert_eq!(restarted, 1);
+
+        // Call store_thread_position and check the position is returned
+        store
+            .store_thread_position(&email, "id:<a@test.com>", 2)
+            .await
+            .unwrap();
+        let position = store.load_thread_position(&email, "id:<a@test.com>").await;
+        assert_eq!(position.unwrap(), Some(2));
+        let position = store.load_thread_position(&email, "id:<b@test.com>").await;
+        assert_eq!(position.unwrap(), None);
+    }
 }
@@ -0,0 +1,269 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::{imap::EmailMessage, store::Store};
+
+/// The conversation a message belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Thread {
+    pub id: String,
+    pub position: u32, // Order of arrival in the thread, starting at 1
+}
+
+/// Assign a message to a thread, following the JWZ threading algorithm.
+///
+/// The messages linked by Message-ID, In-Reply-To and References share a thread,
+/// and the threads of a message referencing several of them are merged. Replies
+/// without any known reference (e.g. from clients dropping the headers) are grouped
+/// by their normalized subject. New threads get an id derived from the root message
+/// id, so it is stable across accounts and reprocessing. A message published again
+/// (e.g. refetched or replayed) keeps its position.
+pub async fn assign_thread(
+    store: &dyn Store,
+    email: &str,
+    message: &EmailMessage,
+    raw: &[u8],
+) -> Result<Thread> {
+    // The references are ordered from the root to the parent
+    let mut ids: Vec<String> = message.references.clone();
+    ids.extend(message.in_reply_to.iter().cloned());
+    ids.extend(message.message_id.iter().cloned());
+    ids.dedup();
+    let mut keys: Vec<String> = ids.iter().map(|id| format!("id:{}", id)).collect();
+
+    let (subject, is_reply) = normalize_subject(&message.subject);
+    let is_reply = is_reply || message.in_reply_to.is_some() || !message.references.is_empty();
+    let subject_key = format!("subject:{}", subject);
+
+    let mut found: Vec<String> = vec![];
+    for key in keys.iter() {
+        if let Some(thread_id) = load_thread(store, email, key).await? {
+            if !found.contains(&thread_id) {
+                found.push(thread_id);
+            }
+        }
+    }
+    if found.is_empty() && is_reply && !subject.is_empty() {
+        found.extend(load_thread(store, email, &subject_key).await?);
+    }
+    let thread_id = match found.first() {
+        Some(thread_id) => thread_id.clone(),
+        None => {
+            let root = match ids.first() {
+                Some(id) => Sha256::digest(id.as_bytes()),
+                None => Sha256::digest(raw),
+            };
+            hex::encode(&root[..8])
+        }
+    };
+    // The message links threads that were separate until now
+    for other in found.iter().skip(1) {
+        debug!("-- thread {} merged into {}", other, thread_id);
+        let alias = vec![format!("thread:{}", other)];
+        store.store_thread_id(email, &alias, &thread_id).await?;
+        store.merge_thread_size(email, other, &thread_id).await?;
+    }
+
+    if !subject.is_empty() {
+        keys.push(subject_key);
+    }
+    store.store_thread_id(email, &keys, &thread_id).await?;
+    let message_key = match &message.message_id {
+        Some(message_id) => format!("id:{}", message_id),
+        None => format!("raw:{}", hex::encode(Sha256::digest(raw))),
+    };
+    let position = match store.load_thread_position(email, &message_key).await? {
+        Some(position) => position,
+        None => {
+            let position = store.increment_thread_size(email, &thread_id).await?;
+            store
+                .store_thread_position(email, &message_key, position)
+                .await?;
+            position
+        }
+    };
+    debug!(
+        "-- message {} is #{} of thread {}",
+        message.seq_id, position, thread_id
+    );
+    Ok(Thread {
+        id: thread_id,
+        position,
+    })
+}
+
+/// The thread of a key, following the threads it was merged into.
+async fn load_thread(store: &dyn Store, email: &str, key: &str) -> Result<Option<String>> {
+    let mut thread_id = match store.load_thread_id(email, key).await? {
+        Some(thread_id) => thread_id,
+        None => return Ok(None),
+    };
+    while let Some(merged) = store
+        .load_thread_id(email, &format!("thread:{}", thread_id))
+        .await?
+    {
+        thread_id = merged;
+    }
+    Ok(Some(thread_id))
+}
+
+/// Normalize a subject for grouping, telling whether it had a reply or forward prefix.
+///
+/// Prefixes in several languages (e.g. "Re:", "Fwd:", "AW:", "RE[2]:") and mailing
+/// list tags (e.g. "[list]") are removed, and the case and whitespace normalized.
+pub fn normalize_subject(subject: &str) -> (String, bool) {
+    static PREFIX: OnceLock<Regex> = OnceLock::new();
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| {
+        Regex::new(r"(?i)^\s*(re|fwd?|aw|wg|sv|vs|rif|enc|res|tr|antw)\s*(\[\d+\]|\(\d+\))?\s*:")
+            .unwrap()
+    });
+    let tag = TAG.get_or_init(|| Regex::new(r"^\s*\[[^\]]*\]").unwrap());
+
+    let mut subject = subject.to_string();
+    let mut is_reply = false;
+    loop {
+        if let Some(found) = prefix.find(&subject) {
+            subject = subject[found.end()..].to_string();
+            is_reply = true;
+        } else if let Some(found) = tag.find(&subject) {
+            subject = subject[found.end()..].to_string();
+        } else {
+            break;
+        }
+    }
+    let normalized = subject
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    (normalized, is_reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mocks::MockStore;
+
+    fn message(subject: &str, message_id: &str, references: &[&str]) -> EmailMessage {
+        EmailMessage {
+            subject: subject.to_string(),
+            message_id: Some(message_id.to_string()),
+            in_reply_to: references.last().map(|id| id.to_string()),
+            references: references.iter().map(|id| id.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_normalize_subject() {
+        assert_eq!(
+            normalize_subject("Hello  World"),
+            ("hello world".to_string(), false)
+        );
+        assert_eq!(
+            normalize_subject("RE[2]: [team] Fwd: AW: Hello"),
+            ("hello".to_string(), true)
+        );
+        assert_eq!(
+            normalize_subject("Regarding: x"),
+            ("regarding: x".to_string(), false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_assign_thread() {
+        let store = MockStore::default();
+        let email = "test@test.com";
+
+        let root = message("Meeting", "<1@test.com>", &[]);
+        let reply = message("Re: Meeting", "<2@test.com>", &["<1@test.com>"]);
+        // A reply from a client that dropped the references
+        let orphan = EmailMessage {
+            in_reply_to: None,
+            references: vec![],
+            ..message("RE: meeting", "<3@test.com>", &[])
+        };
+        let other = message("Meeting", "<4@test.com>", &[]);
+
+        let root_thread = assign_thread(&store, email, &root, b"").await.unwrap();
+        assert_eq!(root_thread.position, 1);
+        let reply_thread = assign_thread(&store, email, &reply, b"").await.unwrap();
+        assert_eq!(reply_thread.id, root_thread.id);
+        assert_eq!(reply_thread.position, 2);
+        let orphan_thread = assign_thread(&store, email, &orphan, b"").await.unwrap();
+        assert_eq!(orphan_thread.id, root_thread.id);
+        assert_eq!(orphan_thread.position, 3);
+        // Same subject, but not a reply
+        let other_thread = assign_thread(&store, email, &other, b"").await.unwrap();
+        assert_ne!(other_thread.id, root_thread.id);
+        assert_eq!(other_thread.position, 1);
+    }
+
+    #[tokio::test]
+    async fn test_assign_thread_out_of_order() {
+        // The reply arrives first, the thread id is still the one of the root
+        let store = MockStore::default();
+        let email = "test@test.com";
+
+        let reply = message("Re: Meeting", "<2@test.com>", &["<1@test.com>"]);
+        let root = message("Meeting", "<1@test.com>", &[]);
+
+        let reply_thread = assign_thread(&store, email, &reply, b"").await.unwrap();
+        let root_thread = assign_thread(&store, email, &root, b"").await.unwrap();
+        assert_eq!(reply_thread.id, root_thread.id);
+        let fresh = assign_thread(&MockStore::default(), email, &root, b"")
+            .await
+            .unwrap();
+        assert_eq!(fresh.id, root_thread.id);
+    }
+
+    #[tokio::test]
+    async fn test_assign_thread_is_idempotent() {
+        let store = MockStore::default();
+        let email = "test@test.com";
+
+        let root = message("Meeting", "<1@test.com>", &[]);
+        let reply = message("Re: Meeting", "<2@test.com>", &["<1@test.com>"]);
+        assign_thread(&store, email, &root, b"").await.unwrap();
+        let reply_thread = assign_thread(&store, email, &reply, b"").await.unwrap();
+        // A refetched or replayed message keeps its position
+        let again = assign_thread(&store, email, &reply, b"").await.unwrap();
+        assert_eq!(again, reply_thread);
+        let next = message("Re: Meeting", "<3@test.com>", &["<1@test.com>"]);
+        let next_thread = assign_thread(&store, email, &next, b"").await.unwrap();
+        assert_eq!(next_thread.position, 3);
+    }
+
+    #[tokio::test]
+    async fn test_assign_thread_merges_threads() {
+        let store = MockStore::default();
+        let email = "test@test.com";
+
+        let first = message("Budget", "<1@test.com>", &[]);
+        let second = message("Planning", "<2@test.com>", &[]);
+        let first_thread = assign_thread(&store, email, &first, b"").await.unwrap();
+        let second_thread = assign_thread(&store, email, &second, b"").await.unwrap();
+        assert_ne!(first_thread.id, second_thread.id);
+
+        // A message referencing both threads links them
+        let both = message(
+            "Re: Budget",
+            "<3@test.com>",
+            &["<1@test.com>", "<2@test.com>"],
+        );
+        let both_thread = assign_thread(&store, email, &both, b"").await.unwrap();
+        assert_eq!(both_thread.id, first_thread.id);
+        assert_eq!(both_thread.position, 3);
+
+        // Later replies to the merged thread join the same thread
+        let reply = message("Re: Planning", "<4@test.com>", &["<2@test.com>"]);
+        let reply_thread = assign_thread(&store, email, &reply, b"").await.unwrap();
+        assert_eq!(reply_thread.id, first_thread.id);
+        assert_eq!(reply_thread.position, 4);
+    }
+}